@@ -0,0 +1,316 @@
+// Append-only audit log, each entry hashing the one before and the head pinned on its own.
+use super::durable;
+use super::timestamp;
+use crate::config;
+use serde::Deserialize;
+use serde::Serialize;
+use sodiumoxide::crypto::hash::sha256;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum AuditEvent {
+    Encrypt,
+    Decrypt,
+    KeyUnlock,
+    KeyAdded,
+    PolicyChange,
+    Remove,
+    Restore,
+    Purge,
+}
+
+// the client process on the other end of the agent socket
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: u32,
+    pub process: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u128,
+    pub event: AuditEvent,
+    pub path: Option<String>,
+    pub keys: Vec<String>,
+    pub detail: Option<String>,
+    pub peer: Option<Peer>,
+    pub success: bool,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> String {
+        let mut unhashed = self.clone();
+        unhashed.hash = String::new();
+        let json = serde_json::to_vec(&unhashed).unwrap();
+        let digest = sha256::hash(&json);
+        to_hex(&digest.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct ChainHead {
+    seq: u64,
+    hash: String,
+}
+
+impl ChainHead {
+    fn genesis() -> Self {
+        ChainHead {
+            seq: 0,
+            hash: GENESIS_HASH.to_string(),
+        }
+    }
+
+    fn of(entries: &[AuditEntry]) -> Self {
+        match entries.last() {
+            Some(entry) => ChainHead {
+                seq: entry.seq,
+                hash: entry.hash.clone(),
+            },
+            None => Self::genesis(),
+        }
+    }
+
+    fn read_pin() -> Result<Option<Self>, String> {
+        let head_file = config::get_audit_head_file();
+        if !head_file.exists() {
+            return Ok(None);
+        }
+        let json_bytes =
+            fs::read(&head_file).map_err(|e| format!("Unable to read audit head: {}", e))?;
+        serde_json::from_slice(&json_bytes)
+            .map(Some)
+            .map_err(|e| format!("Unable to parse audit head: {}", e))
+    }
+
+    fn write_pin(&self) -> Result<(), String> {
+        let json_bytes = serde_json::to_vec(self).unwrap();
+        durable::write_file(&config::get_audit_head_file(), &json_bytes)
+            .map_err(|e| format!("Unable to write audit head: {}", e))
+    }
+}
+
+// a log one entry past its pin that links to it is what a crash between appending an entry
+// and pinning it leaves behind, so it is accepted
+fn check_pin(entries: &[AuditEntry], pin: &ChainHead) -> Result<(), String> {
+    let head = ChainHead::of(entries);
+    if head == *pin {
+        return Ok(());
+    }
+    if let Some(last) = entries.last() {
+        if last.seq == pin.seq + 1 && last.prev_hash == pin.hash && last.compute_hash() == last.hash
+        {
+            return Ok(());
+        }
+    }
+    Err(format!(
+        "Audit log ends at entry {} but its pinned head is entry {}, entries were removed or rewritten",
+        head.seq, pin.seq
+    ))
+}
+
+static HEAD: Mutex<Option<ChainHead>> = Mutex::new(None);
+static PEER: Mutex<Option<Peer>> = Mutex::new(None);
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex += &format!("{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_peer(stream: &UnixStream) -> Option<Peer> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    let process = fs::read_link(format!("/proc/{}/exe", cred.pid))
+        .ok()
+        .map(|p| p.display().to_string());
+    Some(Peer {
+        pid: cred.pid,
+        uid: cred.uid,
+        process,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_peer(_stream: &UnixStream) -> Option<Peer> {
+    None
+}
+
+// remembers who is connected so every entry recorded for the request names them
+pub fn set_peer(peer: Option<Peer>) {
+    if let Ok(mut current) = PEER.lock() {
+        *current = peer;
+    }
+}
+
+pub fn read_entries() -> Result<Vec<AuditEntry>, String> {
+    let audit_file = config::get_audit_log_file();
+    if !audit_file.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(&audit_file).map_err(|e| format!("Unable to read audit log: {}", e))?;
+    let mut entries = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(line)
+            .map_err(|e| format!("Malformed audit entry on line {}: {}", idx + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn verify_links(entries: &[AuditEntry]) -> Result<(), String> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    for (idx, entry) in entries.iter().enumerate() {
+        if entry.seq != idx as u64 + 1 {
+            return Err(format!(
+                "Audit entry {} is out of sequence (expected {})",
+                entry.seq,
+                idx + 1
+            ));
+        }
+        if entry.prev_hash != prev_hash {
+            return Err(format!(
+                "Audit entry {} does not link to the entry before it",
+                entry.seq
+            ));
+        }
+        if entry.compute_hash() != entry.hash {
+            return Err(format!("Audit entry {} has been modified", entry.seq));
+        }
+        prev_hash = entry.hash.clone();
+    }
+    Ok(())
+}
+
+// checks every hash and link in the chain and that it ends at the pinned head, returning the
+// number of entries on success
+pub fn verify_chain(entries: &[AuditEntry]) -> Result<u64, String> {
+    verify_links(entries)?;
+    match ChainHead::read_pin()? {
+        Some(pin) => check_pin(entries, &pin)?,
+        None if !entries.is_empty() => {
+            return Err("Audit log has no pinned head to verify it against".to_string())
+        }
+        None => {}
+    }
+    Ok(entries.len() as u64)
+}
+
+// the head new entries link to. A log written before heads were pinned is taken as it is,
+// and pinned with its next entry.
+fn load_head() -> Result<ChainHead, String> {
+    let entries = read_entries()?;
+    let head = ChainHead::of(&entries);
+    if let Some(pin) = ChainHead::read_pin()? {
+        check_pin(&entries, &pin)?;
+        if head != pin {
+            head.write_pin()?;
+        }
+    }
+    Ok(head)
+}
+
+// pins the head of an intact chain, for a log whose pin fell behind or was lost. Returns the
+// previously pinned entry and the newly pinned one.
+pub fn repin(entries: &[AuditEntry]) -> Result<(Option<u64>, u64), String> {
+    verify_links(entries)?;
+    let previous = ChainHead::read_pin()?.map(|pin| pin.seq);
+    let head = ChainHead::of(entries);
+    head.write_pin()?;
+    Ok((previous, head.seq))
+}
+
+pub fn record(
+    event: AuditEvent,
+    path: Option<&str>,
+    keys: &[String],
+    detail: Option<&str>,
+    success: bool,
+) -> Result<(), String> {
+    let mut head = HEAD
+        .lock()
+        .map_err(|_| "Audit log state is poisoned".to_string())?;
+    if head.is_none() {
+        *head = Some(load_head()?);
+    }
+    let chain_head = head.as_mut().unwrap();
+
+    let peer = PEER.lock().ok().and_then(|p| p.clone());
+    let mut entry = AuditEntry {
+        seq: chain_head.seq + 1,
+        timestamp: timestamp::now_millis(),
+        event,
+        path: path.map(|p| p.to_string()),
+        keys: keys.to_vec(),
+        detail: detail.map(|d| d.to_string()),
+        peer,
+        success,
+        prev_hash: chain_head.hash.clone(),
+        hash: String::new(),
+    };
+    entry.hash = entry.compute_hash();
+
+    let mut line = serde_json::to_string(&entry)
+        .map_err(|e| format!("Unable to encode audit entry: {}", e))?;
+    line.push('\n');
+    let mut audit_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config::get_audit_log_file())
+        .map_err(|e| format!("Unable to open audit log: {}", e))?;
+    audit_file
+        .write_all(line.as_bytes())
+        .and_then(|_| audit_file.sync_data())
+        .map_err(|e| format!("Unable to write audit log: {}", e))?;
+
+    chain_head.seq = entry.seq;
+    chain_head.hash = entry.hash;
+    chain_head.write_pin()
+}
+
+// for events where a lost audit entry must not fail the operation that was already done
+pub fn record_or_log(
+    event: AuditEvent,
+    path: Option<&str>,
+    keys: &[String],
+    detail: Option<&str>,
+    success: bool,
+) {
+    if let Err(err) = record(event, path, keys, detail, success) {
+        super::logger::error(&err);
+    }
+}
@@ -0,0 +1,125 @@
+// Who may write where. Vaults are signed by their author, who must be in the keychain and,
+// where writers are set, one of them.
+use super::audit;
+use super::keychain;
+use super::private;
+use super::state;
+use super::vault;
+
+// the key to sign a vault written at path with, None only when no allowed key can sign
+pub fn find_author(st: &mut state::State, path: &str) -> Result<Option<private::Signer>, String> {
+    let path = vault::store_path(path);
+    let chain = st.get_chain()?;
+    let writers = chain.get_writers_for_path(&path).cloned();
+    let candidates = match writers.as_ref() {
+        Some(names) => chain.key_names_to_keys(names),
+        None => chain.get_keys().to_vec(),
+    };
+    if candidates.iter().all(|k| k.get_sign_key().is_none()) {
+        if writers.is_some() {
+            return Err(format!("None of the writers of {} can sign", path));
+        }
+        return Ok(None);
+    }
+    match st.keys.find_signer(&candidates)? {
+        Some(author) => Ok(Some(author)),
+        None => Err(format!(
+            "No key allowed to write {} could be unlocked",
+            path
+        )),
+    }
+}
+
+// for a vault that is about to be signed for the first time, as migrate does
+pub fn check_unsigned(st: &mut state::State, path: &str) -> Result<(), String> {
+    let path = vault::store_path(path);
+    if st.get_chain()?.get_writers_for_path(&path).is_some() {
+        return Err(format!(
+            "Vault at {} is not signed by an allowed writer",
+            path
+        ));
+    }
+    Ok(())
+}
+
+// returns the verified author, or None for an unsigned vault in a keychain where no key
+// can sign
+pub fn check_author(
+    st: &mut state::State,
+    path: &str,
+    signature: Option<&vault::Signature>,
+) -> Result<Option<String>, String> {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => {
+            check_unsigned(st, path)?;
+            if st.get_chain()?.can_sign() {
+                return Err(format!(
+                    "Vault at {} is not signed, run migrate to sign it",
+                    vault::store_path(path)
+                ));
+            }
+            return Ok(None);
+        }
+    };
+    let path = vault::store_path(path);
+    let chain = st.get_chain()?;
+    let writers = chain
+        .get_writers_for_path(&path)
+        .map(|names| chain.expand_names(names));
+    let author_key = chain
+        .key_names_to_keys(std::slice::from_ref(&signature.author))
+        .pop()
+        .ok_or(format!(
+            "Vault at {} is signed by {}, which is not in the keychain",
+            path, signature.author
+        ))?;
+    if !signature.verify(&author_key) {
+        return Err(format!("Signature of the vault at {} is invalid", path));
+    }
+    if let Some(writers) = writers {
+        if !writers.contains(&signature.author) {
+            return Err(format!(
+                "Vault at {} is written by {}, which may not write there",
+                path, signature.author
+            ));
+        }
+    }
+    Ok(Some(signature.author.clone()))
+}
+
+// an empty list of writers removes the rule for path
+pub fn set_writers(st: &mut state::State, path: &str, writers: Vec<String>) -> Result<(), String> {
+    let path = vault::store_path(path);
+    let chain = st.get_chain()?;
+    for name in writers.iter() {
+        if let Some(group) = name.strip_prefix(keychain::GROUP_PREFIX) {
+            if !chain.groups.contains_key(group) {
+                return Err(format!("Group {} is not in the keychain", name));
+            }
+            continue;
+        }
+        let key = chain.key_names_to_keys(std::slice::from_ref(name)).pop();
+        match key {
+            None => return Err(format!("Key {} is not in the keychain", name)),
+            Some(key) if key.get_sign_key().is_none() => {
+                return Err(format!("Key {} has no signing key to write with", name))
+            }
+            Some(_) => {}
+        }
+    }
+    if writers.is_empty() {
+        chain.writers.remove(&path);
+    } else {
+        chain.writers.insert(path.clone(), writers.clone());
+    }
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        Some(&path),
+        &writers,
+        Some("writers changed"),
+        true,
+    );
+    Ok(())
+}
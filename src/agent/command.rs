@@ -1,15 +1,71 @@
+use super::author;
+use super::device;
+use super::durable;
 use super::generate;
+use super::git;
+use super::group;
+use super::history;
+use super::keychain;
+use super::logger;
+use super::merge;
+use super::migrate;
+use super::paths;
+use super::policy;
+use super::private::KdfStrength;
+use super::public;
+use super::rules;
 use super::secret;
+use super::secure::SecretBytes;
+use super::secure::SecretString;
 use super::state;
+use super::threshold;
+use super::trash;
+use super::vault;
+use super::FrameReader;
+use super::FrameWriter;
 use crate::config;
 use serde::Deserialize;
 use serde::Serialize;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 
 #[derive(Serialize, Deserialize)]
 pub enum Command {
     AddKey(AddKeyRequest),
+    UpgradeKdf(UpgradeKdfRequest),
+    AddSigningKey(AddSigningKeyRequest),
+    ExportKey(ExportKeyRequest),
+    ImportKey(ImportKeyRequest),
+    Enroll(EnrollRequest),
+    ApproveDevice(ApproveDeviceRequest),
     Encrypt(EncryptRequest),
     Decrypt(DecryptRequest),
+    Attach(AttachRequest),
+    Extract(ExtractRequest),
+    Migrate(MigrateRequest),
+    SetWriters(SetWritersRequest),
+    SetAccess(SetAccessRequest),
+    ExplainAccess(ExplainAccessRequest),
+    GroupCreate(GroupRequest),
+    GroupAdd(GroupRequest),
+    GroupRemove(GroupRequest),
+    GroupShow(GroupShowRequest),
+    SetPolicy(SetPolicyRequest),
+    PolicyShow,
+    SetPadding(SetPaddingRequest),
+    PaddingShow,
+    SetThreshold(SetThresholdRequest),
+    ThresholdShow,
+    ThresholdShare(ThresholdShareRequest),
+    History(HistoryRequest),
+    Restore(RestoreRequest),
+    List(ListRequest),
+    Remove(RemoveRequest),
+    TrashList,
+    TrashRestore(TrashRestoreRequest),
+    TrashPurge(TrashPurgeRequest),
+    GitMerge(GitMergeRequest),
+    Textconv(TextconvRequest),
     Reload,
     Quit,
 }
@@ -25,50 +81,479 @@ pub enum KeyType {
 pub struct AddKeyRequest {
     name: String,
     keytype: KeyType,
+    kdf: KdfStrength,
 }
 
 impl AddKeyRequest {
-    pub fn new(name: String, keytype: KeyType) -> Self {
-        AddKeyRequest { name, keytype }
+    pub fn new(name: String, keytype: KeyType, kdf: KdfStrength) -> Self {
+        AddKeyRequest { name, keytype, kdf }
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct UpgradeKdfRequest {
+    name: String,
+    kdf: KdfStrength,
+}
+
+impl UpgradeKdfRequest {
+    pub fn new(name: String, kdf: KdfStrength) -> Self {
+        UpgradeKdfRequest { name, kdf }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddSigningKeyRequest {
+    name: String,
+}
+
+impl AddSigningKeyRequest {
+    pub fn new(name: String) -> Self {
+        AddSigningKeyRequest { name }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportKeyRequest {
+    name: String,
+}
+
+impl ExportKeyRequest {
+    pub fn new(name: String) -> Self {
+        ExportKeyRequest { name }
+    }
+}
+
+// replace allows a key of the same name and another fingerprint to be replaced
+#[derive(Serialize, Deserialize)]
+pub struct ImportKeyRequest {
+    key: public::ExportedKey,
+    replace: bool,
+}
+
+impl ImportKeyRequest {
+    pub fn new(key: public::ExportedKey, replace: bool) -> Self {
+        ImportKeyRequest { key, replace }
+    }
+}
+
+// makes the key of a new machine and its enrollment request, see agent::device
+#[derive(Serialize, Deserialize)]
+pub struct EnrollRequest {
+    name: String,
+    kdf: KdfStrength,
+}
+
+impl EnrollRequest {
+    pub fn new(name: String, kdf: KdfStrength) -> Self {
+        EnrollRequest { name, kdf }
+    }
+}
+
+// like names a key whose paths the new key is given as well
+#[derive(Serialize, Deserialize)]
+pub struct ApproveDeviceRequest {
+    request: device::EnrollmentRequest,
+    like: Option<String>,
+}
+
+impl ApproveDeviceRequest {
+    pub fn new(request: device::EnrollmentRequest, like: Option<String>) -> Self {
+        ApproveDeviceRequest { request, like }
+    }
+}
+
+// rev reads an earlier revision, see agent::history
 #[derive(Serialize, Deserialize)]
 pub struct DecryptRequest {
     path: String,
+    #[serde(default)]
+    rev: Option<u64>,
+    // shares of a threshold vault from other holders
+    #[serde(default)]
+    shares: Vec<threshold::ShareFile>,
 }
 
 impl DecryptRequest {
-    pub fn new(path: String) -> Self {
-        DecryptRequest { path }
+    pub fn new(path: String, rev: Option<u64>, shares: Vec<threshold::ShareFile>) -> Self {
+        DecryptRequest { path, rev, shares }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EncryptRequest {
     path: String,
-    contents: Vec<u8>,
+    contents: SecretBytes,
 }
 
 impl EncryptRequest {
-    pub fn new(path: String, contents: Vec<u8>) -> Self {
+    pub fn new(path: String, contents: SecretBytes) -> Self {
         EncryptRequest { path, contents }
     }
 }
 
+// the file contents follow the request on the socket, see FrameReader
+#[derive(Serialize, Deserialize)]
+pub struct AttachRequest {
+    path: String,
+}
+
+impl AttachRequest {
+    pub fn new(path: String) -> Self {
+        AttachRequest { path }
+    }
+}
+
+// the decrypted contents are sent back ahead of the response, see FrameWriter
+#[derive(Serialize, Deserialize)]
+pub struct ExtractRequest {
+    path: String,
+}
+
+impl ExtractRequest {
+    pub fn new(path: String) -> Self {
+        ExtractRequest { path }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MigrateRequest {
+    dry_run: bool,
+    hide_paths: bool,
+}
+
+impl MigrateRequest {
+    pub fn new(dry_run: bool, hide_paths: bool) -> Self {
+        MigrateRequest {
+            dry_run,
+            hide_paths,
+        }
+    }
+}
+
+// no writers removes the rule for the path
+#[derive(Serialize, Deserialize)]
+pub struct SetWritersRequest {
+    path: String,
+    writers: Vec<String>,
+}
+
+impl SetWritersRequest {
+    pub fn new(path: String, writers: Vec<String>) -> Self {
+        SetWritersRequest { path, writers }
+    }
+}
+
+// the rule for the vaults matching path, one without keys removes the rule for the path
+#[derive(Serialize, Deserialize)]
+pub struct SetAccessRequest {
+    path: String,
+    rule: rules::PathRule,
+}
+
+impl SetAccessRequest {
+    pub fn new(path: String, rule: rules::PathRule) -> Self {
+        SetAccessRequest { path, rule }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExplainAccessRequest {
+    path: String,
+}
+
+impl ExplainAccessRequest {
+    pub fn new(path: String) -> Self {
+        ExplainAccessRequest { path }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GroupRequest {
+    name: String,
+    keys: Vec<String>,
+}
+
+impl GroupRequest {
+    pub fn new(name: String, keys: Vec<String>) -> Self {
+        GroupRequest { name, keys }
+    }
+}
+
+// no name shows every group
+#[derive(Serialize, Deserialize)]
+pub struct GroupShowRequest {
+    name: Option<String>,
+}
+
+impl GroupShowRequest {
+    pub fn new(name: Option<String>) -> Self {
+        GroupShowRequest { name }
+    }
+}
+
+// the policy of the vaults matching path, an empty one removes the policy of the path
+#[derive(Serialize, Deserialize)]
+pub struct SetPolicyRequest {
+    path: String,
+    policy: policy::Policy,
+}
+
+impl SetPolicyRequest {
+    pub fn new(path: String, policy: policy::Policy) -> Self {
+        SetPolicyRequest { path, policy }
+    }
+}
+
+// how every vault of the store is padded from now on
+#[derive(Serialize, Deserialize)]
+pub struct SetPaddingRequest {
+    padding: vault::PaddingRule,
+}
+
+impl SetPaddingRequest {
+    pub fn new(padding: vault::PaddingRule) -> Self {
+        SetPaddingRequest { padding }
+    }
+}
+
+// the threshold rule of the vaults at or below path, no keys remove the rule of the path
+#[derive(Serialize, Deserialize)]
+pub struct SetThresholdRequest {
+    path: String,
+    required: u8,
+    keys: Vec<String>,
+}
+
+impl SetThresholdRequest {
+    pub fn new(path: String, required: u8, keys: Vec<String>) -> Self {
+        SetThresholdRequest {
+            path,
+            required,
+            keys,
+        }
+    }
+}
+
+// the share of the vault at path this machine holds, sealed for the recipient key
+#[derive(Serialize, Deserialize)]
+pub struct ThresholdShareRequest {
+    path: String,
+    recipient: String,
+}
+
+impl ThresholdShareRequest {
+    pub fn new(path: String, recipient: String) -> Self {
+        ThresholdShareRequest { path, recipient }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryRequest {
+    path: String,
+}
+
+impl HistoryRequest {
+    pub fn new(path: String) -> Self {
+        HistoryRequest { path }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RestoreRequest {
+    path: String,
+    rev: u64,
+}
+
+impl RestoreRequest {
+    pub fn new(path: String, rev: u64) -> Self {
+        RestoreRequest { path, rev }
+    }
+}
+
+// an empty path lists the whole store
+#[derive(Serialize, Deserialize)]
+pub struct ListRequest {
+    path: String,
+}
+
+impl ListRequest {
+    pub fn new(path: String) -> Self {
+        ListRequest { path }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveRequest {
+    path: String,
+}
+
+impl RemoveRequest {
+    pub fn new(path: String) -> Self {
+        RemoveRequest { path }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrashRestoreRequest {
+    path: String,
+}
+
+impl TrashRestoreRequest {
+    pub fn new(path: String) -> Self {
+        TrashRestoreRequest { path }
+    }
+}
+
+// older_than is in milliseconds, None purges everything
+#[derive(Serialize, Deserialize)]
+pub struct TrashPurgeRequest {
+    older_than: Option<u128>,
+}
+
+impl TrashPurgeRequest {
+    pub fn new(older_than: Option<u128>) -> Self {
+        TrashPurgeRequest { older_than }
+    }
+}
+
+// the revisions git hands its merge driver and the file they are of, the result replaces ours
+#[derive(Serialize, Deserialize)]
+pub struct GitMergeRequest {
+    base: PathBuf,
+    ours: PathBuf,
+    theirs: PathBuf,
+    file_name: String,
+}
+
+impl GitMergeRequest {
+    pub fn new(base: PathBuf, ours: PathBuf, theirs: PathBuf, file_name: String) -> Self {
+        GitMergeRequest {
+            base,
+            ours,
+            theirs,
+            file_name,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TextconvRequest {
+    file: PathBuf,
+}
+
+impl TextconvRequest {
+    pub fn new(file: PathBuf) -> Self {
+        TextconvRequest { file }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum Response {
-    AddKey(Option<String>),
-    Decrypt(Vec<u8>),
+    AddKey(Option<SecretString>),
+    UpgradeKdf,
+    AddSigningKey,
+    ExportKey(public::ExportedKey),
+    // the number of vaults encrypted for the key and the ones that could not be
+    ImportKey(usize, Vec<String>),
+    Enroll(device::EnrollmentRequest),
+    // the number of vaults encrypted for the new key and the ones that could not be
+    ApproveDevice(usize, Vec<String>),
+    // the contents and their verified author, if they are signed
+    Decrypt(SecretBytes, Option<String>),
     Encrypt,
+    Attach(u64),
+    Extract,
+    Migrate(Vec<String>),
+    SetWriters,
+    // the number of vaults encrypted again and the ones that could not be
+    SetAccess(usize, Vec<String>),
+    ExplainAccess(keychain::AccessExplanation),
+    Group(usize, Vec<String>),
+    GroupShow(Vec<group::Group>),
+    SetPolicy,
+    PolicyShow(Vec<(String, policy::Policy)>),
+    SetPadding,
+    PaddingShow(vault::PaddingRule),
+    // the number of vaults encrypted again and the ones that could not be
+    SetThreshold(usize, Vec<String>),
+    ThresholdShow(Vec<(String, threshold::Threshold)>),
+    ThresholdShare(threshold::ShareFile),
+    History(Vec<history::Revision>),
+    Restore,
+    List(Vec<String>),
+    Remove,
+    TrashList(Vec<trash::TrashEntry>),
+    TrashRestore,
+    TrashPurge(Vec<trash::TrashEntry>),
+    // conflicts left in or resolved in favour of ours
+    GitMerge(Vec<String>),
+    Textconv(SecretBytes),
     Reload,
 }
 
-pub fn process_command(st: &mut state::State, cmd: Command) -> Result<Response, String> {
+impl Command {
+    fn changes_store(&self) -> bool {
+        matches!(
+            self,
+            Command::AddKey(_)
+                | Command::AddSigningKey(_)
+                | Command::ImportKey(_)
+                | Command::ApproveDevice(_)
+                | Command::Encrypt(_)
+                | Command::Attach(_)
+                | Command::Migrate(_)
+                | Command::SetWriters(_)
+                | Command::SetAccess(_)
+                | Command::GroupCreate(_)
+                | Command::GroupAdd(_)
+                | Command::GroupRemove(_)
+                | Command::SetPolicy(_)
+                | Command::SetPadding(_)
+                | Command::SetThreshold(_)
+                | Command::Restore(_)
+                | Command::Remove(_)
+                | Command::TrashRestore(_)
+                | Command::TrashPurge(_)
+        )
+    }
+}
+
+pub fn process_command(
+    st: &mut state::State,
+    cmd: Command,
+    stream: &mut UnixStream,
+) -> Result<Response, String> {
+    // held until the command is done
+    let _lock = match cmd {
+        _ if !cmd.changes_store() => None,
+        Command::Attach(_) => match durable::StoreLock::acquire() {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                FrameReader::new(stream).drain()?;
+                return Err(err);
+            }
+        },
+        _ => Some(durable::StoreLock::acquire()?),
+    };
+    let changes_store = cmd.changes_store();
+    let res = run_command(st, cmd, stream);
+    // whatever was written is committed, even by a command that failed part way
+    if changes_store {
+        git::commit_changes(st);
+    }
+    res
+}
+
+fn run_command(
+    st: &mut state::State,
+    cmd: Command,
+    stream: &mut UnixStream,
+) -> Result<Response, String> {
     match cmd {
         Command::AddKey(req) => match req.keytype {
             KeyType::Sodium => {
-                generate::generate_sodium_key(st, &req.name)?;
+                generate::generate_sodium_key(st, &req.name, req.kdf)?;
                 Ok(Response::AddKey(None))
             }
             KeyType::PaperKey => {
@@ -77,21 +562,167 @@ pub fn process_command(st: &mut state::State, cmd: Command) -> Result<Response,
             }
             KeyType::Yubikey => Err("yubikey gen not yet implemented".to_string()),
         },
+        Command::UpgradeKdf(req) => {
+            generate::upgrade_kdf(&req.name, req.kdf)?;
+            Ok(Response::UpgradeKdf)
+        }
+        Command::AddSigningKey(req) => {
+            generate::add_signing_key(st, &req.name)?;
+            Ok(Response::AddSigningKey)
+        }
+        Command::ExportKey(req) => Ok(Response::ExportKey(generate::export_key(st, &req.name)?)),
+        Command::ImportKey(req) => {
+            let (reencrypted, failed) = generate::import_key(st, req.key, req.replace)?;
+            Ok(Response::ImportKey(reencrypted, failed))
+        }
+        Command::Enroll(req) => Ok(Response::Enroll(device::request(&req.name, req.kdf)?)),
+        Command::ApproveDevice(req) => {
+            let (reencrypted, failed) = device::approve(st, &req.request, req.like.as_deref())?;
+            Ok(Response::ApproveDevice(reencrypted, failed))
+        }
         Command::Encrypt(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Encrypting secret at {}", req.path),
+            );
             secret::write_secret(st, &req.path, req.contents)?;
             Ok(Response::Encrypt)
         }
         Command::Decrypt(req) => {
-            let contents = secret::read_secret(st, &req.path)?;
-            Ok(Response::Decrypt(contents))
+            logger::naming(
+                logger::Level::Info,
+                &format!("Decrypting secret at {}", req.path),
+            );
+            st.shares = req.shares;
+            let res = secret::read_secret(st, &req.path, req.rev);
+            st.shares.clear();
+            let (contents, author) = res?;
+            Ok(Response::Decrypt(contents, author))
+        }
+        Command::Attach(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Attaching file at {}", req.path),
+            );
+            let mut reader = FrameReader::new(stream);
+            let res = secret::attach_secret(st, &req.path, &mut reader);
+            // keeps the connection in step when the transfer stopped early
+            reader.drain()?;
+            Ok(Response::Attach(res?))
+        }
+        Command::Extract(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Extracting file at {}", req.path),
+            );
+            let mut writer = FrameWriter::new(stream);
+            let res = secret::extract_secret(st, &req.path, &mut writer);
+            writer.finish()?;
+            res.map(|_| Response::Extract)
+        }
+        Command::Migrate(req) => {
+            let migrated = migrate::migrate_store(st, req.dry_run, req.hide_paths)?;
+            Ok(Response::Migrate(migrated))
+        }
+        Command::SetWriters(req) => {
+            author::set_writers(st, &req.path, req.writers)?;
+            Ok(Response::SetWriters)
+        }
+        Command::SetAccess(req) => {
+            let (reencrypted, failed) = paths::change_keys_for_path(st, &req.path, req.rule)?;
+            Ok(Response::SetAccess(reencrypted, failed))
+        }
+        Command::ExplainAccess(req) => {
+            let path = vault::store_path(&req.path);
+            Ok(Response::ExplainAccess(st.get_chain()?.explain(&path)))
+        }
+        Command::GroupCreate(req) => {
+            let (reencrypted, failed) = group::create(st, &req.name, req.keys)?;
+            Ok(Response::Group(reencrypted, failed))
+        }
+        Command::GroupAdd(req) => {
+            let (reencrypted, failed) = group::add(st, &req.name, req.keys)?;
+            Ok(Response::Group(reencrypted, failed))
+        }
+        Command::GroupRemove(req) => {
+            let (reencrypted, failed) = group::remove(st, &req.name, req.keys)?;
+            Ok(Response::Group(reencrypted, failed))
+        }
+        Command::GroupShow(req) => Ok(Response::GroupShow(group::show(st, req.name.as_deref())?)),
+        Command::SetPolicy(req) => {
+            policy::set(st, &req.path, req.policy)?;
+            Ok(Response::SetPolicy)
+        }
+        Command::PolicyShow => Ok(Response::PolicyShow(policy::show(st)?)),
+        Command::SetPadding(req) => {
+            policy::set_padding(st, req.padding)?;
+            Ok(Response::SetPadding)
+        }
+        Command::PaddingShow => Ok(Response::PaddingShow(st.get_chain()?.padding)),
+        Command::SetThreshold(req) => {
+            let (reencrypted, failed) = threshold::set(st, &req.path, req.required, req.keys)?;
+            Ok(Response::SetThreshold(reencrypted, failed))
+        }
+        Command::ThresholdShow => Ok(Response::ThresholdShow(threshold::show(st)?)),
+        Command::ThresholdShare(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Sharing the key share of {}", req.path),
+            );
+            let share = threshold::share(st, &req.path, &req.recipient)?;
+            Ok(Response::ThresholdShare(share))
+        }
+        Command::History(req) => {
+            let revisions = secret::list_history(st, &req.path)?;
+            Ok(Response::History(revisions))
+        }
+        Command::Restore(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Restoring revision {} of {}", req.rev, req.path),
+            );
+            secret::restore_secret(st, &req.path, req.rev)?;
+            Ok(Response::Restore)
+        }
+        Command::List(req) => Ok(Response::List(paths::list_files(st, &req.path)?)),
+        Command::Remove(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Moving {} to the trash", req.path),
+            );
+            trash::remove_secret(st, &req.path)?;
+            Ok(Response::Remove)
+        }
+        Command::TrashList => Ok(Response::TrashList(trash::list(st)?)),
+        Command::TrashRestore(req) => {
+            logger::naming(
+                logger::Level::Info,
+                &format!("Restoring {} from the trash", req.path),
+            );
+            trash::restore_secret(st, &req.path)?;
+            Ok(Response::TrashRestore)
+        }
+        Command::TrashPurge(req) => {
+            let purged = trash::purge(st, req.older_than)?;
+            Ok(Response::TrashPurge(purged))
+        }
+        // the store is locked by the git pull this runs under
+        Command::GitMerge(req) => {
+            logger::info(&format!("Merging revisions of {}", req.file_name));
+            let conflicts =
+                merge::merge_files(st, &req.base, &req.ours, &req.theirs, &req.file_name)?;
+            Ok(Response::GitMerge(conflicts))
         }
+        Command::Textconv(req) => Ok(Response::Textconv(merge::textconv(st, &req.file)?)),
         Command::Reload => {
+            logger::reset();
+            logger::info("Reloading agent state");
             *st = state::State::new();
             Ok(Response::Reload)
         }
         Command::Quit => {
             let _ = std::fs::remove_file(config::get_agent_socket_file());
-            super::log_message("Shutting down agent");
+            logger::info("Shutting down agent");
             std::process::exit(0);
         }
     }
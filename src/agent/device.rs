@@ -0,0 +1,170 @@
+// Enrolling a new machine through a signed request an enrolled machine approves, both
+// showing a code to compare.
+use super::audit;
+use super::generate;
+use super::keychain;
+use super::logger;
+use super::paths;
+use super::private;
+use super::private::PrivateKey;
+use super::public::PublicKey;
+use super::public::PublicKeyWrapper;
+use super::state;
+use super::timestamp;
+use serde::Deserialize;
+use serde::Serialize;
+use sodiumoxide::crypto::hash::sha256;
+
+const SIGNATURE_CONTEXT: &[u8] = b"basalt enrollment\0";
+// how long a request can be approved, in milliseconds
+const REQUEST_LIFETIME: u128 = 7 * 24 * 60 * 60 * 1000;
+// 80 bits of the hash, too many to grind a request with the code of another
+const CODE_GROUPS: usize = 5;
+
+#[derive(Serialize, Deserialize)]
+pub struct Enrollment {
+    pub name: String,
+    pub key: PublicKeyWrapper,
+    pub created: u128,
+}
+
+// the request file, the enrollment is kept as the exact json that was signed
+#[derive(Serialize, Deserialize)]
+pub struct EnrollmentRequest {
+    request: String,
+    signature: Vec<u8>,
+}
+
+impl EnrollmentRequest {
+    // the enrollment, once the key it names is shown to have signed it
+    pub fn open(&self) -> Result<Enrollment, String> {
+        let enrollment: Enrollment = serde_json::from_str(&self.request)
+            .map_err(|e| format!("Unable to parse enrollment request: {}", e))?;
+        if enrollment.key.get_key_name() != enrollment.name {
+            return Err("The enrollment request names another key than it holds".to_string());
+        }
+        if !enrollment
+            .key
+            .verify(&signed_message(&self.request), &self.signature)
+        {
+            return Err("The enrollment request is not signed by the key it holds".to_string());
+        }
+        Ok(enrollment)
+    }
+
+    // groups of four hex digits of the hash of the request
+    pub fn verification_code(&self) -> String {
+        let digest = sha256::hash(&signed_message(&self.request));
+        digest.0[..CODE_GROUPS * 2]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<String>>()
+            .join("-")
+    }
+}
+
+fn signed_message(request_json: &str) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(request_json.as_bytes());
+    message
+}
+
+// makes the device key of this machine, which stays out of the keychain until approved
+pub fn request(key_name: &str, kdf: private::KdfStrength) -> Result<EnrollmentRequest, String> {
+    if private::DeviceKey::get_device_key_names()
+        .iter()
+        .any(|name| name == key_name)
+    {
+        return Err(format!("This machine already has a key named {}", key_name));
+    }
+    let (new_key, sec_key) = generate::new_device_key(key_name, kdf)?;
+    let enrollment = Enrollment {
+        name: key_name.to_string(),
+        key: new_key.get_public_key(key_name),
+        created: timestamp::now_millis(),
+    };
+    let request = serde_json::to_string(&enrollment)
+        .map_err(|e| format!("Unable to serialize enrollment request: {}", e))?;
+    let signature = new_key.sign(&signed_message(&request))?;
+    sec_key.write_key(key_name);
+    logger::info(&format!("Generated sodium key {} to enroll", key_name));
+    audit::record_or_log(
+        audit::AuditEvent::KeyAdded,
+        None,
+        &[key_name.to_string()],
+        Some("enrollment requested"),
+        true,
+    );
+    Ok(EnrollmentRequest { request, signature })
+}
+
+// adds the requested key, with the paths and groups of like when given, returning the number of vaults
+// encrypted again and the ones that could not be
+pub fn approve(
+    st: &mut state::State,
+    request: &EnrollmentRequest,
+    like: Option<&str>,
+) -> Result<(usize, Vec<String>), String> {
+    let enrollment = request.open()?;
+    let name = enrollment.name.clone();
+    if timestamp::now_millis() > enrollment.created + REQUEST_LIFETIME {
+        return Err(format!(
+            "The enrollment request of {} has expired, make a new one",
+            name
+        ));
+    }
+    let chain = st.get_chain()?;
+    if !chain
+        .key_names_to_keys(std::slice::from_ref(&name))
+        .is_empty()
+    {
+        return Err(format!("Key {} is already in the keychain", name));
+    }
+    let fingerprint = enrollment.key.fingerprint();
+    if let Some(key) = chain
+        .get_keys()
+        .iter()
+        .find(|k| k.fingerprint() == fingerprint)
+    {
+        return Err(format!(
+            "The key of {} is already in the keychain as {}",
+            name,
+            key.get_key_name()
+        ));
+    }
+    if let Some(like) = like {
+        if chain.key_names_to_keys(&[like.to_string()]).is_empty() {
+            return Err(format!("Key {} is not in the keychain", like));
+        }
+    }
+
+    let before = paths::recipients_by_path(st)?;
+    let chain = st.get_chain()?;
+    chain.add_key(enrollment.key);
+    if let Some(like) = like {
+        let path_rules = chain.paths.values_mut().map(|rule| &mut rule.keys);
+        let rules = path_rules.chain(chain.groups.values_mut());
+        for names in rules {
+            if names.iter().any(|n| n == like) && !names.contains(&name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    st.record_change(&format!("enroll device {}", name), None, None);
+    keychain::KeyChain::write_chain(st)?;
+    paths::write_index(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::KeyAdded,
+        None,
+        std::slice::from_ref(&name),
+        Some("device enrolled"),
+        true,
+    );
+
+    let (reencrypted, failed) = paths::reencrypt_changed(st, before)?;
+    logger::info(&format!(
+        "Enrolled device {}, {} vaults encrypted for it",
+        name, reencrypted
+    ));
+    Ok((reencrypted, failed))
+}
@@ -0,0 +1,122 @@
+// Crash safe file writes, through a synced temporary file renamed into place, and the
+// store lock.
+use crate::config;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time;
+
+const LOCK_FILE_NAME: &str = ".lock";
+const LOCK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const LOCK_RETRY_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+// where a file is written before being renamed into place, hidden from store listings
+pub fn partial_path(path: &Path) -> Result<PathBuf, String> {
+    let file_name = path
+        .file_name()
+        .ok_or(format!("Invalid file path: {}", path.display()))?;
+    let mut partial_name = OsString::from(".");
+    partial_name.push(file_name);
+    partial_name.push(".partial");
+    Ok(path.with_file_name(partial_name))
+}
+
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    write_file_with(path, &mut |file| {
+        file.write_all(contents)
+            .map_err(|e| format!("filesystem error: {}", e))
+    })
+}
+
+// write fills the file, which only replaces path once write succeeded and it is on disk
+pub fn write_file_with<T>(
+    path: &Path,
+    write: &mut dyn FnMut(&mut File) -> Result<T, String>,
+) -> Result<T, String> {
+    let partial_path = partial_path(path)?;
+    let res = File::create(&partial_path)
+        .map_err(|e| format!("filesystem error: {}", e))
+        .and_then(|mut file| {
+            let res = write(&mut file)?;
+            file.sync_all()
+                .map_err(|e| format!("filesystem error: {}", e))?;
+            Ok(res)
+        })
+        .and_then(|res| {
+            fs::rename(&partial_path, path).map_err(|e| format!("filesystem error: {}", e))?;
+            Ok(res)
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&partial_path);
+        return res;
+    }
+    sync_parent(path)?;
+    res
+}
+
+// makes a rename or removal in the directory of path durable
+pub fn sync_parent(path: &Path) -> Result<(), String> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| format!("Unable to sync {}: {}", dir.display(), e))
+}
+
+// when a file was last changed, to notice it being rewritten by another process
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileStamp {
+    modified: time::SystemTime,
+    len: u64,
+}
+
+pub fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some(FileStamp {
+        modified: metadata.modified().ok()?,
+        len: metadata.len(),
+    })
+}
+
+pub fn lock_path() -> PathBuf {
+    config::get_store_directory().join(LOCK_FILE_NAME)
+}
+
+// An advisory lock over the whole store, held while a command changes it so writes of
+// different agents or processes are not interleaved. Released when dropped.
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    pub fn acquire() -> Result<StoreLock, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path())
+            .map_err(|e| format!("Unable to open store lock: {}", e))?;
+        let started = time::Instant::now();
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                return Ok(StoreLock { _file: file });
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(format!("Unable to lock the store: {}", err));
+            }
+            if started.elapsed() > LOCK_TIMEOUT {
+                return Err("Store is locked by another process".to_string());
+            }
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+}
@@ -1,35 +1,205 @@
+use super::audit;
+use super::keychain;
+use super::logger;
 use super::passphrase;
+use super::paths;
 use super::private;
 use super::public;
+use super::public::PublicKey;
+use super::secure::SecretString;
 use super::state;
 
-pub fn generate_sodium_key(st: &mut state::State, key_name: &str) -> Result<(), String> {
-    let mut keychain = st.get_chain()?;
-    let pin = passphrase::generate_pin(key_name)?;
-    let has_pin = !pin.is_empty();
+// a new key and its key file contents, under a PIN the user picks
+pub fn new_device_key(
+    key_name: &str,
+    kdf: private::KdfStrength,
+) -> Result<(private::SodiumPrivateKey, private::DeviceKey), String> {
+    let pin = passphrase::generate_pin(key_name).map_err(|e| e.to_string())?;
     let new_key = private::SodiumPrivateKey::gen_key();
-    let pub_key = new_key.get_public_key(key_name);
-
-    let sec_key = if has_pin {
+    let sec_key = if !pin.is_empty() {
         private::DeviceKey::Encrypted(private::EncryptedSodiumKey::encrypt_key(
             &new_key,
             pin.as_bytes(),
+            private::KdfParams::new(kdf),
         )?)
     } else {
-        private::DeviceKey::Unencrypted(new_key)
+        private::DeviceKey::Unencrypted(new_key.clone())
     };
+    Ok((new_key, sec_key))
+}
 
-    keychain.add_key(pub_key);
-    keychain.write_chain();
+pub fn generate_sodium_key(
+    st: &mut state::State,
+    key_name: &str,
+    kdf: private::KdfStrength,
+) -> Result<(), String> {
+    let (new_key, sec_key) = new_device_key(key_name, kdf)?;
+    let pub_key = new_key.get_public_key(key_name);
+    // lets the new key sign the keychain when no other key is trusted yet
+    st.keys
+        .session_unlocked
+        .insert(key_name.to_string(), Box::new(new_key));
+
+    st.get_chain()?.add_key(pub_key);
+    keychain::KeyChain::write_chain(st)?;
+    paths::write_index(st)?;
     sec_key.write_key(key_name);
+    logger::info(&format!("Generated sodium key {}", key_name));
+    audit::record_or_log(
+        audit::AuditEvent::KeyAdded,
+        None,
+        &[key_name.to_string()],
+        Some("sodium"),
+        true,
+    );
     Ok(())
 }
 
-pub fn generate_paper_key(st: &mut state::State, key_name: &str) -> Result<String, String> {
+pub fn generate_paper_key(st: &mut state::State, key_name: &str) -> Result<SecretString, String> {
     let (paperkey, pubkey) = public::PaperKey::new(key_name.to_string());
     let pubkey = public::PublicKeyWrapper::PaperKey(pubkey);
-    let keychain = st.get_chain()?;
-    keychain.add_key(pubkey);
-    keychain.write_chain();
+    st.get_chain()?.add_key(pubkey);
+    keychain::KeyChain::write_chain(st)?;
+    paths::write_index(st)?;
+    logger::info(&format!("Generated paper key {}", key_name));
+    audit::record_or_log(
+        audit::AuditEvent::KeyAdded,
+        None,
+        &[key_name.to_string()],
+        Some("paper key"),
+        true,
+    );
     Ok(paperkey)
 }
+
+pub fn upgrade_kdf(key_name: &str, kdf: private::KdfStrength) -> Result<(), String> {
+    let enc_key = match private::DeviceKey::read_key(key_name)? {
+        private::DeviceKey::Encrypted(enc_key) => enc_key,
+        private::DeviceKey::Unencrypted(_) => {
+            return Err(format!("Key {} is not protected by a PIN", key_name))
+        }
+    };
+    let new_kdf = private::KdfParams::new(kdf);
+    if !new_kdf.is_stronger_than(enc_key.get_kdf()) {
+        return Err(format!(
+            "Key {} already uses parameters at least as strong as requested",
+            key_name
+        ));
+    }
+
+    let unlocked = enc_key.unlock(key_name)?;
+    if unlocked.is_none() {
+        return Err(format!("Unable to unlock key {}", key_name));
+    }
+    let (dec_key, pin) = unlocked.unwrap();
+    let new_key = private::EncryptedSodiumKey::encrypt_key(&dec_key, pin.as_bytes(), new_kdf)?;
+    private::DeviceKey::Encrypted(new_key).write_key(key_name);
+    logger::info(&format!("Upgraded KDF parameters of key {}", key_name));
+    Ok(())
+}
+
+// gives a key made before keychains were signed its signing key, under the same PIN
+pub fn add_signing_key(st: &mut state::State, key_name: &str) -> Result<(), String> {
+    if st
+        .get_chain()?
+        .key_names_to_keys(&[key_name.to_string()])
+        .is_empty()
+    {
+        return Err(format!("Key {} is not in the keychain", key_name));
+    }
+    let (mut dec_key, pin_and_kdf) = match private::DeviceKey::read_key(key_name)? {
+        private::DeviceKey::Encrypted(enc_key) => {
+            let unlocked = enc_key.unlock(key_name)?;
+            if unlocked.is_none() {
+                return Err(format!("Unable to unlock key {}", key_name));
+            }
+            let (dec_key, pin) = unlocked.unwrap();
+            (dec_key, Some((pin, enc_key.get_kdf().clone())))
+        }
+        private::DeviceKey::Unencrypted(dec_key) => (dec_key, None),
+    };
+    if dec_key.has_signing_key() {
+        return Err(format!("Key {} already has a signing key", key_name));
+    }
+
+    dec_key.add_signing_key();
+    let sec_key = match pin_and_kdf {
+        Some((pin, kdf)) => private::DeviceKey::Encrypted(
+            private::EncryptedSodiumKey::encrypt_key(&dec_key, pin.as_bytes(), kdf)?,
+        ),
+        None => private::DeviceKey::Unencrypted(dec_key.clone()),
+    };
+    // the key file goes first so the keychain never lists a signing key that was lost
+    sec_key.write_key(key_name);
+    st.get_chain()?.add_key(dec_key.get_public_key(key_name));
+    st.keys
+        .session_unlocked
+        .insert(key_name.to_string(), Box::new(dec_key));
+    keychain::KeyChain::write_chain(st)?;
+    logger::info(&format!("Added a signing key to key {}", key_name));
+    Ok(())
+}
+
+pub fn export_key(st: &mut state::State, key_name: &str) -> Result<public::ExportedKey, String> {
+    let key = st
+        .get_chain()?
+        .key_names_to_keys(&[key_name.to_string()])
+        .pop()
+        .ok_or(format!("Key {} is not in the keychain", key_name))?;
+    Ok(public::ExportedKey::new(key))
+}
+
+// adds someone else's key, replacing one of the same name only when asked to, and returns
+// the number of vaults encrypted again for it and the ones that could not be
+pub fn import_key(
+    st: &mut state::State,
+    exported: public::ExportedKey,
+    replace: bool,
+) -> Result<(usize, Vec<String>), String> {
+    exported.check()?;
+    let key = exported.key;
+    let key_name = key.get_key_name().to_string();
+    let fingerprint = key.fingerprint();
+    let chain = st.get_chain()?;
+    if let Some(existing) = chain
+        .key_names_to_keys(std::slice::from_ref(&key_name))
+        .pop()
+    {
+        if existing.fingerprint() == fingerprint {
+            return Err(format!("Key {} is already in the keychain", key_name));
+        }
+        if !replace {
+            return Err(format!(
+                "Key {} in the keychain has another fingerprint: {}",
+                key_name,
+                existing.fingerprint()
+            ));
+        }
+    }
+    if let Some(existing) = chain
+        .get_keys()
+        .iter()
+        .find(|k| k.fingerprint() == fingerprint && k.get_key_name() != key_name)
+    {
+        return Err(format!(
+            "The key of {} is already in the keychain as {}",
+            key_name,
+            existing.get_key_name()
+        ));
+    }
+
+    let before = paths::recipients_by_path(st)?;
+    st.get_chain()?.add_key(key);
+    st.record_change(&format!("import key {}", key_name), None, None);
+    keychain::KeyChain::write_chain(st)?;
+    paths::write_index(st)?;
+    logger::info(&format!("Imported key {}", key_name));
+    audit::record_or_log(
+        audit::AuditEvent::KeyAdded,
+        None,
+        std::slice::from_ref(&key_name),
+        Some(&format!("imported, fingerprint {}", fingerprint)),
+        true,
+    );
+    paths::reencrypt_changed(st, before)
+}
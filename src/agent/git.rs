@@ -0,0 +1,253 @@
+// Stores shared through git, whatever a command writes being committed once it is done.
+use super::index;
+use super::logger;
+use super::state;
+use crate::config;
+use crate::constants;
+use std::fs;
+use std::process;
+
+const GIT_DIR_NAME: &str = ".git";
+const GITIGNORE_FILE_NAME: &str = ".gitignore";
+const GITIGNORE: &str = ".lock
+.*.partial
+.history/
+.trash/
+.keychain.json.prev
+";
+const GITATTRIBUTES_FILE_NAME: &str = ".gitattributes";
+// the merge driver and diff converter git is configured with, see client::git
+pub const MERGE_DRIVER: &str = "basalt";
+// used when the user has no git identity configured
+const FALLBACK_NAME: &str = "basalt";
+const FALLBACK_EMAIL: &str = "basalt@localhost";
+
+// one change made by a command, see State::record_change
+pub struct Change {
+    operation: String,
+    path: Option<String>,
+    author: Option<String>,
+}
+
+impl Change {
+    pub fn new(operation: &str, path: Option<&str>, author: Option<&str>) -> Self {
+        // the commit message would otherwise give away what the index hides
+        let path = path.filter(|_| !index::PathIndex::exists());
+        Change {
+            operation: operation.to_string(),
+            path: path.map(|p| p.to_string()),
+            author: author.map(|a| a.to_string()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut line = self.operation.clone();
+        if let Some(path) = self.path.as_ref() {
+            line += &format!(" {}", path);
+        }
+        if let Some(author) = self.author.as_ref() {
+            line += &format!(" by {}", author);
+        }
+        line
+    }
+}
+
+pub fn is_repository() -> bool {
+    config::get_store_directory().join(GIT_DIR_NAME).exists()
+}
+
+// git working in the store directory, with an identity for commits where git has none
+pub fn command() -> process::Command {
+    let mut command = process::Command::new("git");
+    command.arg("-C").arg(config::get_store_directory());
+    if !has_config("user.name") {
+        command.env("GIT_AUTHOR_NAME", FALLBACK_NAME);
+        command.env("GIT_COMMITTER_NAME", FALLBACK_NAME);
+    }
+    if !has_config("user.email") {
+        command.env("GIT_AUTHOR_EMAIL", FALLBACK_EMAIL);
+        command.env("GIT_COMMITTER_EMAIL", FALLBACK_EMAIL);
+    }
+    command
+}
+
+fn has_config(name: &str) -> bool {
+    process::Command::new("git")
+        .arg("-C")
+        .arg(config::get_store_directory())
+        .args(["config", name])
+        .stdout(process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+// runs git in the store directory, returning its output
+pub fn run(args: &[&str]) -> Result<String, String> {
+    let output = command()
+        .args(args)
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn write_if_missing(file_name: &str, contents: &str) -> Result<(), String> {
+    let path = config::get_store_directory().join(file_name);
+    if path.exists() {
+        return Ok(());
+    }
+    fs::write(&path, contents).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+// adds the lines an existing file lacks, keeping what others put in it
+fn write_lines(file_name: &str, lines: &[String]) -> Result<(), String> {
+    let path = config::get_store_directory().join(file_name);
+    let mut contents = fs::read_to_string(&path).unwrap_or_default();
+    let missing: Vec<&String> = lines
+        .iter()
+        .filter(|line| !contents.lines().any(|l| l.trim() == line.as_str()))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for line in missing {
+        contents += &format!("{}\n", line);
+    }
+    fs::write(&path, contents).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+// what is ignored and how vaults are merged and diffed, shared with everyone using the store
+pub fn write_gitfiles() -> Result<(), String> {
+    write_if_missing(GITIGNORE_FILE_NAME, GITIGNORE)?;
+    // stores from before vaults were merged had the keychain merged on its own
+    let attributes = [
+        format!("* merge={} diff={}", MERGE_DRIVER, MERGE_DRIVER),
+        ".git* !merge !diff".to_string(),
+    ];
+    let path = config::get_store_directory().join(GITATTRIBUTES_FILE_NAME);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let keychain_only = format!("{} merge=basalt-keychain", constants::KEYCHAIN_FILE_NAME);
+    if existing.lines().any(|l| l.trim() == keychain_only) {
+        let kept: String = existing
+            .lines()
+            .filter(|l| l.trim() != keychain_only)
+            .map(|l| format!("{}\n", l))
+            .collect();
+        fs::write(&path, kept).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    }
+    write_lines(GITATTRIBUTES_FILE_NAME, &attributes)
+}
+
+pub fn commit(message: &str) -> Result<bool, String> {
+    run(&["add", "-A"])?;
+    if run(&["diff", "--cached", "--quiet"]).is_ok() {
+        return Ok(false);
+    }
+    run(&["commit", "--quiet", "-m", message])?;
+    Ok(true)
+}
+
+fn commit_message(changes: &[Change]) -> String {
+    let mut lines: Vec<String> = changes.iter().map(|c| c.describe()).collect();
+    lines.dedup();
+    match lines.len() {
+        0 => format!("Update {} store", constants::APP_NAME),
+        1 => lines.remove(0),
+        _ => format!("{}\n\n{}", lines[0], lines[1..].join("\n")),
+    }
+}
+
+// commits what the last command changed, a failed commit leaves the changes in the tree
+pub fn commit_changes(st: &mut state::State) {
+    let changes = st.take_changes();
+    if !is_repository() {
+        return;
+    }
+    let res = write_gitfiles().and_then(|_| commit(&commit_message(&changes)));
+    if let Err(err) = res {
+        logger::error(&format!(
+            "Unable to commit to the store repository: {}",
+            err
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn write_secret(contents: &str) {
+        fs::write(config::get_store_directory().join("secret"), contents).unwrap();
+    }
+
+    fn read_secret() -> String {
+        fs::read_to_string(config::get_store_directory().join("secret")).unwrap()
+    }
+
+    fn git_in(dir: &Path, args: &[&str]) {
+        let status = process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn shares_a_store_through_a_bare_repository() {
+        let remote = tempfile::tempdir().unwrap();
+        let remote_url = remote.path().to_str().unwrap();
+        git_in(remote.path(), &["init", "--quiet", "--bare"]);
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+
+        config::with_home(first.path(), || {
+            run(&["init", "--quiet"]).unwrap();
+            write_gitfiles().unwrap();
+            write_secret("one");
+            assert!(commit("add secret").unwrap());
+            assert!(!commit("nothing").unwrap());
+            let branch = run(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap();
+            let head = format!("refs/heads/{}", branch.trim());
+            git_in(remote.path(), &["symbolic-ref", "HEAD", &head]);
+            run(&["remote", "add", "origin", remote_url]).unwrap();
+            run(&["push", "--quiet", "--set-upstream", "origin", "HEAD"]).unwrap();
+        });
+
+        config::with_home(second.path(), || {
+            let store_dir = config::get_store_directory();
+            git_in(
+                second.path(),
+                &["clone", "--quiet", remote_url, store_dir.to_str().unwrap()],
+            );
+            assert!(is_repository());
+            assert_eq!(read_secret(), "one");
+        });
+
+        config::with_home(first.path(), || {
+            write_secret("two");
+            assert!(commit("change secret").unwrap());
+            run(&["push", "--quiet"]).unwrap();
+        });
+
+        config::with_home(second.path(), || {
+            run(&["pull", "--quiet", "--no-rebase", "--no-edit"]).unwrap();
+            assert_eq!(read_secret(), "two");
+            let log = run(&["log", "--format=%s"]).unwrap();
+            assert_eq!(log, "change secret\nadd secret\n");
+        });
+    }
+}
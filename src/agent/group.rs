@@ -0,0 +1,193 @@
+// Groups of keys that path and writer rules name as @group.
+use super::audit;
+use super::keychain;
+use super::keychain::GROUP_PREFIX;
+use super::paths;
+use super::policy;
+use super::public::PublicKey;
+use super::state;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub keys: Vec<String>,
+    // the paths whose rules name the group
+    pub paths: Vec<String>,
+    pub writers: Vec<String>,
+}
+
+// the name of a group as the keychain keeps it, without the prefix
+fn group_name(name: &str) -> Result<String, String> {
+    let name = name.strip_prefix(GROUP_PREFIX).unwrap_or(name);
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format!("Invalid group name: {}", name));
+    }
+    Ok(name.to_string())
+}
+
+fn check_keys(st: &mut state::State, keys: &[String]) -> Result<(), String> {
+    let chain = st.get_chain()?;
+    for key in keys.iter() {
+        if !chain.get_keys().iter().any(|k| k.get_key_name() == key) {
+            return Err(format!("Key {} is not in the keychain", key));
+        }
+    }
+    Ok(())
+}
+
+fn rules_naming<'a, I>(rules: I, group: &str) -> Vec<String>
+where
+    I: Iterator<Item = (&'a String, &'a Vec<String>)>,
+{
+    let reference = format!("{}{}", GROUP_PREFIX, group);
+    let mut paths: Vec<String> = rules
+        .filter(|(_, names)| names.contains(&reference))
+        .map(|(path, _)| path.clone())
+        .collect();
+    paths.sort();
+    paths
+}
+
+// changes the groups of the keychain and encrypts again the vaults that changes, returning
+// the number of vaults encrypted again and the ones that could not be
+fn change_groups<F>(
+    st: &mut state::State,
+    group: &str,
+    keys: &[String],
+    operation: &str,
+    change: F,
+) -> Result<(usize, Vec<String>), String>
+where
+    F: FnOnce(&mut HashMap<String, Vec<String>>) -> Result<(), String>,
+{
+    let before = paths::recipients_by_path(st)?;
+    let previous = st.get_chain()?.groups.clone();
+    change(&mut st.get_chain()?.groups)?;
+    if let Err(err) = policy::check_store(st) {
+        st.get_chain()?.groups = previous;
+        return Err(err);
+    }
+    let description = format!("{} group {}{}", operation, GROUP_PREFIX, group);
+    st.record_change(&description, None, None);
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        None,
+        keys,
+        Some(&description),
+        true,
+    );
+    paths::reencrypt_changed(st, before)
+}
+
+pub fn create(
+    st: &mut state::State,
+    name: &str,
+    keys: Vec<String>,
+) -> Result<(usize, Vec<String>), String> {
+    let group = group_name(name)?;
+    check_keys(st, &keys)?;
+    if st.get_chain()?.groups.contains_key(&group) {
+        return Err(format!("Group {}{} already exists", GROUP_PREFIX, group));
+    }
+    let members = keys.clone();
+    change_groups(st, &group, &keys, "create", |groups| {
+        groups.insert(group.clone(), members);
+        Ok(())
+    })
+}
+
+pub fn add(
+    st: &mut state::State,
+    name: &str,
+    keys: Vec<String>,
+) -> Result<(usize, Vec<String>), String> {
+    let group = group_name(name)?;
+    check_keys(st, &keys)?;
+    change_groups(st, &group, &keys, "add to", |groups| {
+        let members = groups
+            .get_mut(&group)
+            .ok_or(format!("Group {}{} does not exist", GROUP_PREFIX, group))?;
+        for key in keys.iter() {
+            if !members.contains(key) {
+                members.push(key.clone());
+            }
+        }
+        Ok(())
+    })
+}
+
+// removes keys from the group, or the group itself when no keys are given
+pub fn remove(
+    st: &mut state::State,
+    name: &str,
+    keys: Vec<String>,
+) -> Result<(usize, Vec<String>), String> {
+    let group = group_name(name)?;
+    let chain = st.get_chain()?;
+    let members = chain
+        .groups
+        .get(&group)
+        .ok_or(format!("Group {}{} does not exist", GROUP_PREFIX, group))?;
+    let remaining: Vec<String> = members
+        .iter()
+        .filter(|k| !keys.is_empty() && !keys.contains(k))
+        .cloned()
+        .collect();
+    let path_rules = chain.paths.iter().map(|(path, rule)| (path, &rule.keys));
+    let mut used_by = rules_naming(path_rules, &group);
+    used_by.extend(rules_naming(chain.writers.iter(), &group));
+    // a rule naming a group without keys would leave its vaults to no one
+    if remaining.is_empty() && !used_by.is_empty() {
+        return Err(format!(
+            "Group {}{} would be left without keys while the rules of {} name it",
+            GROUP_PREFIX,
+            group,
+            used_by.join(", ")
+        ));
+    }
+    if keys.is_empty() {
+        let removed = members.clone();
+        return change_groups(st, &group, &removed, "remove", |groups| {
+            groups.remove(&group);
+            Ok(())
+        });
+    }
+    change_groups(st, &group, &keys, "remove from", |groups| {
+        groups.insert(group.clone(), remaining);
+        Ok(())
+    })
+}
+
+// every group, or the one named
+pub fn show(st: &mut state::State, name: Option<&str>) -> Result<Vec<Group>, String> {
+    let wanted = name.map(group_name).transpose()?;
+    let chain = st.get_chain()?;
+    let mut names: Vec<&String> = chain
+        .groups
+        .keys()
+        .filter(|g| wanted.as_ref().is_none_or(|w| w == *g))
+        .collect();
+    names.sort();
+    if let Some(wanted) = wanted.as_ref() {
+        if names.is_empty() {
+            return Err(format!("Group {}{} does not exist", GROUP_PREFIX, wanted));
+        }
+    }
+    Ok(names
+        .into_iter()
+        .map(|group| Group {
+            name: format!("{}{}", GROUP_PREFIX, group),
+            keys: chain.groups[group].clone(),
+            paths: rules_naming(
+                chain.paths.iter().map(|(path, rule)| (path, &rule.keys)),
+                group,
+            ),
+            writers: rules_naming(chain.writers.iter(), group),
+        })
+        .collect())
+}
@@ -0,0 +1,163 @@
+// Earlier revisions of secrets, hard linked under .history as <file name>.<revision>.
+use super::durable;
+use super::vault;
+use crate::config;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub const HISTORY_DIR_NAME: &str = ".history";
+
+// what history lists, the author is the one the vault claims until it is read
+#[derive(Serialize, Deserialize)]
+pub struct Revision {
+    pub rev: u64,
+    pub timestamp: Option<u128>,
+    pub author: Option<String>,
+}
+
+// the revisions of a vault are named after its file, which is hashed in a hidden store. root
+// is the store directory, or a directory laid out like it such as a trash entry.
+fn revision_base(root: &Path, store_file: &vault::StoreFile) -> Result<PathBuf, String> {
+    Ok(root
+        .join(HISTORY_DIR_NAME)
+        .join(store_file.relative_file()?))
+}
+
+fn revision_path_under(
+    root: &Path,
+    store_file: &vault::StoreFile,
+    rev: u64,
+) -> Result<PathBuf, String> {
+    let base = revision_base(root, store_file)?;
+    let mut name = base.file_name().unwrap().to_os_string();
+    name.push(format!(".{}", rev));
+    Ok(base.with_file_name(name))
+}
+
+fn revision_path(store_file: &vault::StoreFile, rev: u64) -> Result<PathBuf, String> {
+    revision_path_under(&config::get_store_directory(), store_file, rev)
+}
+
+// the revisions kept of a vault, oldest first
+pub fn list_revisions(store_file: &vault::StoreFile) -> Result<Vec<u64>, String> {
+    list_revisions_under(&config::get_store_directory(), store_file)
+}
+
+fn list_revisions_under(root: &Path, store_file: &vault::StoreFile) -> Result<Vec<u64>, String> {
+    let base = revision_base(root, store_file)?;
+    let dir = base.parent().unwrap();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let prefix = format!("{}.", base.file_name().unwrap().to_string_lossy());
+    let entries = dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read history: {}", e))?;
+    let mut revs = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read history: {}", e))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(rev) = name.strip_prefix(&prefix) {
+            if let Ok(rev) = rev.parse::<u64>() {
+                revs.push(rev);
+            }
+        }
+    }
+    revs.sort();
+    Ok(revs)
+}
+
+// the vault of one revision, which is still bound to the path of the secret
+pub fn revision_file(store_file: &vault::StoreFile, rev: u64) -> Result<vault::StoreFile, String> {
+    let file = revision_path(store_file, rev)?;
+    if !file.exists() {
+        return Err(format!("No revision {} of {}", rev, store_file.path));
+    }
+    Ok(vault::StoreFile::copy(&store_file.path, file))
+}
+
+pub fn get_revisions(store_file: &vault::StoreFile) -> Result<Vec<Revision>, String> {
+    let mut revisions = Vec::new();
+    for rev in list_revisions(store_file)? {
+        let vault = vault::Vault::read_vault(&revision_file(store_file, rev)?)?;
+        revisions.push(Revision {
+            rev,
+            timestamp: vault.get_timestamp(),
+            author: vault.get_author().map(|a| a.to_string()),
+        });
+    }
+    Ok(revisions)
+}
+
+fn create_parent(file: &Path) -> Result<(), String> {
+    fs::create_dir_all(file.parent().unwrap())
+        .map_err(|e| format!("Unable to create history directory: {}", e))
+}
+
+// keeps the current vault as the next revision, returning where it went
+pub fn archive(store_file: &vault::StoreFile) -> Result<Option<PathBuf>, String> {
+    if !store_file.file.exists() || config::get_config()?.vault.history == 0 {
+        return Ok(None);
+    }
+    let next = list_revisions(store_file)?.last().map_or(1, |rev| rev + 1);
+    let target = revision_path(store_file, next)?;
+    create_parent(&target)?;
+    fs::hard_link(&store_file.file, &target)
+        .map_err(|e| format!("Unable to keep the previous revision: {}", e))?;
+    durable::sync_parent(&target)?;
+    Ok(Some(target))
+}
+
+// drops the oldest revisions beyond what the configuration keeps
+pub fn prune(store_file: &vault::StoreFile) -> Result<(), String> {
+    let keep = config::get_config()?.vault.history as usize;
+    let revs = list_revisions(store_file)?;
+    if revs.len() <= keep {
+        return Ok(());
+    }
+    for rev in revs[..revs.len() - keep].iter() {
+        let file = revision_path(store_file, *rev)?;
+        fs::remove_file(&file).map_err(|e| format!("Unable to prune history: {}", e))?;
+    }
+    Ok(())
+}
+
+// drops every revision of a vault, returning how many there were
+pub fn remove_revisions(store_file: &vault::StoreFile) -> Result<usize, String> {
+    let revs = list_revisions(store_file)?;
+    for rev in revs.iter() {
+        let file = revision_path(store_file, *rev)?;
+        fs::remove_file(&file).map_err(|e| format!("Unable to remove history: {}", e))?;
+    }
+    Ok(revs.len())
+}
+
+// follows a vault that moves to another file, as when a store starts hiding its paths
+pub fn move_revisions(from: &vault::StoreFile, to: &vault::StoreFile) -> Result<(), String> {
+    for rev in list_revisions(from)? {
+        let target = revision_path(to, rev)?;
+        create_parent(&target)?;
+        fs::rename(revision_path(from, rev)?, &target)
+            .map_err(|e| format!("Unable to move history: {}", e))?;
+    }
+    Ok(())
+}
+
+// moves the revisions of a vault from under one root to another, as into and out of the trash
+pub fn move_revisions_between(
+    store_file: &vault::StoreFile,
+    from: &Path,
+    to: &Path,
+) -> Result<(), String> {
+    for rev in list_revisions_under(from, store_file)? {
+        let target = revision_path_under(to, store_file, rev)?;
+        create_parent(&target)?;
+        fs::rename(revision_path_under(from, store_file, rev)?, &target)
+            .map_err(|e| format!("Unable to move history: {}", e))?;
+        durable::sync_parent(&target)?;
+    }
+    Ok(())
+}
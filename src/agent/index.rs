@@ -0,0 +1,189 @@
+// Opt-in hiding of path names: vaults are stored under a keyed hash of their path, and the
+// encrypted index maps the hashes back.
+use super::public;
+use super::secure::SecretBytes;
+use super::state;
+use super::vault;
+use crate::config;
+use crate::constants;
+use serde::Deserialize;
+use serde::Serialize;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::randombytes;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize)]
+pub struct PathIndex {
+    key: SecretBytes,
+    paths: BTreeSet<String>,
+    // paths with removed vaults in the trash, which are only named by their hash there
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    trashed: BTreeSet<String>,
+}
+
+impl PathIndex {
+    pub fn new() -> Self {
+        PathIndex {
+            key: SecretBytes::new(randombytes::randombytes(hmacsha256::KEYBYTES)),
+            paths: BTreeSet::new(),
+            trashed: BTreeSet::new(),
+        }
+    }
+
+    pub fn store_file() -> vault::StoreFile {
+        vault::StoreFile::plain(constants::INDEX_FILE_NAME)
+    }
+
+    pub fn exists() -> bool {
+        config::get_store_directory()
+            .join(constants::INDEX_FILE_NAME)
+            .exists()
+    }
+
+    pub fn read_index(st: &mut state::State) -> Result<Option<Self>, String> {
+        if !PathIndex::exists() {
+            return Ok(None);
+        }
+        let payload = vault::Vault::unlock_vault(st, &PathIndex::store_file())?;
+        serde_json::from_slice(&payload)
+            .map(Some)
+            .map_err(|e| format!("Unable to read path index vault: {}", e))
+    }
+
+    fn read_file(st: &mut state::State, file: &Path) -> Result<Self, String> {
+        let store_file = vault::StoreFile::copy(constants::INDEX_FILE_NAME, file.to_path_buf());
+        let payload = vault::Vault::unlock_vault(st, &store_file)?;
+        serde_json::from_slice(&payload)
+            .map_err(|e| format!("Unable to read path index vault: {}", e))
+    }
+
+    // the paths of an index from outside the store, one per line for git diffs
+    pub fn to_text(st: &mut state::State, file: &Path) -> Result<String, String> {
+        let index = PathIndex::read_file(st, file)?;
+        let mut text = String::new();
+        for path in index.paths.iter() {
+            text += &format!("{}\n", path);
+        }
+        for path in index.trashed.iter() {
+            text += &format!("{} (in the trash)\n", path);
+        }
+        Ok(text)
+    }
+
+    // merges theirs into ours the way a git merge driver does, a path stays unless one side
+    // removed it and the other left it alone
+    pub fn merge_files(
+        st: &mut state::State,
+        base: &Path,
+        ours: &Path,
+        theirs: &Path,
+    ) -> Result<(), String> {
+        let mut ours_index = PathIndex::read_file(st, ours)?;
+        let theirs_index = PathIndex::read_file(st, theirs)?;
+        if *ours_index.key != *theirs_index.key {
+            return Err("Path indexes hash their paths with different keys".to_string());
+        }
+        let base_index = PathIndex::read_file(st, base).unwrap_or_else(|_| PathIndex::new());
+        ours_index.paths = merge_sets(&base_index.paths, &ours_index.paths, &theirs_index.paths);
+        ours_index.trashed = merge_sets(
+            &base_index.trashed,
+            &ours_index.trashed,
+            &theirs_index.trashed,
+        );
+        let payload = serde_json::to_vec(&ours_index)
+            .map(SecretBytes::new)
+            .map_err(|e| format!("Unable to encode path index: {}", e))?;
+        let recipients = st.get_chain()?.get_keys().to_vec();
+        vault::Vault::write_vault(
+            &vault::StoreFile::copy(constants::INDEX_FILE_NAME, ours.to_path_buf()),
+            &payload,
+            recipients,
+            None,
+        )
+    }
+
+    pub fn write_index(&self, recipients: Vec<public::PublicKeyWrapper>) -> Result<(), String> {
+        let payload = serde_json::to_vec(self)
+            .map(SecretBytes::new)
+            .map_err(|e| format!("Unable to encode path index: {}", e))?;
+        vault::Vault::write_vault(&PathIndex::store_file(), &payload, recipients, None)
+    }
+
+    pub fn file_name(&self, path: &str) -> String {
+        let key = hmacsha256::Key::from_slice(&self.key).unwrap();
+        let tag = hmacsha256::authenticate(vault::store_path(path).as_bytes(), &key);
+        let mut name = String::with_capacity(tag.0.len() * 2);
+        for byte in tag.0.iter() {
+            name += &format!("{:02x}", byte);
+        }
+        name
+    }
+
+    pub fn store_file_for(&self, path: &str) -> vault::StoreFile {
+        vault::StoreFile::hidden(path, &self.file_name(path))
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.paths.contains(&vault::store_path(path))
+    }
+
+    // returns whether the path was not in the index yet
+    pub fn insert(&mut self, path: &str) -> bool {
+        self.paths.insert(vault::store_path(path))
+    }
+
+    pub fn remove(&mut self, path: &str) -> bool {
+        self.paths.remove(&vault::store_path(path))
+    }
+
+    // the logical path stored under a file name, including paths in the trash
+    pub fn path_for_file(&self, file_name: &str) -> Option<String> {
+        self.paths
+            .iter()
+            .chain(self.trashed.iter())
+            .find(|path| self.file_name(path) == file_name)
+            .cloned()
+    }
+
+    pub fn trashed_paths(&self) -> &BTreeSet<String> {
+        &self.trashed
+    }
+
+    pub fn set_trashed(&mut self, path: &str, trashed: bool) {
+        let path = vault::store_path(path);
+        if trashed {
+            self.trashed.insert(path);
+        } else {
+            self.trashed.remove(&path);
+        }
+    }
+
+    // every logical path below dir, or every path for an empty dir
+    pub fn paths_under(&self, dir: &str) -> Vec<String> {
+        let dir = vault::store_path(dir);
+        if dir.is_empty() {
+            return self.paths.iter().cloned().collect();
+        }
+        let prefix = format!("{}/", dir);
+        self.paths
+            .iter()
+            .filter(|p| p.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+// entries both sides kept or one side added
+fn merge_sets(
+    base: &BTreeSet<String>,
+    ours: &BTreeSet<String>,
+    theirs: &BTreeSet<String>,
+) -> BTreeSet<String> {
+    ours.union(theirs)
+        .filter(|entry| {
+            (ours.contains(*entry) && theirs.contains(*entry)) || !base.contains(*entry)
+        })
+        .cloned()
+        .collect()
+}
@@ -1,21 +1,119 @@
+use super::durable;
+use super::logger;
+use super::policy::Policy;
+use super::private::PrivateKey;
 use super::public::PublicKey;
 use super::public::PublicKeyWrapper;
+use super::rules;
+use super::rules::PathRule;
 use super::state;
+use super::threshold::Threshold;
 use super::vault;
 use crate::config;
 use crate::constants;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json;
+use sodiumoxide::crypto::sign;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 use std::time;
 
+const SIGNATURE_CONTEXT: &[u8] = b"basalt keychain\0";
+// marks a group where rules name keys, as in @backend
+pub const GROUP_PREFIX: &str = "@";
+
+// what the keychain vault holds, the chain is kept as the exact json that was signed
+#[derive(Serialize, Deserialize)]
+struct SignedKeyChain {
+    chain: String,
+    signer: String,
+    signature: Vec<u8>,
+}
+
+// the newest keychain this device has accepted and the signing keys it listed, so an older
+// or foreign keychain put into the store is refused
+#[derive(Serialize, Deserialize, Default)]
+struct KeyChainPin {
+    timestamp: u128,
+    // the oldest revision still accepted when falling back to the previous keychain
+    #[serde(default)]
+    previous: u128,
+    signers: Vec<sign::PublicKey>,
+}
+
+impl KeyChainPin {
+    fn read() -> Result<Self, String> {
+        let pin_file = config::get_keychain_pin_file();
+        if !pin_file.exists() {
+            return Ok(Self::default());
+        }
+        let json_bytes =
+            fs::read(&pin_file).map_err(|e| format!("Unable to read keychain pin: {}", e))?;
+        serde_json::from_slice(&json_bytes)
+            .map_err(|e| format!("Unable to parse keychain pin: {}", e))
+    }
+
+    fn write(&self) -> Result<(), String> {
+        let json_bytes = serde_json::to_vec(self).unwrap();
+        durable::write_file(&config::get_keychain_pin_file(), &json_bytes)
+            .map_err(|e| format!("Unable to write keychain pin: {}", e))
+    }
+
+    // until a signed keychain has been seen any of its own keys is trusted
+    fn trusts(&self, key: &PublicKeyWrapper) -> bool {
+        match key.get_sign_key() {
+            Some(sign_key) => self.signers.is_empty() || self.signers.contains(sign_key),
+            None => false,
+        }
+    }
+}
+
+// the rules deciding the keys of a path and the keys they come to
+#[derive(Serialize, Deserialize)]
+pub struct AccessExplanation {
+    pub path: String,
+    pub rules: Vec<rules::RuleMatch>,
+    // no replacing rule matched, so every key of the keychain was taken
+    pub all_keys: bool,
+    // the threshold rule that decides the keys instead of the path rules, and its path
+    #[serde(default)]
+    pub threshold: Option<(String, Threshold)>,
+    pub keys: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct KeyChain {
     timestamp: u128,
     keys: Vec<PublicKeyWrapper>,
-    pub paths: HashMap<String, Vec<String>>,
+    // the keys vaults are encrypted for by pattern, see rules
+    pub paths: HashMap<String, PathRule>,
+    // keys allowed to write below a path, anyone in the keychain where there is no rule
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub writers: HashMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub policies: HashMap<String, Policy>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub thresholds: HashMap<String, Threshold>,
+    // the vault format migrate last brought the store to, older vaults being refused
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub migrated_format: u16,
+    #[serde(default, skip_serializing_if = "vault::PaddingRule::is_default")]
+    pub padding: vault::PaddingRule,
+    // the timestamp of the revision each entry last changed in, see entries
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    revisions: BTreeMap<String, u128>,
+    // read from the previous revision because the current one was unreadable
+    #[serde(skip)]
+    from_fallback: bool,
+    // the entries as they were read, to tell which ones changed when the chain is written
+    #[serde(skip)]
+    baseline: BTreeMap<String, String>,
 }
 
 impl KeyChain {
@@ -24,6 +122,15 @@ impl KeyChain {
             timestamp: 0,
             keys: Vec::new(),
             paths: HashMap::new(),
+            writers: HashMap::new(),
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            thresholds: HashMap::new(),
+            migrated_format: 0,
+            padding: vault::PaddingRule::default(),
+            revisions: BTreeMap::new(),
+            from_fallback: false,
+            baseline: BTreeMap::new(),
         };
         chain.update_timestamp();
         chain
@@ -33,6 +140,11 @@ impl KeyChain {
         &self.keys
     }
 
+    // whether vaults are signed, see agent::author
+    pub fn can_sign(&self) -> bool {
+        self.keys.iter().any(|k| k.get_sign_key().is_some())
+    }
+
     pub fn add_key(&mut self, key: PublicKeyWrapper) {
         let new_key_name = key.get_key_name();
         let mut replace = false;
@@ -63,28 +175,287 @@ impl KeyChain {
         }
     }
 
-    pub fn write_chain(&mut self) {
-        self.update_timestamp();
-        let payload = serde_json::to_vec(self).unwrap();
-        let recipients = self.keys.clone();
-        let write_res =
-            vault::Vault::write_vault(&KeyChain::get_keychain_path(), &payload, recipients);
-        if write_res.is_err() {
-            super::log_message(&format!(
-                "Unable to write keychain: {}",
-                write_res.err().unwrap()
-            ));
+    pub fn write_chain(st: &mut state::State) -> Result<(), String> {
+        let res = Self::sign_and_write(st);
+        if res.is_err() {
+            // the next command reads back what is on disk
+            st.discard_chain();
+        }
+        res
+    }
+
+    fn sign_and_write(st: &mut state::State) -> Result<(), String> {
+        let pin = KeyChainPin::read()?;
+        let trusted: Vec<PublicKeyWrapper> = st
+            .get_chain()?
+            .keys
+            .iter()
+            .filter(|k| pin.trusts(k))
+            .cloned()
+            .collect();
+        let signer = st.keys.find_signer(&trusted)?;
+        let chain = st.get_chain()?;
+        let previous = chain.timestamp;
+        chain.timestamp = cmp::max(now(), cmp::max(chain.timestamp, pin.timestamp) + 1);
+        chain.update_revisions();
+        let payload = chain.seal(signer.as_ref(), &pin)?;
+
+        // the revision being replaced is kept unless it is the one that could not be read
+        let chain_path = Self::get_keychain_path();
+        if !chain.from_fallback && Path::new(&chain_path).exists() {
+            let current =
+                fs::read(&chain_path).map_err(|e| format!("Unable to read keychain: {}", e))?;
+            durable::write_file(Path::new(&Self::get_previous_path()), &current)
+                .map_err(|e| format!("Unable to keep the previous keychain: {}", e))?;
         }
+        let recipients = chain.keys.clone();
+        let author = signer.as_ref().map(|(name, key)| vault::Author {
+            name,
+            key: key.as_ref(),
+        });
+        vault::Vault::write_vault(
+            &vault::StoreFile::plain(&chain_path),
+            &payload,
+            recipients,
+            author.as_ref(),
+        )
+        .map_err(|e| format!("Unable to write keychain: {}", e))?;
+        chain.from_fallback = false;
+        chain.pin(previous).write()?;
+        st.mark_chain_written();
+        st.record_change(
+            "update keychain",
+            None,
+            signer.as_ref().map(|(name, _)| name.as_str()),
+        );
+        Ok(())
+    }
+
+    // the keychain vault payload, signed by signer unless no keychain has been pinned yet
+    fn seal(
+        &self,
+        signer: Option<&(String, Box<dyn PrivateKey>)>,
+        pin: &KeyChainPin,
+    ) -> Result<Vec<u8>, String> {
+        let chain_json = serde_json::to_string(self).unwrap();
+        match signer {
+            Some((signer, key)) => {
+                let message = signed_message(&chain_json);
+                let signature = key.sign(&message)?;
+                let verified = self
+                    .keys
+                    .iter()
+                    .any(|k| k.get_key_name() == *signer && k.verify(&message, &signature));
+                if !verified {
+                    return Err(format!(
+                        "Signing key of {} does not match the keychain",
+                        signer
+                    ));
+                }
+                let signed = SignedKeyChain {
+                    chain: chain_json,
+                    signer: signer.clone(),
+                    signature,
+                };
+                Ok(serde_json::to_vec(&signed).unwrap())
+            }
+            None if pin.signers.is_empty() => {
+                logger::warn("No key can sign the keychain yet, writing it unsigned");
+                Ok(chain_json.into_bytes())
+            }
+            None => Err("No trusted key is available to sign the keychain".to_string()),
+        }
+    }
+
+    // every key by its fingerprint and every path rule, as the json compared between
+    // revisions of the chain
+    fn entries(&self) -> BTreeMap<String, String> {
+        let mut entries = BTreeMap::new();
+        for key in self.keys.iter() {
+            entries.insert(
+                format!("key:{}", key.fingerprint()),
+                serde_json::to_string(key).unwrap(),
+            );
+        }
+        for (path, rule) in self.paths.iter() {
+            entries.insert(
+                format!("paths:{}", path),
+                serde_json::to_string(rule).unwrap(),
+            );
+        }
+        for (path, names) in self.writers.iter() {
+            entries.insert(
+                format!("writers:{}", path),
+                serde_json::to_string(names).unwrap(),
+            );
+        }
+        for (group, names) in self.groups.iter() {
+            entries.insert(
+                format!("groups:{}", group),
+                serde_json::to_string(names).unwrap(),
+            );
+        }
+        for (path, policy) in self.policies.iter() {
+            entries.insert(
+                format!("policies:{}", path),
+                serde_json::to_string(policy).unwrap(),
+            );
+        }
+        for (path, rule) in self.thresholds.iter() {
+            entries.insert(
+                format!("thresholds:{}", path),
+                serde_json::to_string(rule).unwrap(),
+            );
+        }
+        if !self.padding.is_default() {
+            entries.insert(
+                "padding".to_string(),
+                serde_json::to_string(&self.padding).unwrap(),
+            );
+        }
+        entries
+    }
+
+    // gives the entries changed since the chain was read the revision being written
+    fn update_revisions(&mut self) {
+        let entries = self.entries();
+        let timestamp = self.timestamp;
+        self.revisions.retain(|id, _| entries.contains_key(id));
+        for (id, json) in entries.iter() {
+            if self.baseline.get(id) != Some(json) {
+                self.revisions.insert(id.clone(), timestamp);
+            }
+        }
+        self.baseline = entries;
+    }
+
+    fn revision_of(&self, id: &str) -> u128 {
+        self.revisions.get(id).cloned().unwrap_or(0)
     }
 
     pub fn read_chain(st: &mut state::State) -> Result<Self, String> {
         let chain_path = Self::get_keychain_path();
-        if !Path::new(&chain_path).exists() {
+        let previous_path = Self::get_previous_path();
+        let pin = KeyChainPin::read()?;
+        if !Path::new(&chain_path).exists() && !Path::new(&previous_path).exists() {
+            if pin.timestamp > 0 {
+                return Err(
+                    "Keychain is missing from the store, refusing possible rollback".to_string(),
+                );
+            }
             return Ok(Self::new());
         }
-        let payload = vault::Vault::unlock_vault(st, &chain_path)?;
-        serde_json::from_slice(&payload)
-            .map_err(|e| format!("Unable to read keychain vault: {}", e))
+
+        let (revision, from_fallback) = match Self::open_revision(st, Path::new(&chain_path)) {
+            Ok(revision) => (revision, false),
+            Err(err) if Path::new(&previous_path).exists() => {
+                logger::warn(&format!(
+                    "Unable to read the keychain ({}), falling back to its previous revision",
+                    err
+                ));
+                let revision = Self::open_revision(st, Path::new(&previous_path)).map_err(|e| {
+                    format!(
+                        "Unable to read the keychain ({}) or its previous revision ({})",
+                        err, e
+                    )
+                })?;
+                (revision, true)
+            }
+            Err(err) => return Err(err),
+        };
+        let (mut chain, signed) = revision;
+        chain.verify(signed.as_ref(), &pin)?;
+        chain.baseline = chain.entries();
+
+        let oldest = if from_fallback {
+            pin.previous
+        } else {
+            pin.timestamp
+        };
+        if chain.timestamp < oldest {
+            return Err(format!(
+                "Keychain revision {} is older than the last one seen ({}), refusing possible rollback",
+                chain.timestamp, oldest
+            ));
+        }
+        if from_fallback {
+            chain.from_fallback = true;
+        } else if chain.timestamp > pin.timestamp {
+            chain.pin(pin.timestamp).write()?;
+        } else {
+            chain.pin(pin.previous).write()?;
+        }
+        Ok(chain)
+    }
+
+    // decrypts one revision of the keychain, along with its signature if it is signed
+    fn open_revision(
+        st: &mut state::State,
+        file: &Path,
+    ) -> Result<(KeyChain, Option<SignedKeyChain>), String> {
+        let store_file = vault::StoreFile::copy(constants::KEYCHAIN_FILE_NAME, file.to_path_buf());
+        let payload = vault::Vault::unlock_vault(st, &store_file)?;
+        match serde_json::from_slice::<SignedKeyChain>(&payload) {
+            Ok(signed) => {
+                let chain = serde_json::from_str(&signed.chain)
+                    .map_err(|e| format!("Unable to read keychain vault: {}", e))?;
+                Ok((chain, Some(signed)))
+            }
+            Err(_) => {
+                let chain = serde_json::from_slice(&payload)
+                    .map_err(|e| format!("Unable to read keychain vault: {}", e))?;
+                Ok((chain, None))
+            }
+        }
+    }
+
+    fn verify(&self, signed: Option<&SignedKeyChain>, pin: &KeyChainPin) -> Result<(), String> {
+        let signed = match signed {
+            Some(signed) => signed,
+            None if pin.signers.is_empty() => return Ok(()),
+            None => return Err("Keychain is not signed, refusing to use it".to_string()),
+        };
+        let signer = self
+            .keys
+            .iter()
+            .find(|k| k.get_key_name() == signed.signer)
+            .filter(|k| pin.trusts(k))
+            .ok_or(format!(
+                "Keychain is signed by {}, which is not a trusted key",
+                signed.signer
+            ))?;
+        if !signer.verify(&signed_message(&signed.chain), &signed.signature) {
+            return Err("Keychain signature is invalid".to_string());
+        }
+        Ok(())
+    }
+
+    fn pin(&self, previous: u128) -> KeyChainPin {
+        KeyChainPin {
+            timestamp: self.timestamp,
+            previous,
+            signers: self
+                .keys
+                .iter()
+                .filter_map(|k| k.get_sign_key().cloned())
+                .collect(),
+        }
+    }
+
+    pub fn expand_names(&self, names: &[String]) -> Vec<String> {
+        let mut expanded: Vec<String> = Vec::new();
+        for name in names.iter() {
+            let members = match name.strip_prefix(GROUP_PREFIX) {
+                Some(group) => self.groups.get(group).cloned().unwrap_or_default(),
+                None => vec![name.clone()],
+            };
+            for member in members {
+                if !expanded.contains(&member) {
+                    expanded.push(member);
+                }
+            }
+        }
+        expanded
     }
 
     pub fn key_names_to_keys(&self, names: &[String]) -> Vec<PublicKeyWrapper> {
@@ -93,7 +464,7 @@ impl KeyChain {
             key_map.insert(key.get_key_name().to_string(), key.clone());
         }
         let mut keys = Vec::new();
-        for name in names.iter() {
+        for name in self.expand_names(names).iter() {
             if key_map.contains_key(name) {
                 keys.push(key_map.get(name).unwrap().clone());
             }
@@ -102,26 +473,337 @@ impl KeyChain {
     }
 
     pub fn get_keys_for_path(&self, path: &str) -> Vec<PublicKeyWrapper> {
-        let paths = get_path_breakdown(path);
-        for part in paths {
-            if self.paths.contains_key(part) {
-                return self.key_names_to_keys(&self.paths.get(part).unwrap().clone());
+        if let Some((_, threshold)) = self.get_threshold_for_path(path) {
+            return self.key_names_to_keys(&threshold.keys);
+        }
+        let (chosen, added) = rules::names_for_path(&self.paths, path);
+        let mut keys = match chosen {
+            Some(names) => self.key_names_to_keys(&names),
+            None => self.keys.clone(),
+        };
+        for key in self.key_names_to_keys(&added) {
+            if !keys.iter().any(|k| k.get_key_name() == key.get_key_name()) {
+                keys.push(key);
             }
         }
-        return self.keys.clone();
+        keys
+    }
+
+    pub fn explain(&self, path: &str) -> AccessExplanation {
+        let ranked = rules::rank(&self.paths, path);
+        AccessExplanation {
+            path: path.to_string(),
+            all_keys: !ranked.iter().any(|r| r.effect == rules::Effect::Chose),
+            rules: ranked,
+            threshold: self
+                .get_threshold_for_path(path)
+                .map(|(rule_path, threshold)| (rule_path.to_string(), threshold.clone())),
+            keys: self
+                .get_keys_for_path(path)
+                .iter()
+                .map(|k| k.get_key_name().to_string())
+                .collect(),
+        }
+    }
+
+    // the threshold rule of the closest path at or above path, and that path
+    pub fn get_threshold_for_path(&self, path: &str) -> Option<(&str, &Threshold)> {
+        get_path_breakdown(path)
+            .into_iter()
+            .find_map(|part| self.thresholds.get_key_value(part))
+            .map(|(part, rule)| (part.as_str(), rule))
+    }
+
+    // the rule of the closest path at or above path
+    pub fn get_writers_for_path(&self, path: &str) -> Option<&Vec<String>> {
+        get_path_breakdown(path)
+            .into_iter()
+            .find_map(|part| self.writers.get(part))
     }
 
-    fn get_keychain_path() -> String {
+    pub fn get_keychain_path() -> String {
         let store_directory = config::get_store_directory();
         let path = store_directory.join(constants::KEYCHAIN_FILE_NAME);
         path.as_os_str().to_str().unwrap().to_string()
     }
 
+    fn get_previous_path() -> String {
+        let store_directory = config::get_store_directory();
+        let path = store_directory.join(constants::KEYCHAIN_PREVIOUS_FILE_NAME);
+        path.as_os_str().to_str().unwrap().to_string()
+    }
+
     fn update_timestamp(&mut self) {
-        let now = time::SystemTime::now();
-        let elapsed = now.duration_since(time::UNIX_EPOCH).unwrap();
-        self.timestamp = elapsed.as_millis();
+        self.timestamp = now();
+    }
+
+    // Three-way merge against base. Keys are matched by fingerprint, a key both sides changed
+    // is taken from the later one. Rules both sides changed, and different keys going by one
+    // name, are kept as in ours and returned as conflicts.
+    pub fn merge(
+        base: Option<&KeyChain>,
+        ours: &KeyChain,
+        theirs: &KeyChain,
+    ) -> (KeyChain, Vec<String>) {
+        let base = base.map(|b| b.entries()).unwrap_or_default();
+        let ours_entries = ours.entries();
+        let theirs_entries = theirs.entries();
+        let theirs_keys: HashMap<String, &PublicKeyWrapper> =
+            theirs.keys.iter().map(|k| (k.fingerprint(), k)).collect();
+        let mut merged = KeyChain::new();
+        merged.timestamp = cmp::max(ours.timestamp, theirs.timestamp);
+        merged.migrated_format = cmp::max(ours.migrated_format, theirs.migrated_format);
+        let mut conflicts = Vec::new();
+
+        let ours_fingerprints: Vec<String> = ours.keys.iter().map(|k| k.fingerprint()).collect();
+        let theirs_only = theirs
+            .keys
+            .iter()
+            .filter(|k| !ours_fingerprints.contains(&k.fingerprint()));
+        for key in ours.keys.iter().chain(theirs_only) {
+            let fingerprint = key.fingerprint();
+            let id = format!("key:{}", fingerprint);
+            let (chosen, side) = match (ours_entries.get(&id), theirs_entries.get(&id)) {
+                (Some(_), Some(_)) if theirs.revision_of(&id) > ours.revision_of(&id) => {
+                    (theirs_keys[&fingerprint], theirs)
+                }
+                (Some(_), Some(_)) => (key, ours),
+                (Some(json), None) if base.get(&id) == Some(json) => continue,
+                (Some(_), None) => (key, ours),
+                (None, Some(json)) if base.get(&id) == Some(json) => continue,
+                (None, _) => (key, theirs),
+            };
+            let name = chosen.get_key_name();
+            if let Some(kept) = merged.keys.iter().find(|k| k.get_key_name() == name) {
+                conflicts.push(format!(
+                    "Key {} is {} in ours and {} in theirs, kept ours",
+                    name,
+                    kept.fingerprint(),
+                    fingerprint
+                ));
+                continue;
+            }
+            merged.keys.push(chosen.clone());
+            if side.revision_of(&id) > 0 {
+                merged.revisions.insert(id.clone(), side.revision_of(&id));
+            }
+        }
+
+        merged.paths = merge_rules(
+            "paths",
+            &base,
+            (ours, &ours.paths),
+            (theirs, &theirs.paths),
+            &mut merged.revisions,
+            &mut conflicts,
+            PathRule::describe,
+        );
+        merged.writers = merge_rules(
+            "writers",
+            &base,
+            (ours, &ours.writers),
+            (theirs, &theirs.writers),
+            &mut merged.revisions,
+            &mut conflicts,
+            |names| describe_names(names),
+        );
+        merged.groups = merge_rules(
+            "groups",
+            &base,
+            (ours, &ours.groups),
+            (theirs, &theirs.groups),
+            &mut merged.revisions,
+            &mut conflicts,
+            |names| describe_names(names),
+        );
+        merged.policies = merge_rules(
+            "policies",
+            &base,
+            (ours, &ours.policies),
+            (theirs, &theirs.policies),
+            &mut merged.revisions,
+            &mut conflicts,
+            Policy::describe,
+        );
+        merged.thresholds = merge_rules(
+            "thresholds",
+            &base,
+            (ours, &ours.thresholds),
+            (theirs, &theirs.thresholds),
+            &mut merged.revisions,
+            &mut conflicts,
+            Threshold::describe,
+        );
+
+        // like a path rule, the padding is taken from the side that changed it
+        let base_padding = base.get("padding");
+        let side = match (ours_entries.get("padding"), theirs_entries.get("padding")) {
+            (o, t) if o == t || t == base_padding => ours,
+            (o, _) if o == base_padding => theirs,
+            _ => {
+                conflicts.push(format!(
+                    "Padding is {} in ours and {} in theirs, kept ours",
+                    ours.padding.describe(),
+                    theirs.padding.describe()
+                ));
+                ours
+            }
+        };
+        merged.padding = side.padding;
+        if side.revision_of("padding") > 0 {
+            merged
+                .revisions
+                .insert("padding".to_string(), side.revision_of("padding"));
+        }
+        (merged, conflicts)
+    }
+
+    // a revision of the keychain from outside the store, which has to be signed by a trusted
+    // key but may be older than the pinned one
+    fn read_detached(
+        st: &mut state::State,
+        file: &Path,
+        pin: &KeyChainPin,
+    ) -> Result<Self, String> {
+        let (chain, signed) = Self::open_revision(st, file)?;
+        chain.verify(signed.as_ref(), pin)?;
+        Ok(chain)
     }
+
+    // a revision of the keychain from outside the store as json, for git diffs
+    pub fn to_text(st: &mut state::State, file: &Path) -> Result<String, String> {
+        let pin = KeyChainPin::read()?;
+        let (chain, signed) = Self::open_revision(st, file)?;
+        let mut text = match chain.verify(signed.as_ref(), &pin) {
+            Ok(()) => String::new(),
+            Err(err) => format!("# WARNING: {}\n", err),
+        };
+        if let Some(signed) = signed.as_ref() {
+            text += &format!("# signed by {}\n", signed.signer);
+        }
+        text += &serde_json::to_string_pretty(&chain).unwrap();
+        text.push('\n');
+        Ok(text)
+    }
+
+    // merges theirs into ours the way a git merge driver does, writing the signed result over
+    // ours and returning the conflicts that were resolved in favour of ours
+    pub fn merge_files(
+        st: &mut state::State,
+        base: &Path,
+        ours: &Path,
+        theirs: &Path,
+    ) -> Result<Vec<String>, String> {
+        let pin = KeyChainPin::read()?;
+        let base = match Self::read_detached(st, base, &pin) {
+            Ok(base) => Some(base),
+            Err(err) => {
+                logger::warn(&format!(
+                    "Merging keychains without their common revision: {}",
+                    err
+                ));
+                None
+            }
+        };
+        let ours_chain = Self::read_detached(st, ours, &pin)
+            .map_err(|e| format!("Unable to read our keychain: {}", e))?;
+        let theirs_chain = Self::read_detached(st, theirs, &pin)
+            .map_err(|e| format!("Unable to read their keychain: {}", e))?;
+        let (mut merged, conflicts) = Self::merge(base.as_ref(), &ours_chain, &theirs_chain);
+        merged.timestamp = cmp::max(now(), cmp::max(merged.timestamp, pin.timestamp) + 1);
+
+        let trusted: Vec<PublicKeyWrapper> = merged
+            .keys
+            .iter()
+            .filter(|k| pin.trusts(k))
+            .cloned()
+            .collect();
+        let signer = st.keys.find_signer(&trusted)?;
+        let payload = merged.seal(signer.as_ref(), &pin)?;
+        let author = signer.as_ref().map(|(name, key)| vault::Author {
+            name,
+            key: key.as_ref(),
+        });
+        vault::Vault::write_vault(
+            &vault::StoreFile::copy(constants::KEYCHAIN_FILE_NAME, ours.to_path_buf()),
+            &payload,
+            merged.keys.clone(),
+            author.as_ref(),
+        )
+        .map_err(|e| format!("Unable to write merged keychain: {}", e))?;
+        Ok(conflicts)
+    }
+}
+
+fn is_zero(n: &u16) -> bool {
+    *n == 0
+}
+
+fn describe_names(names: &[String]) -> String {
+    names.join(", ")
+}
+
+// three-way merge of one kind of path rule, keeping ours where both sides changed a rule
+fn merge_rules<T: Serialize + Clone>(
+    kind: &str,
+    base: &BTreeMap<String, String>,
+    ours: (&KeyChain, &HashMap<String, T>),
+    theirs: (&KeyChain, &HashMap<String, T>),
+    revisions: &mut BTreeMap<String, u128>,
+    conflicts: &mut Vec<String>,
+    describe_rule: fn(&T) -> String,
+) -> HashMap<String, T> {
+    let describe = |rule: Option<&T>| match rule {
+        Some(rule) => describe_rule(rule),
+        None => "no rule".to_string(),
+    };
+    let mut paths: Vec<&String> = ours.1.keys().chain(theirs.1.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut merged = HashMap::new();
+    for path in paths {
+        let id = format!("{}:{}", kind, path);
+        let ours_rule = ours.1.get(path);
+        let theirs_rule = theirs.1.get(path);
+        let ours_json = ours_rule.map(|r| serde_json::to_string(r).unwrap());
+        let theirs_json = theirs_rule.map(|r| serde_json::to_string(r).unwrap());
+        let base_json = base.get(&id).cloned();
+        let (rule, side) = if ours_json == theirs_json || theirs_json == base_json {
+            (ours_rule, ours.0)
+        } else if ours_json == base_json {
+            (theirs_rule, theirs.0)
+        } else {
+            conflicts.push(format!(
+                "The {} rule of {} changed on both sides, ours: {} (revision {}), theirs: {} (revision {}), kept ours",
+                kind,
+                path,
+                describe(ours_rule),
+                ours.0.revision_of(&id),
+                describe(theirs_rule),
+                theirs.0.revision_of(&id)
+            ));
+            (ours_rule, ours.0)
+        };
+        if let Some(rule) = rule {
+            merged.insert(path.clone(), rule.clone());
+            if side.revision_of(&id) > 0 {
+                revisions.insert(id.clone(), side.revision_of(&id));
+            }
+        }
+    }
+    merged
+}
+
+fn now() -> u128 {
+    let now = time::SystemTime::now();
+    let elapsed = now.duration_since(time::UNIX_EPOCH).unwrap();
+    elapsed.as_millis()
+}
+
+fn signed_message(chain_json: &str) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(chain_json.as_bytes());
+    message
 }
 
 fn get_path_breakdown(path: &str) -> Vec<&str> {
@@ -131,3 +813,150 @@ fn get_path_breakdown(path: &str) -> Vec<&str> {
         .map(|p| p.as_os_str().to_str().unwrap())
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::private::SodiumPrivateKey;
+
+    // the chain as read back, to be changed and written as the revision at timestamp
+    fn next_revision(chain: &KeyChain, timestamp: u128) -> KeyChain {
+        let mut next: KeyChain =
+            serde_json::from_str(&serde_json::to_string(chain).unwrap()).unwrap();
+        next.baseline = next.entries();
+        next.timestamp = timestamp;
+        next
+    }
+
+    fn written(mut chain: KeyChain) -> KeyChain {
+        chain.update_revisions();
+        chain
+    }
+
+    fn names(chain: &KeyChain) -> Vec<String> {
+        let mut names: Vec<String> = chain
+            .get_keys()
+            .iter()
+            .map(|k| k.get_key_name().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn base_with(keys: &[&str]) -> KeyChain {
+        let mut base = KeyChain::new();
+        base.timestamp = 1;
+        for name in keys.iter() {
+            base.add_key(SodiumPrivateKey::gen_key().get_public_key(name));
+        }
+        written(base)
+    }
+
+    #[test]
+    fn merge_keeps_a_key_added_on_one_side() {
+        sodiumoxide::init().unwrap();
+        let base = base_with(&["alice"]);
+        let mut ours = next_revision(&base, 2);
+        ours.add_key(SodiumPrivateKey::gen_key().get_public_key("bob"));
+        let ours = written(ours);
+        let theirs = next_revision(&base, 3);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert_eq!(names(&merged), vec!["alice", "bob"]);
+        assert!(conflicts.is_empty());
+        let (merged, _) = KeyChain::merge(Some(&base), &theirs, &ours);
+        assert_eq!(names(&merged), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn merge_drops_a_key_removed_on_one_side() {
+        sodiumoxide::init().unwrap();
+        let base = base_with(&["alice", "bob"]);
+        let ours = next_revision(&base, 2);
+        let mut theirs = next_revision(&base, 3);
+        theirs.remove_key("bob");
+        let theirs = written(theirs);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert_eq!(names(&merged), vec!["alice"]);
+        assert!(conflicts.is_empty());
+        let (merged, _) = KeyChain::merge(Some(&base), &theirs, &ours);
+        assert_eq!(names(&merged), vec!["alice"]);
+    }
+
+    #[test]
+    fn merge_takes_a_key_both_changed_from_the_later_side() {
+        sodiumoxide::init().unwrap();
+        let alice = SodiumPrivateKey::gen_key();
+        let mut base = KeyChain::new();
+        base.timestamp = 1;
+        base.add_key(alice.get_public_key("alice"));
+        let base = written(base);
+        let mut ours = next_revision(&base, 3);
+        ours.keys = vec![alice.get_public_key("alice-laptop")];
+        let ours = written(ours);
+        let mut theirs = next_revision(&base, 2);
+        theirs.keys = vec![alice.get_public_key("alice-desktop")];
+        let theirs = written(theirs);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert_eq!(names(&merged), vec!["alice-laptop"]);
+        assert!(conflicts.is_empty());
+        let (merged, _) = KeyChain::merge(Some(&base), &theirs, &ours);
+        assert_eq!(names(&merged), vec!["alice-laptop"]);
+    }
+
+    #[test]
+    fn merge_reports_one_name_for_different_keys() {
+        sodiumoxide::init().unwrap();
+        let base = base_with(&["alice"]);
+        let carol = SodiumPrivateKey::gen_key().get_public_key("carol");
+        let mut ours = next_revision(&base, 2);
+        ours.add_key(carol.clone());
+        let ours = written(ours);
+        let mut theirs = next_revision(&base, 3);
+        theirs.add_key(SodiumPrivateKey::gen_key().get_public_key("carol"));
+        let theirs = written(theirs);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert_eq!(names(&merged), vec!["alice", "carol"]);
+        let kept = merged
+            .key_names_to_keys(&["carol".to_string()])
+            .pop()
+            .unwrap();
+        assert_eq!(kept.fingerprint(), carol.fingerprint());
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].starts_with("Key carol is"));
+    }
+
+    #[test]
+    fn merge_reports_path_rules_both_changed() {
+        sodiumoxide::init().unwrap();
+        let base = base_with(&["alice", "bob"]);
+        let mut ours = next_revision(&base, 2);
+        ours.paths
+            .insert("prod".to_string(), PathRule::new(vec!["alice".to_string()]));
+        ours.paths
+            .insert("dev".to_string(), PathRule::new(vec!["bob".to_string()]));
+        let ours = written(ours);
+        let mut theirs = next_revision(&base, 3);
+        theirs
+            .paths
+            .insert("prod".to_string(), PathRule::new(vec!["bob".to_string()]));
+        let theirs = written(theirs);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert_eq!(merged.paths["prod"].keys, vec!["alice"]);
+        assert_eq!(merged.paths["dev"].keys, vec!["bob"]);
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].starts_with("The paths rule of prod changed on both sides"));
+    }
+
+    #[test]
+    fn merge_takes_the_padding_from_the_side_that_changed_it() {
+        sodiumoxide::init().unwrap();
+        let base = base_with(&["alice"]);
+        let ours = next_revision(&base, 2);
+        let mut theirs = next_revision(&base, 3);
+        theirs.padding.mode = vault::Padding::Padme;
+        let theirs = written(theirs);
+        let (merged, conflicts) = KeyChain::merge(Some(&base), &ours, &theirs);
+        assert!(merged.padding == theirs.padding);
+        assert!(conflicts.is_empty());
+    }
+}
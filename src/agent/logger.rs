@@ -0,0 +1,198 @@
+// Agent logging, one line per entry. Messages are plain &str so secret types, which are not
+// Display, cannot end up in the log.
+use super::index;
+use super::timestamp;
+use crate::config;
+use crate::constants;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+// LOG_AUTHPRIV facility
+const SYSLOG_FACILITY: u8 = 10;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    fn syslog_severity(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+struct Logger {
+    conf: config::LogConfig,
+    path: PathBuf,
+    file: Option<File>,
+    syslog: Option<UnixDatagram>,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Logger {
+    fn new() -> Logger {
+        // an unusable config file is reported by the request that reads it
+        let conf = config::get_config().map(|c| c.log).unwrap_or_default();
+        let syslog = if conf.syslog {
+            UnixDatagram::unbound()
+                .and_then(|sock| sock.connect(SYSLOG_SOCKET).map(|_| sock))
+                .ok()
+        } else {
+            None
+        };
+        Logger {
+            conf,
+            path: config::get_agent_log_file(),
+            file: None,
+            syslog,
+        }
+    }
+
+    fn write(&mut self, level: Level, request_id: u64, msg: &str) {
+        if level > self.conf.level {
+            return;
+        }
+
+        if let Some(syslog) = self.syslog.as_ref() {
+            let line = format!(
+                "<{}>{}[{}]: {} [req {}] {}",
+                SYSLOG_FACILITY * 8 + level.syslog_severity(),
+                constants::APP_NAME,
+                std::process::id(),
+                level.name(),
+                request_id,
+                msg
+            );
+            let _ = syslog.send(line.as_bytes());
+        }
+        if !self.conf.file {
+            return;
+        }
+
+        let line = format!(
+            "{} {} [req {}] {}\n",
+            timestamp::format(timestamp::now_millis()),
+            level.name(),
+            request_id,
+            msg
+        );
+        self.rotate(line.len() as u64);
+        if self.file.is_none() {
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .ok();
+        }
+        if let Some(log_file) = self.file.as_mut() {
+            let _ = log_file.write_all(line.as_bytes());
+            let _ = log_file.flush();
+        }
+    }
+
+    // agent.log -> agent.log.1 -> ... -> agent.log.<max_files>, dropping the oldest
+    fn rotate(&mut self, incoming: u64) {
+        if self.conf.max_size == 0 {
+            return;
+        }
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size == 0 || size + incoming <= self.conf.max_size {
+            return;
+        }
+
+        self.file = None;
+        if self.conf.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+            return;
+        }
+        for idx in (1..self.conf.max_files).rev() {
+            let _ = fs::rename(self.rotated_path(idx), self.rotated_path(idx + 1));
+        }
+        let _ = fs::rename(&self.path, self.rotated_path(1));
+    }
+
+    fn rotated_path(&self, idx: u32) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", idx));
+        PathBuf::from(name)
+    }
+}
+
+// called once per incoming connection so entries can be tied to the request that caused them
+pub fn next_request() -> u64 {
+    REQUEST_ID.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+// drops the cached configuration and file handle, e.g. after an agent reload
+pub fn reset() {
+    if let Ok(mut logger) = LOGGER.lock() {
+        *logger = None;
+    }
+}
+
+pub fn log(level: Level, msg: &str) {
+    let logger = LOGGER.lock();
+    if logger.is_err() {
+        return;
+    }
+    let mut logger = logger.unwrap();
+    let request_id = REQUEST_ID.load(Ordering::SeqCst);
+    logger
+        .get_or_insert_with(Logger::new)
+        .write(level, request_id, msg);
+}
+
+pub fn error(msg: &str) {
+    log(Level::Error, msg);
+}
+
+pub fn warn(msg: &str) {
+    log(Level::Warn, msg);
+}
+
+pub fn info(msg: &str) {
+    log(Level::Info, msg);
+}
+
+pub fn debug(msg: &str) {
+    log(Level::Debug, msg);
+}
+
+// for messages naming a secret, which a store hiding its path names keeps to debug level
+pub fn naming(level: Level, msg: &str) {
+    if index::PathIndex::exists() {
+        log(Level::Debug, msg);
+    } else {
+        log(level, msg);
+    }
+}
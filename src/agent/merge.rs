@@ -0,0 +1,346 @@
+// Merging and diffing vaults for git, decrypted and merged line by line in the agent.
+use super::audit;
+use super::author;
+use super::index;
+use super::keychain;
+use super::logger;
+use super::paths;
+use super::public::PublicKey;
+use super::secure::SecretBytes;
+use super::state;
+use super::vault;
+use crate::config;
+use crate::constants;
+use std::fs;
+use std::path::Path;
+
+// the largest table of line pairs compared, larger files are merged as a whole
+const MAX_MATCH_CELLS: usize = 4 * 1024 * 1024;
+
+// the logical path of a vault file of the store, which only the index knows when the store
+// hides its path names
+fn logical_path(st: &mut state::State, file_name: &str) -> Result<String, String> {
+    match st.get_index()? {
+        Some(index) => index
+            .path_for_file(file_name)
+            .ok_or(format!("{} is not a vault of this store", file_name)),
+        None => Ok(file_name.to_string()),
+    }
+}
+
+// merges theirs into ours, file_name being where git keeps them in the store, returning the
+// conflicts left in ours
+pub fn merge_files(
+    st: &mut state::State,
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    file_name: &str,
+) -> Result<Vec<String>, String> {
+    let file_name = vault::store_path(file_name);
+    if file_name == constants::KEYCHAIN_FILE_NAME {
+        return keychain::KeyChain::merge_files(st, base, ours, theirs);
+    }
+    if file_name == constants::INDEX_FILE_NAME {
+        return index::PathIndex::merge_files(st, base, ours, theirs).map(|_| Vec::new());
+    }
+    let path = logical_path(st, &file_name)?;
+    let store_file = paths::store_file(st, &path)?;
+
+    let ours_contents = open(st, &store_file.at(ours.to_path_buf()), "merge")?;
+    let theirs_contents = open(st, &store_file.at(theirs.to_path_buf()), "merge")?;
+    // git hands over an empty base when both sides added the path
+    let empty = fs::metadata(base).map(|m| m.len() == 0).unwrap_or(true);
+    let base_contents = if empty {
+        SecretBytes::new(Vec::new())
+    } else {
+        match open(st, &store_file.at(base.to_path_buf()), "merge") {
+            Ok(contents) => contents,
+            Err(err) => {
+                logger::naming(
+                    logger::Level::Warn,
+                    &format!("Merging {} without its common revision: {}", path, err),
+                );
+                SecretBytes::new(Vec::new())
+            }
+        }
+    };
+
+    let (merged, conflicts) = merge_lines(&base_contents, &ours_contents, &theirs_contents);
+    let signer = author::find_author(st, &path)?;
+    let chain = st.get_chain()?;
+    let recipients = chain.get_keys_for_path(&path);
+    let threshold = chain.get_threshold_for_path(&path).map(|(_, t)| t.required);
+    let padding = chain.padding;
+    let key_names: Vec<String> = recipients
+        .iter()
+        .map(|k| k.get_key_name().to_string())
+        .collect();
+    let author = signer.as_ref().map(|(name, key)| vault::Author {
+        name,
+        key: key.as_ref(),
+    });
+    let mut source: &[u8] = &merged;
+    let res = vault::Vault::write_threshold_vault_stream(
+        &store_file.at(ours.to_path_buf()),
+        &mut source,
+        recipients,
+        threshold,
+        padding,
+        author.as_ref(),
+    )
+    .map(|_| ());
+    let err = res.as_ref().err().map(|e| e.as_str());
+    audit::record(
+        audit::AuditEvent::Encrypt,
+        Some(&path),
+        &key_names,
+        err.or(Some("merge")),
+        res.is_ok(),
+    )?;
+    res?;
+    if conflicts == 0 {
+        return Ok(Vec::new());
+    }
+    Ok(vec![format!(
+        "{} conflicting changes to {} are kept between markers",
+        conflicts, path
+    )])
+}
+
+// decrypts a revision, which must be signed as the writers of its path require
+fn open(
+    st: &mut state::State,
+    store_file: &vault::StoreFile,
+    detail: &str,
+) -> Result<SecretBytes, String> {
+    let res =
+        vault::Vault::unlock_vault_with_key(st, store_file).and_then(|(contents, unlocked)| {
+            author::check_author(st, &store_file.path, unlocked.signature.as_ref())
+                .map(|_| (contents, unlocked.key_name))
+        });
+    record_read(&store_file.path, res, detail)
+}
+
+// puts a read on record before its contents go anywhere
+fn record_read(
+    path: &str,
+    res: Result<(SecretBytes, String), String>,
+    detail: &str,
+) -> Result<SecretBytes, String> {
+    match res {
+        Ok((contents, key_name)) => {
+            audit::record(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[key_name],
+                Some(detail),
+                true,
+            )?;
+            Ok(contents)
+        }
+        Err(err) => {
+            audit::record_or_log(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[],
+                Some(&err),
+                false,
+            );
+            Err(err)
+        }
+    }
+}
+
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, byte) in data.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&data[start..=idx]);
+            start = idx + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+// for each line of a, the line of b it is matched with in their longest common subsequence,
+// None when the files are too large to compare
+fn match_lines(a: &[&[u8]], b: &[&[u8]]) -> Option<Vec<Option<usize>>> {
+    let width = b.len() + 1;
+    if (a.len() + 1).saturating_mul(width) > MAX_MATCH_CELLS {
+        return None;
+    }
+    // common[i * width + j] is the length of the common subsequence of a[i..] and b[j..]
+    let mut common = vec![0u32; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i * width + j] = if a[i] == b[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                std::cmp::max(common[(i + 1) * width + j], common[i * width + j + 1])
+            };
+        }
+    }
+    let mut matches = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            matches[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(matches)
+}
+
+fn push_lines(out: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+    if !out.is_empty() && !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+// one stretch between lines both sides kept, returning whether it conflicts
+fn merge_chunk(base: &[&[u8]], ours: &[&[u8]], theirs: &[&[u8]], out: &mut Vec<u8>) -> bool {
+    if ours == theirs || theirs == base {
+        ours.iter().for_each(|line| out.extend_from_slice(line));
+        false
+    } else if ours == base {
+        theirs.iter().for_each(|line| out.extend_from_slice(line));
+        false
+    } else {
+        out.extend_from_slice(b"<<<<<<< ours\n");
+        push_lines(out, ours);
+        out.extend_from_slice(b"=======\n");
+        push_lines(out, theirs);
+        out.extend_from_slice(b">>>>>>> theirs\n");
+        true
+    }
+}
+
+// a three-way merge of lines, returning the merged contents and the number of conflicts
+fn merge_lines(base: &[u8], ours: &[u8], theirs: &[u8]) -> (SecretBytes, usize) {
+    let base = split_lines(base);
+    let ours = split_lines(ours);
+    let theirs = split_lines(theirs);
+    let (to_ours, to_theirs) = match (match_lines(&base, &ours), match_lines(&base, &theirs)) {
+        (Some(to_ours), Some(to_theirs)) => (to_ours, to_theirs),
+        _ => (vec![None; base.len()], vec![None; base.len()]),
+    };
+    let mut merged = Vec::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // the next line of base both sides kept
+        let stable = (i..base.len()).find_map(|b| match (to_ours[b], to_theirs[b]) {
+            (Some(o), Some(t)) => Some((b, o, t)),
+            _ => None,
+        });
+        let (b, o, t) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        if merge_chunk(&base[i..b], &ours[j..o], &theirs[k..t], &mut merged) {
+            conflicts += 1;
+        }
+        if stable.is_none() {
+            break;
+        }
+        merged.extend_from_slice(base[b]);
+        i = b + 1;
+        j = o + 1;
+        k = t + 1;
+    }
+    (SecretBytes::new(merged), conflicts)
+}
+
+// what git diff shows of a vault, its contents to a key holder and a note to anyone else
+pub fn textconv(st: &mut state::State, file: &Path) -> Result<SecretBytes, String> {
+    let vault = vault::Vault::read_vault(&vault::StoreFile::copy("", file.to_path_buf()))?;
+    let recipients: Vec<String> = vault
+        .get_recipient_keys()
+        .iter()
+        .map(|k| k.get_key_name().to_string())
+        .collect();
+    let name = file_name(file);
+    if name == constants::KEYCHAIN_FILE_NAME || name == constants::INDEX_FILE_NAME {
+        let text = if name == constants::KEYCHAIN_FILE_NAME {
+            keychain::KeyChain::to_text(st, file)
+        } else {
+            index::PathIndex::to_text(st, file)
+        };
+        return Ok(text
+            .map(|text| SecretBytes::new(text.into_bytes()))
+            .unwrap_or_else(|err| unreadable(&recipients, &err)));
+    }
+
+    // the file decides the path, git only keeps the name of the temporary files it converts
+    let path = logical_path(st, &name)?;
+    let in_store = file.starts_with(config::get_store_directory());
+    let mut warnings = Vec::new();
+    let path = match vault.get_path() {
+        Some(bound) if bound == path => path,
+        Some(bound) if !in_store && bound.rsplit('/').next() == Some(path.as_str()) => {
+            bound.to_string()
+        }
+        Some(bound) => {
+            let warning = format!("{} is bound to {} instead", path, bound);
+            logger::naming(logger::Level::Warn, &warning);
+            warnings.push(warning);
+            bound.to_string()
+        }
+        None => path,
+    };
+    let store_file = paths::store_file(st, &path)?.at(file.to_path_buf());
+    let res = vault::Vault::unlock_vault_with_key(st, &store_file).map(|(contents, unlocked)| {
+        if let Err(err) = author::check_author(st, &path, unlocked.signature.as_ref()) {
+            warnings.push(err);
+        }
+        (contents, unlocked.key_name)
+    });
+    Ok(match record_read(&path, res, "diff") {
+        Ok(contents) if warnings.is_empty() => contents,
+        Ok(contents) => {
+            let mut text = Vec::new();
+            for warning in warnings.iter() {
+                text.extend_from_slice(format!("# WARNING: {}\n", warning).as_bytes());
+            }
+            text.extend_from_slice(&contents);
+            SecretBytes::new(text)
+        }
+        Err(err) => unreadable(&recipients, &err),
+    })
+}
+
+fn unreadable(recipients: &[String], err: &str) -> SecretBytes {
+    SecretBytes::new(
+        format!(
+            "# encrypted for {}, not readable here: {}\n",
+            recipients.join(", "),
+            err
+        )
+        .into_bytes(),
+    )
+}
+
+// the name of a vault in the store, git names the temporary files it converts XXXXXX_<name>
+fn file_name(file: &Path) -> String {
+    if let Ok(relative) = file.strip_prefix(config::get_store_directory()) {
+        return relative.to_string_lossy().to_string();
+    }
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match name.find('_') {
+        Some(6) => name[7..].to_string(),
+        _ => name,
+    }
+}
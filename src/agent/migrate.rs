@@ -0,0 +1,357 @@
+// Rewrites vaults into the current format, one at a time and rolled back if one fails.
+use super::audit;
+use super::author;
+use super::durable;
+use super::history;
+use super::index;
+use super::keychain;
+use super::logger;
+use super::paths;
+use super::public;
+use super::public::PublicKey;
+use super::spool;
+use super::state;
+use super::vault;
+use crate::config;
+use crate::constants;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+// a vault to migrate as its header tells, before anything is decrypted
+struct Candidate {
+    path: String,
+    source: vault::StoreFile,
+    target: vault::StoreFile,
+    is_reserved: bool,
+    format_version: u16,
+    recipients: Vec<public::PublicKeyWrapper>,
+    // the shares it takes to open a threshold vault, which it still takes once migrated
+    threshold: Option<u8>,
+}
+
+// streams a vault into its target through a spool, which is on record like any other read
+fn migrate_vault(
+    st: &mut state::State,
+    candidate: &Candidate,
+    written: &mut Vec<Written>,
+) -> Result<(), String> {
+    let path = &candidate.path;
+    let mut spool = spool::Spool::new()?;
+    let res =
+        vault::Vault::unlock_vault_to(st, &candidate.source, &mut spool).and_then(|unlocked| {
+            // vaults are signed again by whoever migrates them
+            let author = if candidate.is_reserved {
+                None
+            } else {
+                match unlocked.signature.as_ref() {
+                    Some(signature) => {
+                        author::check_author(st, path, Some(signature)).map(|_| ())?
+                    }
+                    None => author::check_unsigned(st, path)?,
+                }
+                author::find_author(st, path)?
+            };
+            Ok((unlocked.key_name, author))
+        });
+    let (key_name, author) = match res {
+        Ok(opened) => opened,
+        Err(err) => {
+            audit::record_or_log(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[],
+                Some(&err),
+                false,
+            );
+            return Err(format!("Unable to decrypt {}: {}", path, err));
+        }
+    };
+    audit::record(
+        audit::AuditEvent::Decrypt,
+        Some(path),
+        &[key_name],
+        Some("migrate"),
+        true,
+    )?;
+
+    let author = author.as_ref().map(|(name, key)| vault::Author {
+        name,
+        key: key.as_ref(),
+    });
+    let padding = if candidate.is_reserved {
+        vault::PaddingRule::default()
+    } else {
+        st.get_chain()?.padding
+    };
+    let mut contents = spool.reader()?;
+    let res = write_file(written, &candidate.target.file, &mut || {
+        vault::Vault::write_threshold_vault_stream(
+            &candidate.target,
+            &mut contents,
+            candidate.recipients.clone(),
+            candidate.threshold,
+            padding,
+            author.as_ref(),
+        )
+        .map(|_| ())
+    });
+    if let Err(err) = res {
+        audit::record_or_log(
+            audit::AuditEvent::Encrypt,
+            Some(path),
+            &key_names(&candidate.recipients),
+            Some(&err),
+            false,
+        );
+        return Err(format!("Unable to migrate {}: {}", path, err));
+    }
+    Ok(())
+}
+
+fn key_names(recipients: &[public::PublicKeyWrapper]) -> Vec<String> {
+    recipients
+        .iter()
+        .map(|k| k.get_key_name().to_string())
+        .collect()
+}
+
+// a file written by the migration and where the file it replaced is kept until the end
+struct Written {
+    file: PathBuf,
+    backup: Option<PathBuf>,
+}
+
+// a partial file, so store listings and git leave it alone
+fn backup_path(file: &Path) -> Result<PathBuf, String> {
+    let mut name = OsString::from(file.file_name().unwrap_or_default());
+    name.push(".premigrate");
+    durable::partial_path(&file.with_file_name(name))
+}
+
+fn roll_back(written: &[Written]) {
+    for entry in written.iter().rev() {
+        let res = match entry.backup.as_ref() {
+            Some(backup) => fs::rename(backup, &entry.file)
+                .map_err(|e| e.to_string())
+                .and_then(|_| durable::sync_parent(&entry.file)),
+            None => fs::remove_file(&entry.file).map_err(|e| e.to_string()),
+        };
+        if let Err(err) = res {
+            logger::error(&format!(
+                "Unable to roll back migration of {}: {}",
+                entry.file.display(),
+                err
+            ));
+        }
+    }
+}
+
+fn remove_backups(written: &[Written]) {
+    for backup in written.iter().filter_map(|entry| entry.backup.as_ref()) {
+        let _ = fs::remove_file(backup);
+    }
+}
+
+// the file being replaced is linked to a backup rather than read, whatever its size
+fn write_file(
+    written: &mut Vec<Written>,
+    file: &Path,
+    write: &mut dyn FnMut() -> Result<(), String>,
+) -> Result<(), String> {
+    let backup = if file.exists() {
+        let backup = backup_path(file)?;
+        let _ = fs::remove_file(&backup);
+        fs::hard_link(file, &backup)
+            .map_err(|e| format!("Unable to keep {}: {}", file.display(), e))?;
+        Some(backup)
+    } else {
+        None
+    };
+    if let Err(err) = write() {
+        if let Some(backup) = backup {
+            let _ = fs::remove_file(backup);
+        }
+        return Err(err);
+    }
+    written.push(Written {
+        file: file.to_path_buf(),
+        backup,
+    });
+    Ok(())
+}
+
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = dir.read_dir() {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                remove_empty_dirs(&path);
+                let _ = fs::remove_dir(&path);
+            }
+        }
+    }
+}
+
+// returns the paths that were (or with dry_run, would be) migrated
+pub fn migrate_store(
+    st: &mut state::State,
+    dry_run: bool,
+    hide_paths: bool,
+) -> Result<Vec<String>, String> {
+    let hide_paths = hide_paths && st.get_index()?.is_none();
+    let mut new_index = if hide_paths {
+        Some(index::PathIndex::new())
+    } else {
+        None
+    };
+
+    let mut sources = Vec::new();
+    for name in [constants::KEYCHAIN_FILE_NAME, constants::INDEX_FILE_NAME].iter() {
+        if config::get_store_directory().join(name).exists() {
+            sources.push((name.to_string(), vault::StoreFile::plain(name)));
+        }
+    }
+    for path in paths::list_files(st, "")? {
+        let store_file = paths::store_file(st, &path)?;
+        sources.push((path, store_file));
+    }
+
+    // a dry run only reads headers, nothing is decrypted until the vaults are written
+    let can_sign = st.get_chain()?.can_sign();
+    let mut candidates = Vec::new();
+    for (path, source) in sources {
+        let is_reserved = source.path == constants::KEYCHAIN_FILE_NAME
+            || source.path == constants::INDEX_FILE_NAME;
+        let vault = vault::Vault::read_vault(&source)
+            .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let target = match new_index.as_mut() {
+            Some(index) if !is_reserved => {
+                index.insert(&path);
+                index.store_file_for(&path)
+            }
+            _ if vault.needs_migration() => vault::StoreFile::plain(&source.path),
+            // unsigned since before a key of the keychain could sign
+            _ if !is_reserved && vault.get_author().is_none() && can_sign => {
+                vault::StoreFile::plain(&source.path)
+            }
+            _ => continue,
+        };
+        candidates.push(Candidate {
+            path,
+            source,
+            target,
+            is_reserved,
+            format_version: vault.format_version(),
+            recipients: vault.get_recipient_keys(),
+            threshold: vault.get_threshold(),
+        });
+    }
+
+    let migrated: Vec<String> = candidates.iter().map(|c| c.path.clone()).collect();
+    if dry_run {
+        return Ok(migrated);
+    }
+
+    let mut written = Vec::new();
+    for candidate in candidates.iter() {
+        // the names being hidden stay out of the log as well
+        let level = if hide_paths {
+            logger::Level::Debug
+        } else {
+            logger::Level::Info
+        };
+        logger::naming(
+            level,
+            &format!(
+                "Migrating {} from vault format {} to {}",
+                candidate.path,
+                candidate.format_version,
+                vault::FORMAT_VERSION
+            ),
+        );
+        if let Err(err) = migrate_vault(st, candidate, &mut written) {
+            roll_back(&written);
+            return Err(format!("{}, store rolled back", err));
+        }
+        // the rewritten keychain and index hold what is already loaded
+        if candidate.source.path == constants::KEYCHAIN_FILE_NAME {
+            st.mark_chain_written();
+        } else if candidate.source.path == constants::INDEX_FILE_NAME {
+            st.mark_index_written();
+        }
+    }
+
+    if let Some(new_index) = new_index {
+        let index_file = index::PathIndex::store_file();
+        let recipients = st.get_chain()?.get_keys().to_vec();
+        let res = write_file(&mut written, &index_file.file, &mut || {
+            new_index.write_index(recipients.clone())
+        });
+        if let Err(err) = res {
+            roll_back(&written);
+            return Err(format!(
+                "Unable to write the path index, store rolled back: {}",
+                err
+            ));
+        }
+        // only now that everything is in place are the vaults under their old names removed
+        for migration in candidates.iter() {
+            if migration.source.file != migration.target.file {
+                if let Err(err) = fs::remove_file(&migration.source.file) {
+                    logger::error(&format!(
+                        "Unable to remove the old file of {} after hiding its path: {}",
+                        migration.target.file.display(),
+                        err
+                    ));
+                }
+                if let Err(err) = history::move_revisions(&migration.source, &migration.target) {
+                    logger::error(&format!(
+                        "Unable to move the history of {}: {}",
+                        migration.target.file.display(),
+                        err
+                    ));
+                }
+            }
+        }
+        remove_empty_dirs(&config::get_store_directory());
+        st.set_index(new_index);
+    }
+    remove_backups(&written);
+    // the vaults are only on record as written once none of them can be rolled back
+    for migration in candidates.iter() {
+        audit::record_or_log(
+            audit::AuditEvent::Encrypt,
+            Some(&migration.path),
+            &key_names(&migration.recipients),
+            Some(&format!(
+                "migrated from format {}",
+                migration.format_version
+            )),
+            true,
+        );
+    }
+    // from now on a vault not bound to its path can only have been put back from before
+    let chain = st.get_chain()?;
+    if chain.migrated_format < vault::FORMAT_VERSION {
+        chain.migrated_format = vault::FORMAT_VERSION;
+        keychain::KeyChain::write_chain(st).map_err(|e| {
+            format!(
+                "Vaults migrated but unable to record the format of the store: {}",
+                e
+            )
+        })?;
+    }
+    let operation = if hide_paths {
+        "hide path names of"
+    } else {
+        "migrate"
+    };
+    st.record_change(
+        &format!("{} {} vaults", operation, migrated.len()),
+        None,
+        None,
+    );
+    Ok(migrated)
+}
@@ -1,12 +1,32 @@
+pub mod audit;
+pub mod author;
 pub mod command;
+pub mod device;
+pub mod durable;
 pub mod generate;
+pub mod git;
+pub mod group;
+pub mod history;
+pub mod index;
 pub mod keychain;
+pub mod logger;
+pub mod merge;
+pub mod migrate;
 pub mod passphrase;
 pub mod paths;
+pub mod pinpolicy;
+pub mod policy;
 pub mod private;
 pub mod public;
+pub mod rules;
 pub mod secret;
+pub mod secure;
+pub mod shamir;
+pub mod spool;
 pub mod state;
+pub mod threshold;
+pub mod timestamp;
+pub mod trash;
 pub mod vault;
 
 use crate::config;
@@ -15,13 +35,16 @@ use fork::Fork;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_json;
-use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
 pub fn spawn_agent() -> Result<(), String> {
+    // reports a bad config file while there is still a terminal to report it to
+    config::get_config()?;
     let path = config::get_agent_socket_file();
     let listener =
         UnixListener::bind(&path).map_err(|e| format!("Unable to listen on socket: {}", e))?;
@@ -33,6 +56,11 @@ pub fn spawn_agent() -> Result<(), String> {
         _ => {}
     };
 
+    if let Err(err) = secure::harden_process() {
+        logger::warn(&err);
+    }
+    logger::info("Agent started");
+
     let mut st = state::State::new();
     for stream in listener.incoming() {
         st.reset_session_keys();
@@ -40,7 +68,7 @@ pub fn spawn_agent() -> Result<(), String> {
             Ok(mut stream) => {
                 handle_stream(&mut st, &mut stream);
             }
-            Err(err) => log_message(&format!("Unable to accept incoming request: {}", err)),
+            Err(err) => logger::error(&format!("Unable to accept incoming request: {}", err)),
         }
     }
     Ok(())
@@ -50,8 +78,9 @@ pub fn write_message<T>(stream: &mut UnixStream, msg: &T) -> Result<(), String>
 where
     T: Serialize,
 {
-    let msg_bytes =
-        serde_json::to_vec(msg).map_err(|e| format!("Unable to serialize json: {}", e))?;
+    let msg_bytes = serde_json::to_vec(msg)
+        .map(secure::SecretBytes::new)
+        .map_err(|e| format!("Unable to serialize json: {}", e))?;
     let msg_len = msg_bytes.len();
     let prefix = format!("{}\n", msg_len);
     stream
@@ -62,7 +91,7 @@ where
         .map_err(|e| format!("Unable to write to unix socket: {}", e))
 }
 
-pub fn read_message(stream: &mut UnixStream) -> Result<Vec<u8>, String> {
+fn read_length(stream: &mut UnixStream) -> Result<usize, String> {
     let mut msg_len = Vec::new();
     let mut msg_buf: [u8; 1] = [0; 1];
     loop {
@@ -81,14 +110,15 @@ pub fn read_message(stream: &mut UnixStream) -> Result<Vec<u8>, String> {
 
     let msg_len = String::from_utf8(msg_len)
         .map_err(|e| format!("Unable to interpret message length: {}", e))?;
-    let msg_len = str::parse::<usize>(&msg_len)
-        .map_err(|e| format!("Unable to parse message length: {}", e))?;
+    str::parse::<usize>(&msg_len).map_err(|e| format!("Unable to parse message length: {}", e))
+}
 
+fn read_exact(stream: &mut UnixStream, msg_len: usize) -> Result<secure::SecretBytes, String> {
     let mut num_read = 0;
     let mut msg_buf = allocate_message(msg_len);
     while num_read < msg_len {
         let n = stream
-            .read(&mut msg_buf)
+            .read(&mut msg_buf[num_read..])
             .map_err(|e| format!("Unable to read message from unix socket: {}", e))?;
         if n == 0 {
             return Err("Unix Socket is closed prematurely".to_string());
@@ -99,6 +129,102 @@ pub fn read_message(stream: &mut UnixStream) -> Result<Vec<u8>, String> {
     Ok(msg_buf)
 }
 
+pub fn read_message(stream: &mut UnixStream) -> Result<secure::SecretBytes, String> {
+    let msg_len = read_length(stream)?;
+    read_exact(stream, msg_len)
+}
+
+// attachment data follows its command as "<length>\n<bytes>" frames ended by an empty one,
+// so it is never held in memory as a whole
+pub struct FrameReader<'a> {
+    stream: &'a mut UnixStream,
+    frame: secure::SecretBytes,
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> FrameReader<'a> {
+    pub fn new(stream: &'a mut UnixStream) -> Self {
+        FrameReader {
+            stream,
+            frame: secure::SecretBytes::new(Vec::new()),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    // skips whatever the sender still has queued, e.g. after a failed transfer
+    pub fn drain(&mut self) -> Result<(), String> {
+        while !self.done {
+            self.pos = self.frame.len();
+            self.next_frame()?;
+        }
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<(), String> {
+        let frame_len = read_length(self.stream)?;
+        if frame_len == 0 {
+            self.done = true;
+        } else if frame_len > MAX_FRAME_LENGTH {
+            return Err(format!("Frame of {} bytes is too large", frame_len));
+        } else {
+            self.frame = read_exact(self.stream, frame_len)?;
+            self.pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Read for FrameReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.frame.len() && !self.done {
+            self.next_frame().map_err(std::io::Error::other)?;
+        }
+        if self.done {
+            return Ok(0);
+        }
+        let n = std::cmp::min(buf.len(), self.frame.len() - self.pos);
+        buf[..n].copy_from_slice(&self.frame[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub struct FrameWriter<'a> {
+    stream: &'a mut UnixStream,
+}
+
+impl<'a> FrameWriter<'a> {
+    pub fn new(stream: &'a mut UnixStream) -> Self {
+        FrameWriter { stream }
+    }
+
+    // sends the empty frame that ends the transfer
+    pub fn finish(self) -> Result<(), String> {
+        self.stream
+            .write_all(b"0\n")
+            .map_err(|e| format!("Unable to write to unix socket: {}", e))
+    }
+}
+
+impl<'a> Write for FrameWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf = &buf[..std::cmp::min(buf.len(), MAX_FRAME_LENGTH)];
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.stream
+            .write_all(format!("{}\n", buf.len()).as_bytes())?;
+        self.stream.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
 pub fn parse_message<'a, T>(stream: &mut UnixStream) -> Result<T, String>
 where
     T: DeserializeOwned,
@@ -123,23 +249,31 @@ fn send_error(msg: &str, stream: &mut UnixStream) {
     let resp: Vec<Result<command::Response, String>> = vec![Err(msg.to_string())];
     let res = write_message(stream, &resp);
     if res.is_err() {
-        log_message(&res.err().unwrap());
+        logger::error(&res.err().unwrap());
     }
 }
 
-fn allocate_message(len: usize) -> Vec<u8> {
-    let mut ret = Vec::new();
-    for _ in 0..len {
-        ret.push(0);
-    }
-    ret
+// messages carry plaintext secrets and PINs, so the buffer is locked and wiped after use
+fn allocate_message(len: usize) -> secure::SecretBytes {
+    secure::SecretBytes::new(vec![0; len])
 }
 
 fn handle_stream(st: &mut state::State, stream: &mut UnixStream) {
+    logger::next_request();
+    let peer = audit::get_peer(stream);
+    if let Some(peer) = peer.as_ref() {
+        logger::debug(&format!(
+            "Request from pid {} uid {} ({})",
+            peer.pid,
+            peer.uid,
+            peer.process.as_deref().unwrap_or("unknown process")
+        ));
+    }
+    audit::set_peer(peer);
     let commands: Result<Vec<command::Command>, String> = parse_message(stream);
     if commands.is_err() {
         let err_msg = commands.err().unwrap();
-        log_message(&err_msg);
+        logger::error(&err_msg);
         send_error(&err_msg, stream);
         return;
     }
@@ -147,24 +281,18 @@ fn handle_stream(st: &mut state::State, stream: &mut UnixStream) {
     let commands = commands.unwrap();
     let mut responses = Vec::new();
     for command in commands {
-        responses.push(command::process_command(st, command));
+        let response = command::process_command(st, command, stream);
+        if response.is_err() {
+            logger::naming(
+                logger::Level::Warn,
+                &format!("Request failed: {}", response.as_ref().err().unwrap()),
+            );
+        }
+        responses.push(response);
     }
 
     let write_res = write_message(stream, &responses);
     if write_res.is_err() {
-        log_message(&write_res.err().unwrap());
-    }
-}
-
-fn log_message(msg: &str) {
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(config::get_agent_log_file());
-    if log_file.is_err() {
-        return;
+        logger::error(&write_res.err().unwrap());
     }
-    let mut log_file = log_file.unwrap();
-    let _ = write!(log_file, "{}\n", msg);
-    let _ = log_file.flush();
 }
@@ -1,141 +1,320 @@
+use super::pinpolicy;
+use super::secure::SecretBytes;
+use super::secure::SecretString;
+use crate::config;
+use crate::constants;
+use std::fmt;
 use std::io::Read;
 use std::io::Write;
 use std::process::Child;
 use std::process::Command;
 use std::process::Stdio;
 
+// libgpg-error codes pinentry reports in the low 16 bits of an ERR line
+const GPG_ERR_TIMEOUT: u32 = 62;
+const GPG_ERR_CANCELED: u32 = 99;
+
+const ASSUAN_LINE_LENGTH: usize = 1002;
+const MAX_DATA_LENGTH: usize = 4096;
+
+#[derive(Debug)]
+pub enum PinError {
+    Cancelled,
+    Timeout,
+    Failure(String),
+}
+
+impl PinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, PinError::Cancelled)
+    }
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PinError::Cancelled => write!(f, "PIN entry cancelled by user"),
+            PinError::Timeout => write!(f, "PIN entry timed out"),
+            PinError::Failure(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+// scores the PIN typed so far for the pinentry quality bar
+type QualityFn = Box<dyn Fn(&str) -> i32>;
+
 struct PinEntry {
     pin_process: Child,
+    quality: Option<QualityFn>,
 }
 
 impl PinEntry {
-    fn new() -> Result<PinEntry, String> {
-        let child = Command::new("pinentry")
+    fn new(program: &str) -> Result<PinEntry, PinError> {
+        let child = Command::new(program)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .spawn();
         if child.is_err() {
-            Err(format!(
-                "Unable to call pinentry process: {}",
+            return Err(PinError::Failure(format!(
+                "Unable to call pinentry process '{}': {}",
+                program,
                 child.err().unwrap()
-            ))
-        } else {
-            Ok(PinEntry {
-                pin_process: child.unwrap(),
-            })
+            )));
         }
+        let mut pinentry = PinEntry {
+            pin_process: child.unwrap(),
+            quality: None,
+        };
+        pinentry.read_response()?;
+        Ok(pinentry)
     }
 
-    fn read_line(&mut self) -> Result<String, String> {
+    // spawns pinentry and sends the settings shared by every prompt
+    fn open(desc: &str, error: Option<&str>) -> Result<PinEntry, PinError> {
+        let conf = config::get_config().map_err(PinError::Failure)?.pinentry;
+        let mut pinentry = PinEntry::new(&conf.program)?;
+        pinentry.send_command("SETTITLE", Some(constants::APP_NAME))?;
+        pinentry.send_command("SETPROMPT", Some("PIN:"))?;
+        pinentry.send_command("SETDESC", Some(desc))?;
+        if conf.timeout > 0 {
+            pinentry.send_command("SETTIMEOUT", Some(&conf.timeout.to_string()))?;
+        }
+        if error.is_some() {
+            pinentry.send_command("SETERROR", error)?;
+        }
+        Ok(pinentry)
+    }
+
+    fn read_line(&mut self) -> Result<SecretString, PinError> {
         if self.pin_process.stdout.is_none() {
-            return Err("Unable to read to pinentry stdout".to_string());
+            return Err(PinError::Failure(
+                "Unable to read to pinentry stdout".to_string(),
+            ));
         }
         let stdout = self.pin_process.stdout.as_mut().unwrap();
-        let mut line = Vec::new();
+        // lines may carry the PIN, so they live in a locked buffer that is never reallocated
+        let mut line = SecretBytes::with_capacity(ASSUAN_LINE_LENGTH);
         loop {
             let mut buf = [0; 1];
             match stdout.read(&mut buf) {
                 Ok(0) => {
-                    return Err("Reached unexpected EOF".to_string());
+                    return Err(PinError::Failure("Reached unexpected EOF".to_string()));
                 }
                 Ok(_) => {
                     if buf[0] == 0xA {
-                        let line_string = String::from_utf8(line);
-                        if line_string.is_err() {
-                            return Err(format!(
+                        return SecretString::from_bytes(line).map_err(|e| {
+                            PinError::Failure(format!(
                                 "Unable to parse pinentry response string: {}",
-                                line_string.unwrap_err()
-                            ));
-                        }
-                        let line_string = line_string.unwrap();
-                        return Ok(line_string);
+                                e
+                            ))
+                        });
                     } else {
-                        line.push(buf[0]);
+                        line.push(buf[0]).map_err(|_| {
+                            PinError::Failure("Pinentry response line is too long".to_string())
+                        })?;
                     }
                 }
                 Err(e) => {
-                    return Err(format!("Unable to read from pinentry stdout: {}", e));
+                    return Err(PinError::Failure(format!(
+                        "Unable to read from pinentry stdout: {}",
+                        e
+                    )));
                 }
             }
         }
     }
 
-    fn send_command(&mut self, cmd: &str) -> Result<String, String> {
+    // reads lines until the final OK or ERR, collecting any D lines on the way
+    fn read_response(&mut self) -> Result<Option<SecretString>, PinError> {
+        let mut data: Option<SecretBytes> = None;
+        loop {
+            let line = self.read_line()?;
+            if &*line == "OK" || line.starts_with("OK ") {
+                return match data {
+                    Some(bytes) => SecretString::from_bytes(bytes)
+                        .map(Some)
+                        .map_err(|e| PinError::Failure(format!("Pinentry data: {}", e))),
+                    None => Ok(None),
+                };
+            } else if let Some(err) = line.strip_prefix("ERR ") {
+                return Err(parse_error(err));
+            } else if let Some(escaped) = line.strip_prefix("D ") {
+                let data = data.get_or_insert_with(|| SecretBytes::with_capacity(MAX_DATA_LENGTH));
+                unescape_data(escaped, data)?;
+            } else if let Some(inquiry) = line.strip_prefix("INQUIRE ") {
+                self.answer_inquiry(inquiry)?;
+            } else if &*line == "S" || line.starts_with("S ") || line.starts_with("#") {
+                continue;
+            } else {
+                // the line may contain PIN data, so it is not echoed into the error
+                return Err(PinError::Failure(
+                    "Unexpected pinentry response".to_string(),
+                ));
+            }
+        }
+    }
+
+    // pinentry inquires the quality of the PIN as it is typed when a quality bar is shown
+    fn answer_inquiry(&mut self, inquiry: &str) -> Result<(), PinError> {
+        let inquiry_split: Vec<&str> = inquiry.splitn(2, " ").collect();
+        if inquiry_split[0] != "QUALITY" || self.quality.is_none() {
+            return self.write_line("CAN");
+        }
+        let mut pin = SecretBytes::with_capacity(MAX_DATA_LENGTH);
+        if inquiry_split.len() > 1 {
+            unescape_data(inquiry_split[1], &mut pin)?;
+        }
+        let pin = SecretString::from_bytes(pin).map_err(PinError::Failure)?;
+        let score = (self.quality.as_ref().unwrap())(&pin);
+        self.write_line(&format!("D {}", score))?;
+        self.write_line("END")
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), PinError> {
         if self.pin_process.stdin.is_none() {
-            return Err("Unable to write to pinentry stdin".to_string());
+            return Err(PinError::Failure(
+                "Unable to write to pinentry stdin".to_string(),
+            ));
         }
         let stdin = self.pin_process.stdin.as_mut().unwrap();
 
-        let cmd_to_send = format!("{}\n", cmd);
-        let write_res = stdin.write(cmd_to_send.as_bytes());
+        let write_res = stdin.write_all(format!("{}\n", line).as_bytes());
         if write_res.is_err() {
-            return Err(format!(
+            return Err(PinError::Failure(format!(
                 "Unable to write to pinentry stdin: {}",
                 write_res.unwrap_err()
-            ));
+            )));
         }
         let write_flush = stdin.flush();
         if write_flush.is_err() {
-            return Err(format!(
+            return Err(PinError::Failure(format!(
                 "Unable to flush pinentry stdin: {}",
                 write_flush.unwrap_err()
-            ));
+            )));
         }
+        Ok(())
+    }
 
-        let mut res = self.read_line()?;
-        while res.starts_with("S") {
-            res = self.read_line()?;
-        }
-        Ok(res)
+    fn send_command(
+        &mut self,
+        cmd: &str,
+        arg: Option<&str>,
+    ) -> Result<Option<SecretString>, PinError> {
+        let cmd_to_send = match arg {
+            Some(arg) => format!("{} {}", cmd, escape_arg(arg)),
+            None => cmd.to_string(),
+        };
+        self.write_line(&cmd_to_send)?;
+        self.read_response()
     }
 }
 
 impl Drop for PinEntry {
     fn drop(&mut self) {
+        let _ = self.send_command("BYE", None);
+        // closing stdin makes pinentry exit even if BYE was not delivered
+        self.pin_process.stdin.take();
         let _ = self.pin_process.wait();
     }
 }
 
-fn parse_response(msg: &str) -> Result<String, String> {
+fn parse_error(msg: &str) -> PinError {
     let msg_split: Vec<&str> = msg.splitn(2, " ").collect();
-    if msg_split.len() == 1 {
-        if msg_split[0] == "OK" {
-            return Ok(String::new());
-        } else {
-            return Err(format!("Pinetry Error Response: {}", msg_split[0]));
+    let code = str::parse::<u32>(msg_split[0]).unwrap_or(0);
+    let description = if msg_split.len() > 1 {
+        msg_split[1]
+    } else {
+        msg_split[0]
+    };
+    match code & 0xFFFF {
+        GPG_ERR_CANCELED => PinError::Cancelled,
+        GPG_ERR_TIMEOUT => PinError::Timeout,
+        _ => PinError::Failure(format!("Pinentry Error Response: {}", description)),
+    }
+}
+
+fn escape_arg(arg: &str) -> String {
+    let mut escaped = String::new();
+    for ch in arg.chars() {
+        match ch {
+            '%' => escaped += "%25",
+            '\r' => escaped += "%0D",
+            '\n' => escaped += "%0A",
+            _ => escaped.push(ch),
         }
     }
-    let prefix = msg_split[0];
-    let suffix = msg_split[1];
-    if prefix == "ERR" {
-        Err(suffix.to_string())
-    } else {
-        Ok(suffix.to_string())
+    escaped
+}
+
+fn unescape_data(data: &str, decoded: &mut SecretBytes) -> Result<(), PinError> {
+    let bytes = data.as_bytes();
+    let overflow = |_| PinError::Failure("Pinentry data is too long".to_string());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            if idx + 2 >= bytes.len() {
+                return Err(PinError::Failure(
+                    "Truncated escape in pinentry data".to_string(),
+                ));
+            }
+            let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).unwrap_or("");
+            let byte = u8::from_str_radix(hex, 16)
+                .map_err(|_| PinError::Failure("Invalid escape in pinentry data".to_string()))?;
+            decoded.push(byte).map_err(overflow)?;
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]).map_err(overflow)?;
+            idx += 1;
+        }
     }
+    Ok(())
 }
 
-pub fn get_pin(key_name: &str) -> Result<String, String> {
-    let mut pinentry = PinEntry::new()?;
-    let start = pinentry.read_line()?;
-    let _ = parse_response(&start)?;
-    let resp = pinentry.send_command(&format!("SETDESC Please enter PIN for {}", key_name))?;
-    let _ = parse_response(&resp)?;
-    let pin = pinentry.send_command("GETPIN")?;
-    let resp = parse_response(&pin);
-    let _ = pinentry.send_command("BYE");
-    resp
+pub fn get_pin(key_name: &str, error: Option<&str>) -> Result<SecretString, PinError> {
+    let mut pinentry = PinEntry::open(&format!("Please enter PIN for {}", key_name), error)?;
+    let pin = pinentry.send_command("GETPIN", None)?;
+    Ok(pin.unwrap_or_else(|| SecretString::new(String::new())))
 }
 
-pub fn generate_pin(key_name: &str) -> Result<String, String> {
-    let mut pinentry = PinEntry::new()?;
-    let start = pinentry.read_line()?;
-    let _ = parse_response(&start).unwrap();
-    let resp = pinentry.send_command(&format!("SETDESC Please enter PIN for {}", key_name))?;
-    let _ = parse_response(&resp)?;
-    let resp = pinentry.send_command("SETREPEAT Repeat")?;
-    let _ = parse_response(&resp)?;
-    let pin = pinentry.send_command("GETPIN")?;
-    let resp = parse_response(&pin);
-    let _ = pinentry.send_command("BYE");
-    resp
+pub fn generate_pin(key_name: &str) -> Result<SecretString, PinError> {
+    let conf = config::get_config().map_err(PinError::Failure)?;
+    let policy = conf.pin_policy;
+    let max_tries = conf.pinentry.max_tries;
+    let mut error: Option<String> = None;
+    for _ in 0..max_tries {
+        let mut pinentry = PinEntry::open(
+            &format!("Please enter a new PIN for {}", key_name),
+            error.as_deref(),
+        )?;
+        let quality_policy = policy.clone();
+        pinentry.quality = Some(Box::new(move |pin| {
+            pinpolicy::quality_score(&quality_policy, pin)
+        }));
+        pinentry.send_command("SETQUALITYBAR", Some("Quality:"))?;
+        pinentry.send_command(
+            "SETQUALITYBAR_TT",
+            Some("Estimated strength of the PIN you are typing"),
+        )?;
+        pinentry.send_command("SETREPEAT", Some("Repeat:"))?;
+        pinentry.send_command("SETREPEATERROR", Some("PINs do not match"))?;
+        let pin = pinentry
+            .send_command("GETPIN", None)?
+            .unwrap_or_else(|| SecretString::new(String::new()));
+        match pinpolicy::check_pin(&policy, &pin) {
+            Ok(()) => return Ok(pin),
+            Err(violation) => error = Some(violation),
+        }
+    }
+    Err(PinError::Failure(format!(
+        "PIN rejected by policy: {}",
+        error.unwrap_or_default()
+    )))
+}
+
+pub fn wrong_pin_message(tries_left: u32) -> String {
+    if tries_left == 1 {
+        "Wrong PIN, 1 try left".to_string()
+    } else {
+        format!("Wrong PIN, {} tries left", tries_left)
+    }
 }
@@ -1,7 +1,15 @@
+use super::audit;
+use super::author;
+use super::history;
+use super::keychain;
+use super::policy;
 use super::public::PublicKey;
+use super::rules;
 use super::state;
 use super::vault;
 use crate::config;
+use crate::constants;
+use std::collections::BTreeMap;
 use std::path::Path;
 
 pub fn path_is_safe(p: &str) -> bool {
@@ -11,6 +19,79 @@ pub fn path_is_safe(p: &str) -> bool {
     canon_path.starts_with(store_dir)
 }
 
+// where the vault for a logical path is kept, which is hashed when the store hides names
+pub fn store_file(st: &mut state::State, path: &str) -> Result<vault::StoreFile, String> {
+    match st.get_index()? {
+        Some(index) => Ok(index.store_file_for(path)),
+        None => Ok(vault::StoreFile::plain(path)),
+    }
+}
+
+// adds a newly written path to the index of a store that hides its names
+pub fn record_path(st: &mut state::State, path: &str) -> Result<(), String> {
+    match st.get_index()? {
+        Some(index) if !index.contains(path) => index.insert(path),
+        _ => return Ok(()),
+    };
+    write_index(st)
+}
+
+// the index is encrypted to the whole keychain, so it is rewritten when keys change
+pub fn write_index(st: &mut state::State) -> Result<(), String> {
+    if st.get_index()?.is_none() {
+        return Ok(());
+    }
+    let recipients = st.get_chain()?.get_keys().to_vec();
+    st.get_index()?.unwrap().write_index(recipients)?;
+    st.mark_index_written();
+    Ok(())
+}
+
+fn is_reserved(name: &str) -> bool {
+    name == constants::KEYCHAIN_FILE_NAME || name == constants::INDEX_FILE_NAME
+}
+
+fn walk_store(dir: &Path, prefix: &str, paths: &mut Vec<String>) -> Result<(), String> {
+    let entries = dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read directory: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read directory: {}", e))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // partially written vaults
+        if name.starts_with('.') || (prefix.is_empty() && is_reserved(&name)) {
+            continue;
+        }
+        let rel_path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let full_path = entry.path();
+        if full_path.is_dir() {
+            walk_store(&full_path, &rel_path, paths)?;
+        } else if full_path.is_file() {
+            paths.push(rel_path);
+        }
+    }
+    Ok(())
+}
+
+// the logical path of every secret below dir ("" for the whole store)
+pub fn list_files(st: &mut state::State, dir: &str) -> Result<Vec<String>, String> {
+    if let Some(index) = st.get_index()? {
+        return Ok(index.paths_under(dir));
+    }
+    let dir = vault::store_path(dir);
+    let full_path = config::get_store_directory().join(&dir);
+    let mut paths = Vec::new();
+    if full_path.is_dir() {
+        walk_store(&full_path, &dir, &mut paths)?;
+    }
+    paths.sort();
+    Ok(paths)
+}
+
 pub fn get_key_names_for_path(st: &mut state::State, p: &str) -> Result<Vec<String>, String> {
     let chain = st.get_chain()?;
     let keys = chain.get_keys_for_path(p);
@@ -26,84 +107,170 @@ pub fn get_all_key_names(st: &mut state::State) -> Result<Vec<String>, String> {
         .collect())
 }
 
-fn key_names_equal(names1: &[String], names2: &[String]) -> bool {
-    let mut names1_vec = Vec::new();
-    names1_vec.extend_from_slice(names1);
-    names1_vec.sort();
-    let mut names2_vec = Vec::new();
-    names2_vec.extend_from_slice(names2);
-    names2_vec.sort();
-
-    names1_vec == names2_vec
+// checks that every name of a rule is a key or a group of the keychain
+pub fn check_rule_names(st: &mut state::State, names: &[String]) -> Result<(), String> {
+    let chain = st.get_chain()?;
+    for name in names.iter() {
+        let known = match name.strip_prefix(keychain::GROUP_PREFIX) {
+            Some(group) => chain.groups.contains_key(group),
+            None => chain.get_keys().iter().any(|k| k.get_key_name() == name),
+        };
+        if !known {
+            return Err(format!("{} is not a key or group of the keychain", name));
+        }
+    }
+    Ok(())
 }
 
+// sets the rule for the vaults matching pattern, a rule without keys removing the rule for
+// pattern. Returns the number of vaults encrypted again and the ones that could not be.
 pub fn change_keys_for_path(
     st: &mut state::State,
-    path: &str,
-    new_keys: Vec<String>,
-) -> Result<(), String> {
-    let actual_path = config::get_store_directory().join(path);
-    if !actual_path.exists() {
-        return Err(format!("Path '{}' doesn't exist in the store", path));
-    }
-    if actual_path.is_file() {
-        reencrypt_file(st, path, &new_keys);
-    } else if actual_path.is_dir() {
-        reencrypt_dir(st, path, &new_keys);
+    pattern: &str,
+    rule: rules::PathRule,
+) -> Result<(usize, Vec<String>), String> {
+    let pattern = vault::store_path(pattern);
+    rules::check_pattern(&pattern)?;
+    check_rule_names(st, &rule.keys)?;
+    let before = recipients_by_path(st)?;
+    let chain = st.get_chain()?;
+    let keys = rule.keys.clone();
+    let previous = if keys.is_empty() {
+        chain.paths.remove(&pattern)
+    } else {
+        chain.paths.insert(pattern.clone(), rule)
+    };
+    if let Err(err) = policy::check_store(st) {
+        let chain = st.get_chain()?;
+        match previous {
+            Some(previous) => chain.paths.insert(pattern.clone(), previous),
+            None => chain.paths.remove(&pattern),
+        };
+        return Err(err);
     }
-
-    st.get_chain()?.paths.insert(path.to_string(), new_keys);
-    Ok(())
+    st.record_change("change recipients of", Some(&pattern), None);
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        Some(&pattern),
+        &keys,
+        Some("recipients changed"),
+        true,
+    );
+    reencrypt_changed(st, before)
 }
 
-pub fn reencrypt_file(
+// encrypts the vault at path again for new_keys, whatever it was encrypted for
+pub fn write_recipients(
     st: &mut state::State,
     path: &str,
     new_keys: &[String],
 ) -> Result<(), String> {
-    {
-        let keychain = st.get_chain()?;
-        let old_keys: Vec<String> = keychain
-            .get_keys_for_path(path)
+    let store_file = store_file(st, path)?;
+    let (contents, unlocked) = vault::Vault::unlock_vault_with_key(st, &store_file)?;
+    // a vault is only signed again once its current signature checks out
+    author::check_author(st, path, unlocked.signature.as_ref())?;
+    let signer = author::find_author(st, path)?;
+    let keychain = st.get_chain()?;
+    let new_recipients = keychain.key_names_to_keys(&new_keys);
+    if new_recipients.is_empty() {
+        return Err(format!("No key of the keychain may read {}", path));
+    }
+    let threshold = keychain
+        .get_threshold_for_path(path)
+        .map(|(_, t)| t.required);
+    let padding = keychain.padding;
+    let author = signer.as_ref().map(|(name, key)| vault::Author {
+        name,
+        key: key.as_ref(),
+    });
+    let mut source: &[u8] = &contents;
+    vault::Vault::write_threshold_vault_stream(
+        &store_file,
+        &mut source,
+        new_recipients,
+        threshold,
+        padding,
+        author.as_ref(),
+    )
+    .map(|_| ())
+}
+
+// the fingerprints of the keys each vault is encrypted for, taken before the keychain changes,
+// and the number of shares a threshold vault takes, so a change of it alone counts
+pub fn recipients_by_path(st: &mut state::State) -> Result<BTreeMap<String, Vec<String>>, String> {
+    let mut recipients = BTreeMap::new();
+    for path in list_files(st, "")? {
+        let chain = st.get_chain()?;
+        let mut fingerprints: Vec<String> = chain
+            .get_keys_for_path(&path)
             .iter()
-            .map(|k| k.get_key_name().to_string())
+            .map(|k| k.fingerprint())
             .collect();
-        if key_names_equal(&old_keys, new_keys) {
-            return Ok(());
+        fingerprints.sort();
+        if let Some((_, threshold)) = chain.get_threshold_for_path(&path) {
+            fingerprints.push(format!("threshold {}", threshold.required));
         }
+        recipients.insert(path, fingerprints);
     }
+    Ok(recipients)
+}
 
-    let contents = vault::Vault::unlock_vault(st, path)?;
-    let keychain = st.get_chain()?;
-    let new_recipients = keychain.key_names_to_keys(&new_keys);
-    vault::Vault::write_vault(path, &contents, new_recipients)
+// whether everyone who could read a vault still can on their own, a new threshold
+// counting as taking that away
+fn only_gained_readers(before: &[String], after: &[String]) -> bool {
+    before.iter().all(|entry| after.contains(entry))
+        && !after
+            .iter()
+            .any(|entry| entry.starts_with("threshold ") && !before.contains(entry))
 }
 
-pub fn reencrypt_dir(st: &mut state::State, path: &str, new_keys: &[String]) -> Result<(), String> {
-    let full_path = config::get_store_directory().join(path);
-    let sub_entries = full_path
-        .read_dir()
-        .map_err(|e| format!("Unable to read directory: {}", e))?;
+// the earlier revisions of a vault whose readers were taken away, which are still encrypted
+// for them
+fn remove_history(st: &mut state::State, path: &str) -> Result<(), String> {
+    let removed = history::remove_revisions(&store_file(st, path)?)?;
+    if removed > 0 {
+        audit::record_or_log(
+            audit::AuditEvent::Purge,
+            Some(path),
+            &[],
+            Some(&format!(
+                "{} earlier revisions of the old keys removed",
+                removed
+            )),
+            true,
+        );
+    }
+    Ok(())
+}
 
-    for entry in sub_entries {
-        if entry.is_ok() {
-            let entry = entry.unwrap().file_name();
-            let new_path = Path::new(path).join(&entry);
-            let new_full_path = full_path.join(entry);
-            if st
-                .get_chain()?
-                .paths
-                .contains_key(new_path.to_str().unwrap())
-            {
-                continue;
-            }
-            if new_full_path.is_dir() {
-                reencrypt_dir(st, new_path.to_str().unwrap(), new_keys);
-            } else if new_full_path.is_file() {
-                reencrypt_file(st, new_path.to_str().unwrap(), new_keys);
+// encrypts again the vaults whose keys changed since before was taken, dropping their history
+// where keys lost access, returning how many were and the ones that could not be
+pub fn reencrypt_changed(
+    st: &mut state::State,
+    before: BTreeMap<String, Vec<String>>,
+) -> Result<(usize, Vec<String>), String> {
+    let after = recipients_by_path(st)?;
+    let mut reencrypted = 0;
+    let mut failed = Vec::new();
+    for (path, fingerprints) in after {
+        if before.get(&path) == Some(&fingerprints) {
+            continue;
+        }
+        let names = get_key_names_for_path(st, &path)?;
+        if let Err(err) = write_recipients(st, &path, &names) {
+            failed.push(format!("{}: {}", path, err));
+            continue;
+        }
+        reencrypted += 1;
+        let kept = before
+            .get(&path)
+            .is_none_or(|b| only_gained_readers(b, &fingerprints));
+        if !kept {
+            if let Err(err) = remove_history(st, &path) {
+                failed.push(format!("{}: {}", path, err));
             }
         }
     }
-
-    Ok(())
+    Ok((reencrypted, failed))
 }
@@ -0,0 +1,188 @@
+use crate::config;
+
+// score at which the pinentry quality bar is full
+const FULL_QUALITY_BITS: f64 = 64.0;
+
+const COMMON_PINS: &[&str] = &[
+    "000000",
+    "111111",
+    "123123",
+    "123456",
+    "1234567",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "654321",
+    "abc123",
+    "admin",
+    "basalt",
+    "iloveyou",
+    "letmein",
+    "passw0rd",
+    "password",
+    "qwerty",
+    "qwertyuiop",
+    "welcome",
+];
+
+fn pool_size(pin: &str) -> u32 {
+    let mut has_lower = false;
+    let mut has_upper = false;
+    let mut has_digit = false;
+    let mut has_symbol = false;
+    let mut has_other = false;
+    for ch in pin.chars() {
+        if ch.is_ascii_lowercase() {
+            has_lower = true;
+        } else if ch.is_ascii_uppercase() {
+            has_upper = true;
+        } else if ch.is_ascii_digit() {
+            has_digit = true;
+        } else if ch.is_ascii() {
+            has_symbol = true;
+        } else {
+            has_other = true;
+        }
+    }
+
+    let mut pool = 0;
+    if has_lower {
+        pool += 26;
+    }
+    if has_upper {
+        pool += 26;
+    }
+    if has_digit {
+        pool += 10;
+    }
+    if has_symbol {
+        pool += 33;
+    }
+    if has_other {
+        pool += 100;
+    }
+    pool
+}
+
+// rough brute force estimate: each character is worth log2 of the character pool,
+// except repeats and +1/-1 runs ("aaaa", "1234", "dcba") which are worth a single bit
+pub fn estimate_entropy(pin: &str) -> f64 {
+    let pool = pool_size(pin);
+    if pool == 0 {
+        return 0.0;
+    }
+    let per_char = (pool as f64).log2();
+    let chars: Vec<char> = pin.chars().collect();
+    let mut bits = 0.0;
+    for (idx, ch) in chars.iter().enumerate() {
+        if idx > 0 {
+            let delta = *ch as i64 - chars[idx - 1] as i64;
+            if delta.abs() <= 1 {
+                bits += 1.0;
+                continue;
+            }
+        }
+        bits += per_char;
+    }
+    bits
+}
+
+fn is_blocked(policy: &config::PinPolicyConfig, pin: &str) -> bool {
+    let lowered = pin.to_lowercase();
+    COMMON_PINS.iter().any(|p| *p == lowered)
+        || policy.blocklist.iter().any(|p| p.to_lowercase() == lowered)
+}
+
+pub fn check_pin(policy: &config::PinPolicyConfig, pin: &str) -> Result<(), String> {
+    if pin.is_empty() {
+        if policy.allow_unencrypted {
+            return Ok(());
+        }
+        return Err("Unencrypted keys are not allowed, please enter a PIN".to_string());
+    }
+    if pin.chars().count() < policy.min_length {
+        return Err(format!(
+            "PIN must be at least {} characters long",
+            policy.min_length
+        ));
+    }
+    if is_blocked(policy, pin) {
+        return Err("PIN is too common".to_string());
+    }
+    let entropy = estimate_entropy(pin);
+    if entropy < policy.min_entropy {
+        return Err(format!(
+            "PIN is too weak (estimated {:.0} bits, {:.0} required)",
+            entropy, policy.min_entropy
+        ));
+    }
+    Ok(())
+}
+
+// pinentry shows negative scores as not meeting the requirements
+pub fn quality_score(policy: &config::PinPolicyConfig, pin: &str) -> i32 {
+    if pin.is_empty() {
+        return 0;
+    }
+    let score = (estimate_entropy(pin) * 100.0 / FULL_QUALITY_BITS).min(100.0) as i32;
+    if check_pin(policy, pin).is_err() {
+        -score.max(1)
+    } else {
+        score.max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> config::PinPolicyConfig {
+        config::PinPolicyConfig {
+            min_length: 6,
+            min_entropy: 20.0,
+            blocklist: vec!["Correct-Horse".to_string()],
+            allow_unencrypted: false,
+        }
+    }
+
+    #[test]
+    fn empty_pin_needs_allow_unencrypted() {
+        let mut policy = policy();
+        assert!(check_pin(&policy, "").is_err());
+        policy.allow_unencrypted = true;
+        assert!(check_pin(&policy, "").is_ok());
+    }
+
+    #[test]
+    fn short_pins_are_refused() {
+        let policy = policy();
+        assert!(check_pin(&policy, "x9#Qz").is_err());
+        assert!(check_pin(&policy, "x9#Qz!").is_ok());
+    }
+
+    #[test]
+    fn blocklist_ignores_case() {
+        let policy = policy();
+        assert!(check_pin(&policy, "PASSWORD").is_err());
+        assert!(check_pin(&policy, "correct-horse").is_err());
+        assert!(check_pin(&policy, "CORRECT-HORSE").is_err());
+    }
+
+    #[test]
+    fn runs_and_repeats_are_worth_little() {
+        assert!(estimate_entropy("aaaaaaaa") < 12.0);
+        assert!(estimate_entropy("13579bdf") > estimate_entropy("12345678"));
+        assert!(estimate_entropy("zyxwvuts") < 15.0);
+        assert!(check_pin(&policy(), "98765432").is_err());
+    }
+
+    #[test]
+    fn quality_score_sign_follows_the_policy() {
+        let policy = policy();
+        assert_eq!(quality_score(&policy, ""), 0);
+        assert!(quality_score(&policy, "x9#Qz") < 0);
+        assert!(quality_score(&policy, "password") < 0);
+        assert!(quality_score(&policy, "x9#Qz!tK") > 0);
+        assert!(quality_score(&policy, "x9#Qz!tK") <= 100);
+    }
+}
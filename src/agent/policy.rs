@@ -0,0 +1,310 @@
+// Recipient policies the keys of the vaults matching a pattern have to meet, every matching
+// policy applying.
+use super::audit;
+use super::keychain;
+use super::paths;
+use super::public::PublicKey;
+use super::public::PublicKeyWrapper;
+use super::rules;
+use super::state;
+use super::vault;
+use serde::Deserialize;
+use serde::Serialize;
+
+const HARDWARE_KEY_TYPE: &str = "yubikey";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Policy {
+    // keys that must be recipients, for a @group any of its keys will do
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub min_recipients: usize,
+    // at least one recipient is a hardware key
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hardware: bool,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl Policy {
+    pub fn is_empty(&self) -> bool {
+        *self == Policy::default()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.required.is_empty() {
+            parts.push(format!("requires {}", self.required.join(", ")));
+        }
+        if self.min_recipients > 0 {
+            parts.push(format!("at least {} recipients", self.min_recipients));
+        }
+        if self.hardware {
+            parts.push("a hardware key".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+fn describe_pattern(pattern: &str) -> String {
+    if pattern.is_empty() {
+        "the whole store".to_string()
+    } else {
+        pattern.to_string()
+    }
+}
+
+// checks the keys a vault at path is encrypted for against every policy matching path
+pub fn check(
+    chain: &keychain::KeyChain,
+    path: &str,
+    recipients: &[PublicKeyWrapper],
+) -> Result<(), String> {
+    let mut patterns: Vec<&String> = chain
+        .policies
+        .keys()
+        .filter(|pattern| rules::applies(pattern, path))
+        .collect();
+    patterns.sort();
+    let names: Vec<&str> = recipients.iter().map(|k| k.get_key_name()).collect();
+    for pattern in patterns {
+        let policy = &chain.policies[pattern];
+        let rule = describe_pattern(pattern);
+        if recipients.len() < policy.min_recipients {
+            return Err(format!(
+                "The policy of {} requires at least {} recipients, {} would have {}",
+                rule,
+                policy.min_recipients,
+                path,
+                recipients.len()
+            ));
+        }
+        for required in policy.required.iter() {
+            let members = chain.expand_names(std::slice::from_ref(required));
+            if !members.iter().any(|m| names.contains(&m.as_str())) {
+                return Err(format!(
+                    "The policy of {} requires {} among the recipients of {}",
+                    rule, required, path
+                ));
+            }
+        }
+        if policy.hardware && !recipients.iter().any(|k| k.key_type() == HARDWARE_KEY_TYPE) {
+            return Err(format!(
+                "The policy of {} requires a hardware key among the recipients of {}",
+                rule, path
+            ));
+        }
+    }
+    Ok(())
+}
+
+// a threshold rule naming a group takes the keys the group now comes to
+fn check_thresholds(chain: &keychain::KeyChain) -> Result<(), String> {
+    let mut paths: Vec<&String> = chain.thresholds.keys().collect();
+    paths.sort();
+    for path in paths {
+        let threshold = &chain.thresholds[path];
+        let holders = chain.key_names_to_keys(&threshold.keys).len();
+        if threshold.required as usize > holders {
+            return Err(format!(
+                "The threshold of {} takes {} shares but its keys {} would come to {}",
+                describe_pattern(path),
+                threshold.required,
+                threshold.keys.join(", "),
+                holders
+            ));
+        }
+    }
+    Ok(())
+}
+
+// checks every vault of the store against the policies and the threshold rules against
+// their holders, as the keychain now stands
+pub fn check_store(st: &mut state::State) -> Result<(), String> {
+    check_thresholds(st.get_chain()?)?;
+    for path in paths::list_files(st, "")? {
+        let chain = st.get_chain()?;
+        check(chain, &path, &chain.get_keys_for_path(&path))?;
+    }
+    Ok(())
+}
+
+// sets the policy of the vaults matching pattern, an empty policy removing it. The policy
+// is refused when a vault of the store already breaks it.
+pub fn set(st: &mut state::State, pattern: &str, policy: Policy) -> Result<(), String> {
+    let pattern = vault::store_path(pattern);
+    rules::check_pattern(&pattern)?;
+    paths::check_rule_names(st, &policy.required)?;
+    // hardware keys are never generated here, only imported
+    if policy.hardware
+        && !st
+            .get_chain()?
+            .get_keys()
+            .iter()
+            .any(|k| k.key_type() == HARDWARE_KEY_TYPE)
+    {
+        return Err("The keychain has no hardware key, import one before requiring it".to_string());
+    }
+    let description = if policy.is_empty() {
+        "policy removed".to_string()
+    } else {
+        format!("policy changed: {}", policy.describe())
+    };
+    let chain = st.get_chain()?;
+    let previous = if policy.is_empty() {
+        chain.policies.remove(&pattern)
+    } else {
+        chain.policies.insert(pattern.clone(), policy)
+    };
+    if let Err(err) = check_store(st) {
+        let chain = st.get_chain()?;
+        match previous {
+            Some(previous) => chain.policies.insert(pattern.clone(), previous),
+            None => chain.policies.remove(&pattern),
+        };
+        return Err(format!("{}, change its rules first", err));
+    }
+    st.record_change("change policy of", Some(&pattern), None);
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        Some(&pattern),
+        &[],
+        Some(&description),
+        true,
+    );
+    Ok(())
+}
+
+// pads the vaults written from now on by the rule, the ones already written keep theirs
+// until they are next written
+pub fn set_padding(st: &mut state::State, padding: vault::PaddingRule) -> Result<(), String> {
+    let chain = st.get_chain()?;
+    if chain.padding == padding {
+        return Ok(());
+    }
+    chain.padding = padding;
+    st.record_change("change padding", None, None);
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        None,
+        &[],
+        Some(&format!("padding changed: {}", padding.describe())),
+        true,
+    );
+    Ok(())
+}
+
+// every policy by its pattern
+pub fn show(st: &mut state::State) -> Result<Vec<(String, Policy)>, String> {
+    let mut policies: Vec<(String, Policy)> = st
+        .get_chain()?
+        .policies
+        .iter()
+        .map(|(pattern, policy)| (pattern.clone(), policy.clone()))
+        .collect();
+    policies.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::private::SodiumPrivateKey;
+    use crate::agent::public::Yubikey;
+    use crate::agent::threshold::Threshold;
+    use sodiumoxide::crypto::box_;
+
+    fn chain_with(pattern: &str, policy: Policy) -> keychain::KeyChain {
+        let mut chain: keychain::KeyChain =
+            serde_json::from_str(r#"{"timestamp": 0, "keys": [], "paths": {}}"#).unwrap();
+        chain.policies.insert(pattern.to_string(), policy);
+        chain.groups.insert(
+            "ops".to_string(),
+            vec!["alice".to_string(), "bob".to_string()],
+        );
+        chain
+    }
+
+    fn sodium(name: &str) -> PublicKeyWrapper {
+        SodiumPrivateKey::gen_key().get_public_key(name)
+    }
+
+    fn yubikey(name: &str) -> PublicKeyWrapper {
+        PublicKeyWrapper::Yubikey(Yubikey {
+            name: name.to_string(),
+            enc_key: box_::gen_keypair().0,
+            challenge: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn thresholds_count_the_keys_of_their_groups() {
+        sodiumoxide::init().unwrap();
+        let mut chain = chain_with("", Policy::default());
+        chain.add_key(sodium("alice"));
+        chain.add_key(sodium("bob"));
+        chain.thresholds.insert(
+            "vault".to_string(),
+            Threshold {
+                required: 2,
+                keys: vec!["@ops".to_string()],
+            },
+        );
+        assert!(check_thresholds(&chain).is_ok());
+        chain
+            .groups
+            .insert("ops".to_string(), vec!["alice".to_string()]);
+        let err = check_thresholds(&chain).unwrap_err();
+        assert!(err.contains("would come to 1"));
+    }
+
+    #[test]
+    fn min_recipients_counts_the_keys() {
+        sodiumoxide::init().unwrap();
+        let policy = Policy {
+            min_recipients: 2,
+            ..Policy::default()
+        };
+        let chain = chain_with("prod", policy);
+        assert!(check(&chain, "prod/db", &[sodium("alice")]).is_err());
+        assert!(check(&chain, "prod/db", &[sodium("alice"), sodium("bob")]).is_ok());
+        assert!(check(&chain, "dev/db", &[sodium("alice")]).is_ok());
+    }
+
+    #[test]
+    fn required_names_expand_groups() {
+        sodiumoxide::init().unwrap();
+        let policy = Policy {
+            required: vec!["@ops".to_string(), "carol".to_string()],
+            ..Policy::default()
+        };
+        let chain = chain_with("", policy);
+        assert!(check(&chain, "db", &[sodium("bob"), sodium("carol")]).is_ok());
+        let err = check(&chain, "db", &[sodium("carol"), sodium("dave")]).unwrap_err();
+        assert!(err.contains("requires @ops"));
+        let err = check(&chain, "db", &[sodium("alice")]).unwrap_err();
+        assert!(err.contains("requires carol"));
+    }
+
+    #[test]
+    fn hardware_needs_a_hardware_recipient() {
+        sodiumoxide::init().unwrap();
+        let policy = Policy {
+            hardware: true,
+            ..Policy::default()
+        };
+        let chain = chain_with("*/prod", policy);
+        assert!(check(&chain, "app/prod/db", &[sodium("alice")]).is_err());
+        assert!(check(&chain, "app/prod/db", &[sodium("alice"), yubikey("token")]).is_ok());
+        assert!(check(&chain, "app/dev/db", &[sodium("alice")]).is_ok());
+    }
+}
@@ -1,90 +1,324 @@
+use super::audit;
+use super::durable;
+use super::logger;
+use super::passphrase;
 use super::public;
+use super::secure::SecretBytes;
+use super::secure::SecretString;
 use crate::config;
 use glob::glob;
 use serde::Deserialize;
 use serde::Serialize;
 use sodiumoxide::crypto::box_;
-use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::pwhash::scryptsalsa208sha256;
 use sodiumoxide::crypto::sealedbox;
 use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::sign;
 use std::fs;
 
+// a key able to sign, with its name
+pub type Signer = (String, Box<dyn PrivateKey>);
+
 pub trait PrivateKey {
-    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, String>;
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<SecretBytes, String>;
     fn duplicate(&self) -> Box<dyn PrivateKey>;
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String>;
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SodiumPrivateKey {
-    dec_key: box_::SecretKey,
+    dec_key: SecretBytes,
+    // Ed25519, missing from keys generated before the keychain was signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sign_key: Option<SecretBytes>,
 }
 
 impl SodiumPrivateKey {
     pub fn gen_key() -> SodiumPrivateKey {
         let (_, sec_key) = box_::gen_keypair();
-        SodiumPrivateKey { dec_key: sec_key }
+        let mut key = SodiumPrivateKey {
+            dec_key: SecretBytes::from_slice(&sec_key.0),
+            sign_key: None,
+        };
+        key.add_signing_key();
+        key
+    }
+
+    pub fn has_signing_key(&self) -> bool {
+        self.sign_key.is_some()
+    }
+
+    pub fn add_signing_key(&mut self) {
+        let (_, sign_key) = sign::gen_keypair();
+        self.sign_key = Some(SecretBytes::from_slice(&sign_key.0));
+    }
+
+    fn secret_key(&self) -> box_::SecretKey {
+        box_::SecretKey::from_slice(&self.dec_key).unwrap()
+    }
+
+    fn signing_key(&self) -> Option<sign::SecretKey> {
+        self.sign_key
+            .as_ref()
+            .map(|key| sign::SecretKey::from_slice(key).unwrap())
+    }
+
+    fn is_valid(&self) -> bool {
+        self.dec_key.len() == box_::SECRETKEYBYTES
+            && self
+                .sign_key
+                .as_ref()
+                .is_none_or(|key| key.len() == sign::SECRETKEYBYTES)
     }
 
     pub fn get_public_key(&self, key_name: &str) -> public::PublicKeyWrapper {
-        let sodium_public_key = self.dec_key.public_key();
+        let sodium_public_key = self.secret_key().public_key();
         let sodium_public_key = public::SodiumKey {
             name: key_name.to_string(),
             enc_key: sodium_public_key,
+            sign_key: self.signing_key().map(|key| key.public_key()),
         };
         public::PublicKeyWrapper::Sodium(sodium_public_key)
     }
 }
 
 impl PrivateKey for SodiumPrivateKey {
-    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
-        let pub_key = self.dec_key.public_key();
-        let dec_res = sealedbox::open(ciphertext, &pub_key, &self.dec_key);
-        dec_res.map_err(|_| "Unable to decrypt ciphertext".to_string())
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<SecretBytes, String> {
+        let sec_key = self.secret_key();
+        let pub_key = sec_key.public_key();
+        let dec_res = sealedbox::open(ciphertext, &pub_key, &sec_key);
+        dec_res
+            .map(SecretBytes::new)
+            .map_err(|_| "Unable to decrypt ciphertext".to_string())
     }
 
     fn duplicate(&self) -> Box<dyn PrivateKey> {
         Box::new(self.clone())
     }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let sign_key = self
+            .signing_key()
+            .ok_or("Key has no signing key".to_string())?;
+        Ok(sign::sign_detached(message, &sign_key).0.to_vec())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum KdfAlgorithm {
+    Scrypt,
+    Argon2id,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum KdfStrength {
+    Interactive,
+    Moderate,
+    Sensitive,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct KdfParams {
+    algorithm: KdfAlgorithm,
+    ops_limit: usize,
+    mem_limit: usize,
+}
+
+impl KdfParams {
+    pub fn new(strength: KdfStrength) -> KdfParams {
+        let (ops_limit, mem_limit) = match strength {
+            KdfStrength::Interactive => (
+                argon2id13::OPSLIMIT_INTERACTIVE,
+                argon2id13::MEMLIMIT_INTERACTIVE,
+            ),
+            KdfStrength::Moderate => (argon2id13::OPSLIMIT_MODERATE, argon2id13::MEMLIMIT_MODERATE),
+            KdfStrength::Sensitive => (
+                argon2id13::OPSLIMIT_SENSITIVE,
+                argon2id13::MEMLIMIT_SENSITIVE,
+            ),
+        };
+        KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            ops_limit: ops_limit.0,
+            mem_limit: mem_limit.0,
+        }
+    }
+
+    // key files written before the parameters were recorded used the scrypt interactive limits
+    fn legacy() -> KdfParams {
+        KdfParams {
+            algorithm: KdfAlgorithm::Scrypt,
+            ops_limit: scryptsalsa208sha256::OPSLIMIT_INTERACTIVE.0,
+            mem_limit: scryptsalsa208sha256::MEMLIMIT_INTERACTIVE.0,
+        }
+    }
+
+    pub fn is_stronger_than(&self, other: &KdfParams) -> bool {
+        if self.algorithm != other.algorithm {
+            return self.algorithm == KdfAlgorithm::Argon2id;
+        }
+        self != other && self.ops_limit >= other.ops_limit && self.mem_limit >= other.mem_limit
+    }
+
+    fn gen_salt(&self) -> Vec<u8> {
+        match self.algorithm {
+            KdfAlgorithm::Scrypt => scryptsalsa208sha256::gen_salt().0.to_vec(),
+            KdfAlgorithm::Argon2id => argon2id13::gen_salt().0.to_vec(),
+        }
+    }
+
+    fn derive_key(&self, key: &mut [u8], pwd: &[u8], salt: &[u8]) -> Result<(), String> {
+        let res = match self.algorithm {
+            KdfAlgorithm::Scrypt => {
+                let salt = scryptsalsa208sha256::Salt::from_slice(salt)
+                    .ok_or("Invalid salt length for scrypt".to_string())?;
+                scryptsalsa208sha256::derive_key(
+                    key,
+                    pwd,
+                    &salt,
+                    scryptsalsa208sha256::OpsLimit(self.ops_limit),
+                    scryptsalsa208sha256::MemLimit(self.mem_limit),
+                )
+                .map(|_| ())
+            }
+            KdfAlgorithm::Argon2id => {
+                let salt = argon2id13::Salt::from_slice(salt)
+                    .ok_or("Invalid salt length for argon2id".to_string())?;
+                argon2id13::derive_key(
+                    key,
+                    pwd,
+                    &salt,
+                    argon2id13::OpsLimit(self.ops_limit),
+                    argon2id13::MemLimit(self.mem_limit),
+                )
+                .map(|_| ())
+            }
+        };
+        res.map_err(|_| "Insufficient memory for hashing".to_string())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedSodiumKey {
-    salt: pwhash::Salt,
+    salt: Vec<u8>,
     nonce: secretbox::Nonce,
     dec_key_cipher: Vec<u8>,
+    #[serde(default = "KdfParams::legacy")]
+    kdf: KdfParams,
 }
 
 impl EncryptedSodiumKey {
-    pub fn encrypt_key(key: &SodiumPrivateKey, pwd: &[u8]) -> Result<EncryptedSodiumKey, String> {
-        let salt = pwhash::gen_salt();
+    pub fn encrypt_key(
+        key: &SodiumPrivateKey,
+        pwd: &[u8],
+        kdf: KdfParams,
+    ) -> Result<EncryptedSodiumKey, String> {
+        let salt = kdf.gen_salt();
         let nonce = secretbox::gen_nonce();
-        let mut sym_key: [u8; secretbox::KEYBYTES] = [0; secretbox::KEYBYTES];
+        let mut sym_key = SecretBytes::new(vec![0; secretbox::KEYBYTES]);
 
-        pwhash::derive_key_interactive(&mut sym_key, pwd, &salt)
-            .map_err(|_| "unable to allocated pwhash memory".to_string())?;
+        kdf.derive_key(&mut sym_key, pwd, &salt)?;
         let sym_key_formatted = secretbox::Key::from_slice(&sym_key).unwrap();
-        let ciphertext = secretbox::seal(&key.dec_key.0, &nonce, &sym_key_formatted);
+        // the signing key, when there is one, is sealed right after the decryption key
+        let mut plaintext = SecretBytes::new(Vec::with_capacity(
+            box_::SECRETKEYBYTES + sign::SECRETKEYBYTES,
+        ));
+        plaintext.extend_from_slice(&key.dec_key)?;
+        if let Some(sign_key) = key.sign_key.as_ref() {
+            plaintext.extend_from_slice(sign_key)?;
+        }
+        let ciphertext = secretbox::seal(&plaintext, &nonce, &sym_key_formatted);
         Ok(EncryptedSodiumKey {
             salt,
             nonce,
             dec_key_cipher: ciphertext,
+            kdf,
         })
     }
 
     pub fn decrypt_key(&self, pwd: &[u8]) -> Result<SodiumPrivateKey, String> {
-        let mut sym_key: [u8; secretbox::KEYBYTES] = [0; secretbox::KEYBYTES];
-        pwhash::derive_key_interactive(&mut sym_key, pwd, &self.salt)
-            .map_err(|_| "Insufficient memory for hashing".to_string())?;
+        let mut sym_key = SecretBytes::new(vec![0; secretbox::KEYBYTES]);
+        self.kdf.derive_key(&mut sym_key, pwd, &self.salt)?;
         let sym_key_formatted = secretbox::Key::from_slice(&sym_key).unwrap();
-        let dec_key = secretbox::open(&self.dec_key_cipher, &self.nonce, &sym_key_formatted)
+        let plaintext = secretbox::open(&self.dec_key_cipher, &self.nonce, &sym_key_formatted)
+            .map(SecretBytes::new)
             .map_err(|_| "Unable to decrypt secret key".to_string())?;
 
-        let dec_key_formatted = box_::SecretKey::from_slice(&dec_key)
-            .ok_or("Invalid secret key decrypted".to_string())?;
+        if plaintext.len() < box_::SECRETKEYBYTES {
+            return Err("Invalid secret key decrypted".to_string());
+        }
+        let (dec_key, sign_key) = plaintext.split_at(box_::SECRETKEYBYTES);
+        let key = SodiumPrivateKey {
+            dec_key: SecretBytes::from_slice(dec_key),
+            sign_key: if sign_key.is_empty() {
+                None
+            } else {
+                Some(SecretBytes::from_slice(sign_key))
+            },
+        };
+        if !key.is_valid() {
+            return Err("Invalid secret key decrypted".to_string());
+        }
+        Ok(key)
+    }
 
-        Ok(SodiumPrivateKey {
-            dec_key: dec_key_formatted,
-        })
+    // prompts for the PIN until it decrypts the key, returning the key and the PIN that opened it
+    pub fn unlock(
+        &self,
+        key_name: &str,
+    ) -> Result<Option<(SodiumPrivateKey, SecretString)>, String> {
+        let max_tries = config::get_config()?.pinentry.max_tries;
+        let mut error = None;
+        for attempt in 0..max_tries {
+            let pin = passphrase::get_pin(key_name, error.as_deref());
+            if pin.is_err() {
+                let err = pin.err().unwrap();
+                audit::record_or_log(
+                    audit::AuditEvent::KeyUnlock,
+                    None,
+                    &[key_name.to_string()],
+                    Some(&err.to_string()),
+                    false,
+                );
+                if err.is_cancelled() {
+                    return Err(err.to_string());
+                }
+                logger::warn(&format!("Unable to get PIN for {}: {}", key_name, err));
+                return Ok(None);
+            }
+            let pin = pin.unwrap();
+            let dec_key = self.decrypt_key(pin.as_bytes());
+            if dec_key.is_err() {
+                error = Some(passphrase::wrong_pin_message(max_tries - attempt - 1));
+                continue;
+            }
+            audit::record_or_log(
+                audit::AuditEvent::KeyUnlock,
+                None,
+                &[key_name.to_string()],
+                None,
+                true,
+            );
+            return Ok(Some((dec_key.unwrap(), pin)));
+        }
+        let msg = format!(
+            "Giving up on key {} after {} wrong PINs",
+            key_name, max_tries
+        );
+        logger::warn(&msg);
+        audit::record_or_log(
+            audit::AuditEvent::KeyUnlock,
+            None,
+            &[key_name.to_string()],
+            Some(&msg),
+            false,
+        );
+        Ok(None)
+    }
+
+    pub fn get_kdf(&self) -> &KdfParams {
+        &self.kdf
     }
 }
 
@@ -132,9 +366,9 @@ impl DeviceKey {
             );
             std::process::exit(1);
         }
-        let json_bytes = json_bytes.unwrap();
+        let json_bytes = SecretBytes::new(json_bytes.unwrap());
         let fname = config::get_keys_directory().join(format!("{}.key", key_name));
-        let write_res = fs::write(&fname, &json_bytes);
+        let write_res = durable::write_file(&fname, &json_bytes);
         if write_res.is_err() {
             eprintln!(
                 "Unable to write private key to file: {}",
@@ -150,7 +384,16 @@ impl DeviceKey {
             return Err(format!("No key file for name: {}", key_name));
         }
 
-        let json_bytes = fs::read(&fname).map_err(|e| format!("Unable to read key: {}", e))?;
-        serde_json::from_slice(&json_bytes).map_err(|e| format!("Unable to parse key json: {}", e))
+        let json_bytes = fs::read(&fname)
+            .map(SecretBytes::new)
+            .map_err(|e| format!("Unable to read key: {}", e))?;
+        let dev_key: DeviceKey = serde_json::from_slice(&json_bytes)
+            .map_err(|e| format!("Unable to parse key json: {}", e))?;
+        if let DeviceKey::Unencrypted(ref key) = dev_key {
+            if !key.is_valid() {
+                return Err(format!("Invalid secret key in key file: {}", key_name));
+            }
+        }
+        Ok(dev_key)
     }
 }
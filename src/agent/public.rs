@@ -1,7 +1,15 @@
+use super::secure::SecretString;
 use serde::Deserialize;
 use serde::Serialize;
 use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::sealedbox;
+use sodiumoxide::crypto::sign;
+
+const FINGERPRINT_CONTEXT: &[u8] = b"basalt-key-fingerprint";
+
+// the version of exported keys, 2 from when fingerprints cover the signing key as well
+pub const EXPORT_FORMAT_VERSION: u16 = 2;
 
 pub trait PublicKey {
     fn get_key_name(&self) -> &str;
@@ -36,6 +44,57 @@ impl PublicKeyWrapper {
             _ => false,
         }
     }
+
+    // only sodium keys can sign for now
+    pub fn get_sign_key(&self) -> Option<&sign::PublicKey> {
+        match self {
+            PublicKeyWrapper::Sodium(key) => key.sign_key.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_enc_key(&self) -> &box_::PublicKey {
+        match self {
+            PublicKeyWrapper::Sodium(key) => &key.enc_key,
+            PublicKeyWrapper::PaperKey(key) => &key.enc_key,
+            PublicKeyWrapper::Yubikey(key) => &key.enc_key,
+        }
+    }
+
+    pub fn key_type(&self) -> &str {
+        match self {
+            PublicKeyWrapper::Sodium(_) => "sodium",
+            PublicKeyWrapper::PaperKey(_) => "paper key",
+            PublicKeyWrapper::Yubikey(_) => "yubikey",
+        }
+    }
+
+    // identifies the key whatever it is named, the hex sha256 of its encryption key followed
+    // by its signing key if it has one
+    pub fn fingerprint(&self) -> String {
+        let mut encoded = FINGERPRINT_CONTEXT.to_vec();
+        encoded.extend_from_slice(&self.get_enc_key().0);
+        if let Some(sign_key) = self.get_sign_key() {
+            encoded.extend_from_slice(&sign_key.0);
+        }
+        let digest = sha256::hash(&encoded);
+        let mut fingerprint = String::with_capacity(digest.0.len() * 2);
+        for byte in digest.0.iter() {
+            fingerprint += &format!("{:02x}", byte);
+        }
+        fingerprint
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let sign_key = self.get_sign_key();
+        let signature = sign::Signature::from_slice(signature);
+        match (sign_key, signature) {
+            (Some(sign_key), Some(signature)) => {
+                sign::verify_detached(&signature, message, sign_key)
+            }
+            _ => false,
+        }
+    }
 }
 
 impl PublicKey for PublicKeyWrapper {
@@ -56,10 +115,52 @@ impl PublicKey for PublicKeyWrapper {
     }
 }
 
+// a public key as handed to someone else, see key export
+#[derive(Serialize, Deserialize)]
+pub struct ExportedKey {
+    #[serde(default)]
+    pub format_version: u16,
+    pub name: String,
+    pub key_type: String,
+    pub fingerprint: String,
+    pub key: PublicKeyWrapper,
+}
+
+impl ExportedKey {
+    pub fn new(key: PublicKeyWrapper) -> Self {
+        ExportedKey {
+            format_version: EXPORT_FORMAT_VERSION,
+            name: key.get_key_name().to_string(),
+            key_type: key.key_type().to_string(),
+            fingerprint: key.fingerprint(),
+            key,
+        }
+    }
+
+    // whether what the blob says about the key holds
+    pub fn check(&self) -> Result<(), String> {
+        if self.format_version != EXPORT_FORMAT_VERSION {
+            return Err(format!(
+                "The key was exported in format {}, ask its owner to export it again in format {}",
+                self.format_version, EXPORT_FORMAT_VERSION
+            ));
+        }
+        if self.fingerprint != self.key.fingerprint() {
+            return Err("The fingerprint of the exported key does not match the key".to_string());
+        }
+        if self.name != self.key.get_key_name() || self.key_type != self.key.key_type() {
+            return Err("The exported key is damaged".to_string());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SodiumKey {
     pub name: String,
     pub enc_key: box_::PublicKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign_key: Option<sign::PublicKey>,
 }
 
 impl PublicKey for SodiumKey {
@@ -79,9 +180,9 @@ pub struct PaperKey {
 }
 
 impl PaperKey {
-    pub fn new(name: String) -> (String, Self) {
+    pub fn new(name: String) -> (SecretString, Self) {
         let (pubkey, seckey) = box_::gen_keypair();
-        let paper_sec_key = PaperKey::bytes_to_hex(&seckey.0);
+        let paper_sec_key = SecretString::new(PaperKey::bytes_to_hex(&seckey.0));
         (
             paper_sec_key,
             PaperKey {
@@ -97,7 +198,7 @@ impl PaperKey {
     }
 
     fn bytes_to_hex(bytes: &[u8]) -> String {
-        let mut byte_string = String::new();
+        let mut byte_string = String::with_capacity(bytes.len() * 2);
         for byte in bytes {
             byte_string += &format!("{:.02x}", byte);
         }
@@ -156,3 +257,43 @@ impl PublicKey for Yubikey {
         sealedbox::seal(message, &self.enc_key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::private::SodiumPrivateKey;
+
+    #[test]
+    fn fingerprint_covers_the_signing_key() {
+        sodiumoxide::init().unwrap();
+        let key = SodiumPrivateKey::gen_key().get_public_key("alice");
+        let mut other = match key.clone() {
+            PublicKeyWrapper::Sodium(key) => key,
+            _ => unreachable!(),
+        };
+        other.name = "bob".to_string();
+        assert_eq!(
+            PublicKeyWrapper::Sodium(other.clone()).fingerprint(),
+            key.fingerprint()
+        );
+        other.sign_key = Some(sign::gen_keypair().0);
+        let signing = PublicKeyWrapper::Sodium(other.clone()).fingerprint();
+        assert_ne!(signing, key.fingerprint());
+        other.sign_key = Some(sign::gen_keypair().0);
+        assert_ne!(PublicKeyWrapper::Sodium(other).fingerprint(), signing);
+    }
+
+    #[test]
+    fn exported_keys_are_checked() {
+        sodiumoxide::init().unwrap();
+        let key = SodiumPrivateKey::gen_key().get_public_key("alice");
+        let exported = ExportedKey::new(key.clone());
+        assert!(exported.check().is_ok());
+        let mut older = ExportedKey::new(key.clone());
+        older.format_version = 0;
+        assert!(older.check().is_err());
+        let mut swapped = ExportedKey::new(key);
+        swapped.key = SodiumPrivateKey::gen_key().get_public_key("alice");
+        assert!(swapped.check().is_err());
+    }
+}
@@ -0,0 +1,317 @@
+// Path rules choosing the keys of vaults. A plain path matches itself and everything below
+// it, a glob (* within a part, ** across parts) matches what it describes and below.
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+const GLOB_CHARS: &[char] = &['*', '?', '['];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum RuleMode {
+    #[default]
+    Replace,
+    Add,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "PathRuleRepr", into = "PathRuleRepr")]
+pub struct PathRule {
+    pub keys: Vec<String>,
+    pub priority: i32,
+    pub mode: RuleMode,
+}
+
+// rules from before priorities and modes are bare lists of keys, and plain rules are still
+// written that way so older keychains read the same
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PathRuleRepr {
+    Keys(Vec<String>),
+    Rule {
+        keys: Vec<String>,
+        #[serde(default)]
+        priority: i32,
+        #[serde(default)]
+        mode: RuleMode,
+    },
+}
+
+impl From<PathRuleRepr> for PathRule {
+    fn from(repr: PathRuleRepr) -> Self {
+        match repr {
+            PathRuleRepr::Keys(keys) => PathRule::new(keys),
+            PathRuleRepr::Rule {
+                keys,
+                priority,
+                mode,
+            } => PathRule {
+                keys,
+                priority,
+                mode,
+            },
+        }
+    }
+}
+
+impl From<PathRule> for PathRuleRepr {
+    fn from(rule: PathRule) -> Self {
+        if rule.priority == 0 && rule.mode == RuleMode::Replace {
+            return PathRuleRepr::Keys(rule.keys);
+        }
+        PathRuleRepr::Rule {
+            keys: rule.keys,
+            priority: rule.priority,
+            mode: rule.mode,
+        }
+    }
+}
+
+impl PathRule {
+    pub fn new(keys: Vec<String>) -> Self {
+        PathRule {
+            keys,
+            priority: 0,
+            mode: RuleMode::Replace,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = self.keys.join(", ");
+        if self.mode == RuleMode::Add {
+            text += " (added)";
+        }
+        if self.priority != 0 {
+            text += &format!(" (priority {})", self.priority);
+        }
+        text
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(GLOB_CHARS)
+}
+
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    if is_glob(pattern) {
+        glob::Pattern::new(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+    }
+    Ok(())
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    if !is_glob(pattern) {
+        return pattern == path;
+    }
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    glob::Pattern::new(pattern)
+        .map(|p| p.matches_with(path, options))
+        .unwrap_or(false)
+}
+
+// the number of parts of the deepest of path and the directories above it that pattern
+// matches, None when it matches none of them
+fn match_depth(pattern: &str, path: &str) -> Option<usize> {
+    Path::new(path)
+        .ancestors()
+        .map(|p| p.to_string_lossy().to_string())
+        .find(|ancestor| matches(pattern, ancestor))
+        .map(|ancestor| ancestor.split('/').filter(|p| !p.is_empty()).count())
+}
+
+// whether pattern matches path or a directory above it
+pub fn applies(pattern: &str, path: &str) -> bool {
+    match_depth(pattern, path).is_some()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Effect {
+    // the rule decided the keys
+    Chose,
+    Added,
+    // a replacing rule ranked above it decided the keys
+    Overridden,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RuleMatch {
+    pub pattern: String,
+    pub rule: PathRule,
+    pub effect: Effect,
+}
+
+// the rules matching path with what each did, ranked by priority, then by the depth they
+// match at and their literal characters. A replacing rule decides the keys and those ranked
+// below it do not count, additive rules above it add theirs.
+pub fn rank(rules: &HashMap<String, PathRule>, path: &str) -> Vec<RuleMatch> {
+    let mut matching: Vec<(usize, &String, &PathRule)> = rules
+        .iter()
+        .filter_map(|(pattern, rule)| match_depth(pattern, path).map(|d| (d, pattern, rule)))
+        .collect();
+    let literal = |pattern: &str| pattern.chars().filter(|c| !GLOB_CHARS.contains(c)).count();
+    matching.sort_by(|a, b| {
+        b.2.priority
+            .cmp(&a.2.priority)
+            .then(b.0.cmp(&a.0))
+            .then(literal(b.1).cmp(&literal(a.1)))
+            .then_with(|| a.1.cmp(b.1))
+    });
+    let mut decided = false;
+    matching
+        .into_iter()
+        .map(|(_, pattern, rule)| {
+            let effect = if decided {
+                Effect::Overridden
+            } else if rule.mode == RuleMode::Replace {
+                decided = true;
+                Effect::Chose
+            } else {
+                Effect::Added
+            };
+            RuleMatch {
+                pattern: pattern.clone(),
+                rule: rule.clone(),
+                effect,
+            }
+        })
+        .collect()
+}
+
+// the names the rules give path and the names added to them, no names when no replacing
+// rule matches
+pub fn names_for_path(
+    rules: &HashMap<String, PathRule>,
+    path: &str,
+) -> (Option<Vec<String>>, Vec<String>) {
+    let mut chosen = None;
+    let mut added = Vec::new();
+    for ranked in rank(rules, path) {
+        match ranked.effect {
+            Effect::Chose => chosen = Some(ranked.rule.keys),
+            Effect::Added => added.extend(ranked.rule.keys),
+            Effect::Overridden => {}
+        }
+    }
+    (chosen, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(keys: &[&str], priority: i32, mode: RuleMode) -> PathRule {
+        PathRule {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            priority,
+            mode,
+        }
+    }
+
+    fn rules(list: &[(&str, PathRule)]) -> HashMap<String, PathRule> {
+        list.iter()
+            .map(|(pattern, rule)| (pattern.to_string(), rule.clone()))
+            .collect()
+    }
+
+    fn chosen(rules: &HashMap<String, PathRule>, path: &str) -> Option<Vec<String>> {
+        names_for_path(rules, path).0
+    }
+
+    #[test]
+    fn globs_match_within_and_below() {
+        assert!(applies("*/prod", "staging/prod/db"));
+        assert!(applies("*/prod/**", "staging/prod/db"));
+        assert!(applies("**/*.pem", "a/b/c/key.pem"));
+        assert!(!applies("*/prod", "a/b/prod/db"));
+        assert!(!applies("*.pem", "dir/key.pem"));
+        assert!(applies("team", "team/db"));
+        assert!(!applies("team", "teams/db"));
+    }
+
+    #[test]
+    fn the_most_specific_rule_decides() {
+        let mut set = rules(&[
+            ("*/prod/**", rule(&["ops"], 0, RuleMode::Replace)),
+            ("**/*.pem", rule(&["pki"], 0, RuleMode::Replace)),
+        ]);
+        // both match the path itself, */prod/** has more literal characters
+        assert_eq!(
+            chosen(&set, "app/prod/key.pem"),
+            Some(vec!["ops".to_string()])
+        );
+        assert_eq!(
+            chosen(&set, "app/dev/key.pem"),
+            Some(vec!["pki".to_string()])
+        );
+        set.insert(
+            "app/prod/key.pem".to_string(),
+            rule(&["alice"], 0, RuleMode::Replace),
+        );
+        assert_eq!(
+            chosen(&set, "app/prod/key.pem"),
+            Some(vec!["alice".to_string()])
+        );
+        // a rule on a directory above is less specific than a glob matching the path
+        let set = rules(&[
+            ("app/prod", rule(&["alice"], 0, RuleMode::Replace)),
+            ("**/*.pem", rule(&["pki"], 0, RuleMode::Replace)),
+        ]);
+        assert_eq!(
+            chosen(&set, "app/prod/key.pem"),
+            Some(vec!["pki".to_string()])
+        );
+        assert_eq!(chosen(&set, "app/prod/db"), Some(vec!["alice".to_string()]));
+        assert_eq!(chosen(&set, "app/dev/db"), None);
+    }
+
+    #[test]
+    fn priority_ranks_first_and_ties_fall_back_to_specificity() {
+        let set = rules(&[
+            ("app", rule(&["ops"], 1, RuleMode::Replace)),
+            ("app/prod/db", rule(&["alice"], 0, RuleMode::Replace)),
+        ]);
+        assert_eq!(chosen(&set, "app/prod/db"), Some(vec!["ops".to_string()]));
+        let set = rules(&[
+            ("app", rule(&["ops"], 1, RuleMode::Replace)),
+            ("app/prod/db", rule(&["alice"], 1, RuleMode::Replace)),
+        ]);
+        assert_eq!(chosen(&set, "app/prod/db"), Some(vec!["alice".to_string()]));
+        // equally specific patterns are ranked by their text, so the order is stable
+        let set = rules(&[
+            ("app/*", rule(&["ops"], 0, RuleMode::Replace)),
+            ("app/?", rule(&["alice"], 0, RuleMode::Replace)),
+        ]);
+        assert_eq!(chosen(&set, "app/x"), Some(vec!["ops".to_string()]));
+    }
+
+    #[test]
+    fn additive_rules_add_to_the_deciding_rule() {
+        let set = rules(&[
+            ("app", rule(&["ops"], 0, RuleMode::Replace)),
+            ("app/prod", rule(&["auditor"], 0, RuleMode::Add)),
+            ("**/*.pem", rule(&["pki"], 0, RuleMode::Add)),
+            ("other", rule(&["bob"], 0, RuleMode::Add)),
+        ]);
+        let (chosen, mut added) = names_for_path(&set, "app/prod/key.pem");
+        added.sort();
+        assert_eq!(chosen, Some(vec!["ops".to_string()]));
+        assert_eq!(added, vec!["auditor".to_string(), "pki".to_string()]);
+        // nothing is added by a rule ranked below the one deciding
+        let set = rules(&[
+            ("app/prod", rule(&["ops"], 0, RuleMode::Replace)),
+            ("app", rule(&["auditor"], 0, RuleMode::Add)),
+        ]);
+        let ranked = rank(&set, "app/prod/db");
+        assert!(ranked[0].effect == Effect::Chose);
+        assert!(ranked[1].effect == Effect::Overridden);
+        assert!(names_for_path(&set, "app/prod/db").1.is_empty());
+        // with no replacing rule the additions stand alone
+        let (chosen, added) = names_for_path(&set, "app/dev");
+        assert_eq!(chosen, None);
+        assert_eq!(added, vec!["auditor".to_string()]);
+    }
+}
@@ -1,12 +1,217 @@
+use super::audit;
+use super::author;
+use super::history;
+use super::logger;
+use super::paths;
+use super::policy;
+use super::public::PublicKey;
+use super::secure::SecretBytes;
+use super::spool;
 use super::state;
 use super::vault;
+use std::io;
+use std::io::Read;
+use std::io::Write;
 
-pub fn read_secret(st: &mut state::State, path: &str) -> Result<Vec<u8>, String> {
-    vault::Vault::unlock_vault(st, path)
+// what a write is recorded as in the changes of the store and the audit log
+struct Operation {
+    change: String,
+    event: audit::AuditEvent,
+    detail: Option<String>,
 }
 
-pub fn write_secret(st: &mut state::State, path: &str, payload: Vec<u8>) -> Result<(), String> {
+impl Operation {
+    fn encrypt() -> Operation {
+        Operation {
+            change: "encrypt".to_string(),
+            event: audit::AuditEvent::Encrypt,
+            detail: None,
+        }
+    }
+}
+
+// the contents, the key that unlocked them and their verified author
+fn unlock_secret(
+    st: &mut state::State,
+    path: &str,
+    rev: Option<u64>,
+) -> Result<(SecretBytes, String, Option<String>), String> {
+    paths::store_file(st, path)
+        .and_then(|store_file| match rev {
+            Some(rev) => history::revision_file(&store_file, rev),
+            None => Ok(store_file),
+        })
+        .and_then(|store_file| vault::Vault::unlock_vault_with_key(st, &store_file))
+        .and_then(|(contents, unlocked)| {
+            author::check_author(st, path, unlocked.signature.as_ref())
+                .map(|author| (contents, unlocked.key_name, author))
+        })
+}
+
+// also returns the verified author of the secret, if it is signed. An earlier revision is
+// read when rev is given, see agent::history.
+pub fn read_secret(
+    st: &mut state::State,
+    path: &str,
+    rev: Option<u64>,
+) -> Result<(SecretBytes, Option<String>), String> {
+    let detail = rev.map(|rev| format!("revision {}", rev));
+    match unlock_secret(st, path, rev) {
+        Ok((contents, key_name, author)) => {
+            // plaintext is only handed out once the access is on record
+            audit::record(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[key_name],
+                detail.as_deref(),
+                true,
+            )?;
+            Ok((contents, author))
+        }
+        Err(err) => {
+            audit::record_or_log(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[],
+                Some(&err),
+                false,
+            );
+            Err(err)
+        }
+    }
+}
+
+pub fn write_secret(st: &mut state::State, path: &str, payload: SecretBytes) -> Result<(), String> {
+    let mut source: &[u8] = &payload;
+    attach_secret(st, path, &mut source).map(|_| ())
+}
+
+// like write_secret for contents too large to hold in memory, returning their size
+pub fn attach_secret(
+    st: &mut state::State,
+    path: &str,
+    source: &mut dyn Read,
+) -> Result<u64, String> {
+    store_secret(st, path, source, Operation::encrypt())
+}
+
+fn store_secret(
+    st: &mut state::State,
+    path: &str,
+    source: &mut dyn Read,
+    operation: Operation,
+) -> Result<u64, String> {
+    let store_file = paths::store_file(st, path)?;
+    let signer = author::find_author(st, path)?;
     let chain = st.get_chain()?;
     let keys = chain.get_keys_for_path(path);
-    vault::Vault::write_vault(path, &payload, keys)
+    policy::check(chain, path, &keys)?;
+    let threshold = chain.get_threshold_for_path(path).map(|(_, t)| t.required);
+    let padding = chain.padding;
+    let key_names: Vec<String> = keys.iter().map(|k| k.get_key_name().to_string()).collect();
+    let author = signer.as_ref().map(|(name, key)| vault::Author {
+        name,
+        key: key.as_ref(),
+    });
+    let archived = history::archive(&store_file)?;
+    let res = vault::Vault::write_threshold_vault_stream(
+        &store_file,
+        source,
+        keys,
+        threshold,
+        padding,
+        author.as_ref(),
+    );
+    match (res.is_ok(), archived) {
+        (true, _) => {
+            if let Err(err) = history::prune(&store_file) {
+                logger::naming(
+                    logger::Level::Warn,
+                    &format!("Unable to prune history of {}: {}", path, err),
+                );
+            }
+        }
+        // the previous contents are still in place
+        (false, Some(archived)) => {
+            let _ = std::fs::remove_file(archived);
+        }
+        (false, None) => {}
+    }
+    let res = res.and_then(|size| paths::record_path(st, path).map(|_| size));
+    if res.is_ok() {
+        let author = signer.as_ref().map(|(name, _)| name.as_str());
+        st.record_change(&operation.change, Some(path), author);
+    }
+    // the file is already written, so a lost audit entry does not fail the attach
+    let detail = match &res {
+        Ok(_) => operation.detail.as_deref(),
+        Err(err) => Some(err.as_str()),
+    };
+    audit::record_or_log(operation.event, Some(path), &key_names, detail, res.is_ok());
+    res
+}
+
+// The contents are decrypted into a spool and only streamed to out once the author is
+// checked and the access is on record.
+pub fn extract_secret(
+    st: &mut state::State,
+    path: &str,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let mut contents = spool::Spool::new()?;
+    let res = paths::store_file(st, path)
+        .and_then(|store_file| vault::Vault::unlock_vault_to(st, &store_file, &mut contents))
+        .and_then(|unlocked| {
+            author::check_author(st, path, unlocked.signature.as_ref()).map(|_| unlocked.key_name)
+        });
+    let key_name = match res {
+        Ok(key_name) => key_name,
+        Err(err) => {
+            audit::record_or_log(
+                audit::AuditEvent::Decrypt,
+                Some(path),
+                &[],
+                Some(&err),
+                false,
+            );
+            return Err(err);
+        }
+    };
+    audit::record(
+        audit::AuditEvent::Decrypt,
+        Some(path),
+        &[key_name],
+        None,
+        true,
+    )?;
+    io::copy(&mut contents.reader()?, out)
+        .map(|_| ())
+        .map_err(|e| format!("Unable to send decrypted contents: {}", e))
+}
+
+pub fn list_history(st: &mut state::State, path: &str) -> Result<Vec<history::Revision>, String> {
+    let store_file = paths::store_file(st, path)?;
+    let revisions = history::get_revisions(&store_file)?;
+    if revisions.is_empty() && !store_file.file.exists() {
+        return Err(format!("Path '{}' doesn't exist in the store", path));
+    }
+    Ok(revisions)
+}
+
+// writes an earlier revision back as the current contents, which become a revision as well
+pub fn restore_secret(st: &mut state::State, path: &str, rev: u64) -> Result<(), String> {
+    let operation = Operation {
+        change: format!("restore revision {} of", rev),
+        event: audit::AuditEvent::Restore,
+        detail: Some(format!("revision {}", rev)),
+    };
+    let contents = match unlock_secret(st, path, Some(rev)) {
+        Ok((contents, _, _)) => contents,
+        Err(err) => {
+            audit::record_or_log(operation.event, Some(path), &[], Some(&err), false);
+            return Err(err);
+        }
+    };
+    let mut source: &[u8] = &contents;
+    store_secret(st, path, &mut source, operation).map(|_| ())
 }
@@ -0,0 +1,186 @@
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use sodiumoxide::utils;
+use std::io;
+use std::ops::Deref;
+use std::ops::DerefMut;
+
+// Heap buffer for secret material. The allocation is mlocked so it is not swapped out,
+// and it is zeroed (by sodium_munlock) when dropped.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+}
+
+impl SecretBytes {
+    pub fn new(mut bytes: Vec<u8>) -> SecretBytes {
+        if bytes.capacity() > 0 {
+            let _ = utils::mlock(allocation(&mut bytes));
+        }
+        SecretBytes { bytes }
+    }
+
+    pub fn with_capacity(capacity: usize) -> SecretBytes {
+        SecretBytes::new(Vec::with_capacity(capacity))
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> SecretBytes {
+        let mut secret = SecretBytes::with_capacity(bytes.len());
+        secret.bytes.extend_from_slice(bytes);
+        secret
+    }
+
+    // the capacity is fixed up front so pushes never move the secret to a new allocation
+    pub fn push(&mut self, byte: u8) -> Result<(), String> {
+        if self.bytes.len() == self.bytes.capacity() {
+            return Err("Secret buffer is full".to_string());
+        }
+        self.bytes.push(byte);
+        Ok(())
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.bytes.capacity() - self.bytes.len() < bytes.len() {
+            return Err("Secret buffer is full".to_string());
+        }
+        self.bytes.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl io::Write for SecretBytes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf).map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn allocation(bytes: &mut Vec<u8>) -> &mut [u8] {
+    let capacity = bytes.capacity();
+    unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr(), capacity) }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        if self.bytes.capacity() > 0 {
+            let region = allocation(&mut self.bytes);
+            if utils::munlock(region).is_err() {
+                utils::memzero(region);
+            }
+        }
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        SecretBytes::from_slice(&self.bytes)
+    }
+}
+
+impl Serialize for SecretBytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.bytes.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<u8>::deserialize(deserializer).map(SecretBytes::new)
+    }
+}
+
+// utf-8 secret such as a PIN or paper key, with the same guarantees as SecretBytes
+#[derive(Clone)]
+pub struct SecretString {
+    bytes: SecretBytes,
+}
+
+impl SecretString {
+    pub fn new(string: String) -> SecretString {
+        SecretString {
+            bytes: SecretBytes::new(string.into_bytes()),
+        }
+    }
+
+    pub fn from_bytes(bytes: SecretBytes) -> Result<SecretString, String> {
+        std::str::from_utf8(&bytes).map_err(|e| format!("Secret is not valid utf-8: {}", e))?;
+        Ok(SecretString { bytes })
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        unsafe { std::str::from_utf8_unchecked(&self.bytes) }
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(SecretString::new)
+    }
+}
+
+// keeps secrets out of core files and stops other processes of the same user from
+// attaching to the agent or reading its memory
+pub fn harden_process() -> Result<(), String> {
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) } != 0 {
+        return Err(format!(
+            "Unable to disable core dumps: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+            return Err(format!(
+                "Unable to mark agent as non-dumpable: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
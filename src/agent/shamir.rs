@@ -0,0 +1,133 @@
+// Shamir secret sharing over GF(2^8), byte by byte and without lookup tables so timing does
+// not depend on the shares.
+use super::secure::SecretBytes;
+use sodiumoxide::randombytes;
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= (b & 1).wrapping_neg() & a;
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+// a^254, the inverse of a for any a but 0
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+        exponent >>= 1;
+    }
+    result
+}
+
+// splits secret into count shares of which any threshold give it back
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<SecretBytes>, String> {
+    if threshold == 0 || threshold > count {
+        return Err(format!(
+            "Cannot split a secret into {} shares needing {} of them",
+            count, threshold
+        ));
+    }
+    let mut shares = Vec::with_capacity(count as usize);
+    for x in 1..=count {
+        let mut share = SecretBytes::with_capacity(secret.len() + 1);
+        share.push(x)?;
+        shares.push(share);
+    }
+    for byte in secret.iter() {
+        // the polynomial's constant term is the secret byte, the others are random
+        let mut coefficients = SecretBytes::new(vec![0; threshold as usize]);
+        let last = coefficients.len() - 1;
+        randombytes::randombytes_into(&mut coefficients[..last]);
+        coefficients[last] = *byte;
+        for share in shares.iter_mut() {
+            let x = share[0];
+            let y = coefficients.iter().fold(0, |acc, c| mul(acc, x) ^ c);
+            share.push(y)?;
+        }
+    }
+    Ok(shares)
+}
+
+// the secret the shares were split from, by Lagrange interpolation at 0. Too few shares give
+// a wrong secret rather than an error, it is up to the caller to tell.
+pub fn combine(shares: &[SecretBytes]) -> Result<SecretBytes, String> {
+    let first = shares.first().ok_or("No shares to combine".to_string())?;
+    let len = first.len();
+    for (i, share) in shares.iter().enumerate() {
+        if share.len() != len || len < 2 {
+            return Err("Shares of different secrets cannot be combined".to_string());
+        }
+        if share[0] == 0 || shares[..i].iter().any(|s| s[0] == share[0]) {
+            return Err("Invalid or repeated share".to_string());
+        }
+    }
+    let mut basis = Vec::with_capacity(shares.len());
+    for share in shares.iter() {
+        let xi = share[0];
+        let mut numerator = 1;
+        let mut denominator = 1;
+        for other in shares.iter().filter(|s| s[0] != xi) {
+            numerator = mul(numerator, other[0]);
+            denominator = mul(denominator, other[0] ^ xi);
+        }
+        basis.push(mul(numerator, inv(denominator)));
+    }
+    let mut secret = SecretBytes::with_capacity(len - 1);
+    for i in 1..len {
+        let byte = shares
+            .iter()
+            .zip(basis.iter())
+            .fold(0, |acc, (share, b)| acc ^ mul(share[i], *b));
+        secret.push(byte)?;
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret() -> Vec<u8> {
+        sodiumoxide::init().unwrap();
+        randombytes::randombytes(32)
+    }
+
+    #[test]
+    fn combines_exactly_threshold_shares() {
+        let secret = secret();
+        let shares = split(&secret, 3, 5).unwrap();
+        for picked in [[0, 1, 2], [0, 2, 4], [4, 3, 1]].iter() {
+            let subset: Vec<SecretBytes> = picked.iter().map(|i| shares[*i].clone()).collect();
+            assert_eq!(&combine(&subset).unwrap()[..], &secret[..]);
+        }
+    }
+
+    #[test]
+    fn fewer_shares_do_not_give_the_secret() {
+        let secret = secret();
+        let shares = split(&secret, 3, 5).unwrap();
+        let combined = combine(&shares[..2]).unwrap();
+        assert_eq!(combined.len(), secret.len());
+        assert_ne!(&combined[..], &secret[..]);
+    }
+
+    #[test]
+    fn rejects_repeated_and_zero_shares() {
+        let shares = split(&secret(), 2, 3).unwrap();
+        let repeated = vec![shares[0].clone(), shares[0].clone()];
+        assert!(combine(&repeated).is_err());
+        let mut zero = shares[1].clone();
+        zero[0] = 0;
+        assert!(combine(&[shares[0].clone(), zero]).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+// Decrypted contents too large for locked memory, kept in a temporary file encrypted under
+// a key that only lives in memory.
+use super::secure::SecretBytes;
+use sodiumoxide::crypto::secretstream;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct Spool {
+    file: BufWriter<File>,
+    key: secretstream::Key,
+    stream_header: secretstream::Header,
+    stream: secretstream::Stream<secretstream::Push>,
+    chunk: SecretBytes,
+    chunk_len: usize,
+}
+
+impl Spool {
+    pub fn new() -> Result<Spool, String> {
+        let file = tempfile::tempfile().map_err(|e| format!("Unable to create spool: {}", e))?;
+        let key = secretstream::gen_key();
+        let (stream, stream_header) = secretstream::Stream::init_push(&key)
+            .map_err(|_| "Unable to start spool encryption stream".to_string())?;
+        Ok(Spool {
+            file: BufWriter::new(file),
+            key,
+            stream_header,
+            stream,
+            chunk: SecretBytes::new(vec![0; CHUNK_SIZE]),
+            chunk_len: 0,
+        })
+    }
+
+    fn push(&mut self, tag: secretstream::Tag) -> io::Result<()> {
+        let encrypted = self
+            .stream
+            .push(&self.chunk[..self.chunk_len], None, tag)
+            .map_err(|_| io::Error::other("Unable to encrypt spool"))?;
+        self.file.write_all(&encrypted)?;
+        self.chunk_len = 0;
+        Ok(())
+    }
+
+    // reads back everything written so far
+    pub fn reader(mut self) -> Result<SpoolReader, String> {
+        let stream_header = self.stream_header;
+        let key = self.key.clone();
+        let res = self.push(secretstream::Tag::Final).and_then(|_| {
+            let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok(file)
+        });
+        let file = res.map_err(|e| format!("Unable to write spool: {}", e))?;
+        let stream = secretstream::Stream::init_pull(&stream_header, &key)
+            .map_err(|_| "Unable to start spool decryption stream".to_string())?;
+        Ok(SpoolReader {
+            file: BufReader::new(file),
+            stream,
+            chunk: SecretBytes::new(Vec::new()),
+            pos: 0,
+        })
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.chunk_len == CHUNK_SIZE {
+            self.push(secretstream::Tag::Message)?;
+        }
+        let n = std::cmp::min(buf.len(), CHUNK_SIZE - self.chunk_len);
+        self.chunk[self.chunk_len..self.chunk_len + n].copy_from_slice(&buf[..n]);
+        self.chunk_len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SpoolReader {
+    file: BufReader<File>,
+    stream: secretstream::Stream<secretstream::Pull>,
+    chunk: SecretBytes,
+    pos: usize,
+}
+
+impl Read for SpoolReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.stream.is_finalized() {
+                return Ok(0);
+            }
+            let mut encrypted = vec![0; CHUNK_SIZE + secretstream::ABYTES];
+            let mut filled = 0;
+            while filled < encrypted.len() {
+                let n = self.file.read(&mut encrypted[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            let (chunk, _) = self
+                .stream
+                .pull(&encrypted[..filled], None)
+                .map_err(|_| io::Error::other("Spool is corrupt"))?;
+            self.chunk = SecretBytes::new(chunk);
+            self.pos = 0;
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_was_written() {
+        sodiumoxide::init().unwrap();
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE - 7].iter() {
+            let contents: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            let mut spool = Spool::new().unwrap();
+            spool.write_all(&contents).unwrap();
+            let mut read = Vec::new();
+            spool.reader().unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, contents);
+        }
+    }
+}
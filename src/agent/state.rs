@@ -1,10 +1,16 @@
+use super::audit;
+use super::durable;
+use super::git;
+use super::index;
 use super::keychain;
 use super::passphrase;
 use super::private;
 use super::public;
 use super::public::PublicKey;
+use super::threshold;
 use sodiumoxide::crypto::pwhash;
 use std::collections::HashMap;
+use std::path::Path;
 
 pub struct KeyStore {
     pub unlocked: HashMap<String, Box<dyn private::PrivateKey>>,
@@ -25,9 +31,12 @@ impl KeyStore {
         self.session_unlocked = HashMap::new();
     }
 
-    pub fn try_unlock(&mut self, key_name: &str) -> Option<&dyn private::PrivateKey> {
+    pub fn try_unlock(
+        &mut self,
+        key_name: &str,
+    ) -> Result<Option<&dyn private::PrivateKey>, String> {
         if !self.locked.contains_key(key_name) {
-            return None;
+            return Ok(None);
         }
         let locked_key_result = self.locked.get_mut(key_name).unwrap().try_unlock(key_name);
         match locked_key_result {
@@ -36,52 +45,109 @@ impl KeyStore {
                     key_name.to_string(),
                     self.locked.get(key_name).unwrap().key.duplicate(),
                 );
-                return Some(self.locked.get(key_name).unwrap().key.as_ref());
+                return Ok(Some(self.locked.get(key_name).unwrap().key.as_ref()));
             }
             UnlockResult::Failure => {
-                return None;
+                return Ok(None);
             }
             UnlockResult::Lockout => {
                 self.locked.remove(key_name);
-                return None;
+                return Ok(None);
+            }
+            UnlockResult::Cancelled => {
+                return Err(format!("Unlocking key {} was cancelled", key_name));
+            }
+        }
+    }
+
+    // the first of candidates with a signing key that can be unlocked, preferring keys that
+    // are already unlocked before prompting for a PIN
+    pub fn find_signer(
+        &mut self,
+        candidates: &[public::PublicKeyWrapper],
+    ) -> Result<Option<private::Signer>, String> {
+        let candidates: Vec<&public::PublicKeyWrapper> = candidates
+            .iter()
+            .filter(|k| k.get_sign_key().is_some())
+            .collect();
+        for key in candidates.iter() {
+            let name = key.get_key_name();
+            let unlocked = self.unlocked.get(name).or(self.session_unlocked.get(name));
+            if let Some(unlocked) = unlocked {
+                return Ok(Some((name.to_string(), unlocked.duplicate())));
+            }
+        }
+        for key in candidates.iter() {
+            let name = key.get_key_name();
+            if let Some(unlocked) = self.try_unlock(name)? {
+                return Ok(Some((name.to_string(), unlocked.duplicate())));
+            }
+        }
+        for key in candidates.iter() {
+            if let Some(unlocked) = self.try_load_key(key)? {
+                return Ok(Some((key.get_key_name().to_string(), unlocked.duplicate())));
             }
         }
+        Ok(None)
+    }
+
+    // the private key of pub_key, unlocking or loading it from disk when it has to be
+    pub fn get_private_key(
+        &mut self,
+        pub_key: &public::PublicKeyWrapper,
+    ) -> Result<Option<&dyn private::PrivateKey>, String> {
+        let name = pub_key.get_key_name();
+        if self.unlocked.contains_key(name) {
+            return Ok(self.unlocked.get(name).map(|k| k.as_ref()));
+        }
+        if self.session_unlocked.contains_key(name) {
+            return Ok(self.session_unlocked.get(name).map(|k| k.as_ref()));
+        }
+        if self.locked.contains_key(name) {
+            return self.try_unlock(name);
+        }
+        match pub_key {
+            public::PublicKeyWrapper::Sodium(_) => self.try_load_key(pub_key),
+            _ => Ok(None),
+        }
     }
 
     pub fn try_load_key(
         &mut self,
         pub_key: &public::PublicKeyWrapper,
-    ) -> Option<&dyn private::PrivateKey> {
+    ) -> Result<Option<&dyn private::PrivateKey>, String> {
         match pub_key {
             public::PublicKeyWrapper::Sodium(k) => {
                 let key_name = pub_key.get_key_name();
                 let dev_key = private::DeviceKey::read_key(key_name);
                 if dev_key.is_err() {
-                    return None;
+                    return Ok(None);
                 }
                 let dev_key = dev_key.unwrap();
 
                 match dev_key {
                     private::DeviceKey::Unencrypted(pkey) => {
+                        audit::record_or_log(
+                            audit::AuditEvent::KeyUnlock,
+                            None,
+                            &[key_name.to_string()],
+                            Some("unencrypted device key"),
+                            true,
+                        );
                         self.session_unlocked
                             .insert(key_name.to_string(), Box::new(pkey));
-                        return self.session_unlocked.get(key_name).map(|v| v.as_ref());
+                        return Ok(self.session_unlocked.get(key_name).map(|v| v.as_ref()));
                     }
-                    private::DeviceKey::Encrypted(pkey) => loop {
-                        let pin = passphrase::get_pin(key_name);
-                        if pin.is_err() {
-                            return None;
-                        }
-                        let pin = pin.unwrap();
-                        let dec_key = pkey.decrypt_key(pin.as_bytes());
-                        if dec_key.is_err() {
-                            continue;
+                    private::DeviceKey::Encrypted(pkey) => {
+                        let dec_key = pkey.unlock(key_name)?;
+                        if dec_key.is_none() {
+                            return Ok(None);
                         }
-                        let dec_key = dec_key.unwrap();
+                        let (dec_key, _) = dec_key.unwrap();
                         self.session_unlocked
                             .insert(key_name.to_string(), Box::new(dec_key));
-                        return self.session_unlocked.get(key_name).map(|v| v.as_ref());
-                    },
+                        return Ok(self.session_unlocked.get(key_name).map(|v| v.as_ref()));
+                    }
                 }
             }
             public::PublicKeyWrapper::PaperKey(_) => {
@@ -105,6 +171,7 @@ pub enum UnlockResult {
     Success,
     Failure,
     Lockout,
+    Cancelled,
 }
 
 impl UnlockResult {
@@ -132,9 +199,13 @@ impl UnlockResult {
 
 impl LockedKey {
     pub fn try_unlock(&mut self, key_name: &str) -> UnlockResult {
+        let mut error = None;
         while self.num_tries < self.max_tries {
-            let pin = passphrase::get_pin(key_name);
+            let pin = passphrase::get_pin(key_name, error.as_deref());
             if pin.is_err() {
+                if pin.err().unwrap().is_cancelled() {
+                    return UnlockResult::Cancelled;
+                }
                 return UnlockResult::Failure;
             }
             let pin = pin.unwrap();
@@ -143,6 +214,9 @@ impl LockedKey {
                 return UnlockResult::Success;
             }
             self.num_tries += 1;
+            error = Some(passphrase::wrong_pin_message(
+                self.max_tries - self.num_tries,
+            ));
         }
         UnlockResult::Lockout
     }
@@ -151,26 +225,90 @@ impl LockedKey {
 pub struct State {
     pub keys: KeyStore,
     chain: Option<keychain::KeyChain>,
+    index: Option<index::PathIndex>,
+    index_loaded: bool,
+    // the keychain and index files as they were loaded, to reload them once another
+    // process has rewritten them
+    chain_stamp: Option<durable::FileStamp>,
+    index_stamp: Option<durable::FileStamp>,
+    // what the current command wrote, committed once it is done, see agent::git
+    changes: Vec<git::Change>,
+    // shares of threshold vaults other holders supplied for the current command
+    pub shares: Vec<threshold::ShareFile>,
 }
 
 impl State {
     pub fn new() -> State {
         State {
             chain: None,
+            index: None,
+            index_loaded: false,
             keys: KeyStore::new(),
+            chain_stamp: None,
+            index_stamp: None,
+            changes: Vec::new(),
+            shares: Vec::new(),
         }
     }
 
     pub fn get_chain(&mut self) -> Result<&mut keychain::KeyChain, String> {
-        if self.chain.is_some() {
-            Ok(self.chain.as_mut().unwrap())
-        } else {
+        let stamp = chain_stamp();
+        if self.chain.is_none() || stamp != self.chain_stamp {
+            self.chain = None;
             self.chain = Some(keychain::KeyChain::read_chain(self)?);
-            Ok(self.chain.as_mut().unwrap())
+            self.chain_stamp = stamp;
         }
+        Ok(self.chain.as_mut().unwrap())
+    }
+
+    pub fn discard_chain(&mut self) {
+        self.chain = None;
+    }
+
+    // keeps the agent's own write from looking like someone else's
+    pub fn mark_chain_written(&mut self) {
+        self.chain_stamp = chain_stamp();
+    }
+
+    pub fn record_change(&mut self, operation: &str, path: Option<&str>, author: Option<&str>) {
+        self.changes.push(git::Change::new(operation, path, author));
+    }
+
+    pub fn take_changes(&mut self) -> Vec<git::Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    // None unless the store hides its path names
+    pub fn get_index(&mut self) -> Result<Option<&mut index::PathIndex>, String> {
+        let stamp = index_stamp();
+        if !self.index_loaded || stamp != self.index_stamp {
+            self.index = index::PathIndex::read_index(self)?;
+            self.index_loaded = true;
+            self.index_stamp = stamp;
+        }
+        Ok(self.index.as_mut())
+    }
+
+    // for an index that was just written
+    pub fn set_index(&mut self, index: index::PathIndex) {
+        self.index = Some(index);
+        self.index_loaded = true;
+        self.mark_index_written();
+    }
+
+    pub fn mark_index_written(&mut self) {
+        self.index_stamp = index_stamp();
     }
 
     pub fn reset_session_keys(&mut self) {
         self.keys.reset_session();
     }
 }
+
+fn chain_stamp() -> Option<durable::FileStamp> {
+    durable::file_stamp(Path::new(&keychain::KeyChain::get_keychain_path()))
+}
+
+fn index_stamp() -> Option<durable::FileStamp> {
+    durable::file_stamp(&index::PathIndex::store_file().file)
+}
@@ -0,0 +1,214 @@
+// Threshold vaults, whose key is split into Shamir shares of which a number of holders
+// together open the vault. Shares are only combined in memory.
+use super::audit;
+use super::keychain;
+use super::paths;
+use super::policy;
+use super::public::PublicKey;
+use super::state;
+use super::vault;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Threshold {
+    // the number of shares that open a vault
+    pub required: u8,
+    pub keys: Vec<String>,
+}
+
+impl Threshold {
+    pub fn describe(&self) -> String {
+        format!("{} of {}", self.required, self.keys.join(", "))
+    }
+}
+
+// a share of one revision of a vault, sealed for the key that is to open it
+#[derive(Serialize, Deserialize)]
+pub struct ShareFile {
+    pub path: String,
+    // the revision of the vault, told apart by the stream header drawn anew for each
+    pub nonce: Vec<u8>,
+    pub holder: String,
+    pub recipient: String,
+    pub sealed: Vec<u8>,
+}
+
+// no keys remove the rule, returns the vaults encrypted again and those this machine does
+// not hold enough shares of
+pub fn set(
+    st: &mut state::State,
+    path: &str,
+    required: u8,
+    keys: Vec<String>,
+) -> Result<(usize, Vec<String>), String> {
+    let path = vault::store_path(path);
+    paths::check_rule_names(st, &keys)?;
+    let chain = st.get_chain()?;
+    let holders = chain.key_names_to_keys(&keys).len();
+    if !keys.is_empty() && (required < 2 || required as usize > holders) {
+        return Err(format!(
+            "A threshold takes between 2 and the {} keys named, not {}",
+            holders, required
+        ));
+    }
+    if holders > u8::MAX as usize {
+        return Err("A threshold takes at most 255 keys".to_string());
+    }
+    let before = paths::recipients_by_path(st)?;
+    let chain = st.get_chain()?;
+    let previous = if keys.is_empty() {
+        chain.thresholds.remove(&path)
+    } else {
+        let rule = Threshold {
+            required,
+            keys: keys.clone(),
+        };
+        chain.thresholds.insert(path.clone(), rule)
+    };
+    if let Err(err) = policy::check_store(st) {
+        let chain = st.get_chain()?;
+        match previous {
+            Some(previous) => chain.thresholds.insert(path.clone(), previous),
+            None => chain.thresholds.remove(&path),
+        };
+        return Err(err);
+    }
+    st.record_change("change threshold of", Some(&path), None);
+    keychain::KeyChain::write_chain(st)?;
+    audit::record_or_log(
+        audit::AuditEvent::PolicyChange,
+        Some(&path),
+        &keys,
+        Some(&format!("threshold of {} changed", required)),
+        true,
+    );
+    paths::reencrypt_changed(st, before)
+}
+
+// every threshold rule by its path
+pub fn show(st: &mut state::State) -> Result<Vec<(String, Threshold)>, String> {
+    let mut thresholds: Vec<(String, Threshold)> = st
+        .get_chain()?
+        .thresholds
+        .iter()
+        .map(|(path, rule)| (path.clone(), rule.clone()))
+        .collect();
+    thresholds.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(thresholds)
+}
+
+// the share of the vault at path held by a key of this machine, sealed for recipient
+pub fn share(st: &mut state::State, path: &str, recipient: &str) -> Result<ShareFile, String> {
+    let path = vault::store_path(path);
+    let sealed_for = st
+        .get_chain()?
+        .key_names_to_keys(&[recipient.to_string()])
+        .pop()
+        .ok_or(format!("Key {} is not in the keychain", recipient))?;
+    let store_file = paths::store_file(st, &path)?;
+    let res = vault::Vault::open_own_share(st, &store_file);
+    let detail = format!("share sealed for {}", recipient);
+    let (share, holder, nonce) = match res {
+        Ok(opened) => opened,
+        Err(err) => {
+            audit::record_or_log(
+                audit::AuditEvent::Decrypt,
+                Some(&path),
+                &[],
+                Some(&err),
+                false,
+            );
+            return Err(err);
+        }
+    };
+    // the share is only handed out once it is on record
+    audit::record(
+        audit::AuditEvent::Decrypt,
+        Some(&path),
+        std::slice::from_ref(&holder),
+        Some(&detail),
+        true,
+    )?;
+    Ok(ShareFile {
+        path,
+        nonce,
+        holder,
+        recipient: recipient.to_string(),
+        sealed: sealed_for.encrypt(&share),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::merge;
+    use crate::agent::migrate;
+    use crate::agent::private::SodiumPrivateKey;
+    use crate::agent::secret;
+    use crate::agent::secure::SecretBytes;
+    use crate::config;
+
+    // a store whose vaults below glass take both of its keys, which are unlocked
+    fn threshold_store() -> state::State {
+        let mut st = state::State::new();
+        std::fs::create_dir_all(config::get_store_directory().join("glass")).unwrap();
+        for name in ["alice", "bob"].iter() {
+            let key = SodiumPrivateKey::gen_key();
+            st.get_chain().unwrap().add_key(key.get_public_key(name));
+            st.keys.unlocked.insert(name.to_string(), Box::new(key));
+        }
+        st.get_chain().unwrap().thresholds.insert(
+            "glass".to_string(),
+            Threshold {
+                required: 2,
+                keys: vec!["alice".to_string(), "bob".to_string()],
+            },
+        );
+        keychain::KeyChain::write_chain(&mut st).unwrap();
+        let contents = SecretBytes::from_slice(b"break glass");
+        secret::write_secret(&mut st, "glass/root", contents).unwrap();
+        st
+    }
+
+    fn threshold_of(store_file: &vault::StoreFile) -> Option<u8> {
+        vault::Vault::read_vault(store_file)
+            .unwrap()
+            .get_threshold()
+    }
+
+    #[test]
+    fn threshold_vaults_stay_threshold_when_migrated() {
+        sodiumoxide::init().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        config::with_home(home.path(), || {
+            let mut st = threshold_store();
+            let migrated = migrate::migrate_store(&mut st, false, true).unwrap();
+            assert!(migrated.contains(&"glass/root".to_string()));
+            let store_file = paths::store_file(&mut st, "glass/root").unwrap();
+            assert!(!store_file.file.ends_with("glass/root"));
+            assert_eq!(threshold_of(&store_file), Some(2));
+            let (contents, _) = secret::read_secret(&mut st, "glass/root", None).unwrap();
+            assert_eq!(&contents[..], b"break glass");
+        });
+    }
+
+    #[test]
+    fn threshold_vaults_stay_threshold_when_merged() {
+        sodiumoxide::init().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        config::with_home(home.path(), || {
+            let mut st = threshold_store();
+            let store_file = paths::store_file(&mut st, "glass/root").unwrap();
+            let ours = home.path().join("ours");
+            let theirs = home.path().join("theirs");
+            std::fs::copy(&store_file.file, &ours).unwrap();
+            std::fs::copy(&store_file.file, &theirs).unwrap();
+            let conflicts =
+                merge::merge_files(&mut st, &store_file.file, &ours, &theirs, "glass/root")
+                    .unwrap();
+            assert!(conflicts.is_empty());
+            assert_eq!(threshold_of(&store_file.at(ours)), Some(2));
+        });
+    }
+}
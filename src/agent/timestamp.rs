@@ -0,0 +1,119 @@
+// Millisecond unix timestamps and their UTC ISO 8601 form, after
+// http://howardhinnant.github.io/date_algorithms.html
+use std::time;
+
+pub fn now_millis() -> u128 {
+    let now = time::SystemTime::now();
+    let elapsed = now
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or(time::Duration::from_secs(0));
+    elapsed.as_millis()
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// 2026-10-18T21:51:30.135Z
+pub fn format(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs_of_day = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        millis % 1000
+    )
+}
+
+// accepts unix seconds, YYYY-MM-DD or YYYY-MM-DDTHH:MM[:SS][Z], always read as UTC
+pub fn parse(input: &str) -> Result<u128, String> {
+    let input = input.trim().trim_end_matches('Z');
+    let invalid = || format!("Unable to parse time '{}'", input);
+    if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) {
+        let secs = str::parse::<u128>(input).map_err(|_| invalid())?;
+        return Ok(secs * 1000);
+    }
+
+    let mut date_time = input.splitn(2, ['T', ' ']);
+    let date: Vec<&str> = date_time.next().unwrap_or("").split('-').collect();
+    if date.len() != 3 {
+        return Err(invalid());
+    }
+    let mut fields = Vec::new();
+    for part in date.iter() {
+        fields.push(str::parse::<i64>(part).map_err(|_| invalid())?);
+    }
+    let time: Vec<&str> = match date_time.next() {
+        Some(time) => time.split(':').collect(),
+        None => Vec::new(),
+    };
+    if time.len() == 1 || time.len() > 3 {
+        return Err(invalid());
+    }
+    for part in time.iter() {
+        fields.push(str::parse::<i64>(part).map_err(|_| invalid())?);
+    }
+    fields.resize(6, 0);
+
+    let (month, day, hour, minute, second) =
+        (fields[1], fields[2], fields[3], fields[4], fields[5]);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid());
+    }
+    let days = days_from_civil(fields[0], month, day);
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    if secs < 0 {
+        return Err(invalid());
+    }
+    Ok(secs as u128 * 1000)
+}
+
+// a length of time such as 90s, 45m, 12h, 30d or 2w, in milliseconds
+pub fn parse_duration(input: &str) -> Result<u128, String> {
+    let input = input.trim();
+    let invalid = || format!("Unable to parse duration '{}'", input);
+    let unit_start = input
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = input.split_at(unit_start);
+    let count = str::parse::<u128>(count).map_err(|_| invalid())?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(invalid()),
+    };
+    Ok(count * unit_secs * 1000)
+}
@@ -0,0 +1,279 @@
+// Removed secrets, kept encrypted with their history under .trash until purged.
+use super::audit;
+use super::durable;
+use super::history;
+use super::paths;
+use super::state;
+use super::timestamp;
+use super::vault;
+use crate::config;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+const TRASH_DIR_NAME: &str = ".trash";
+const OVERWRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrashEntry {
+    pub path: String,
+    // when it was removed, in milliseconds since the epoch
+    pub removed: u128,
+}
+
+// a removed vault and where it is kept relative to its removal directory
+struct Trashed {
+    entry: TrashEntry,
+    relative: PathBuf,
+}
+
+fn trash_dir() -> PathBuf {
+    config::get_store_directory().join(TRASH_DIR_NAME)
+}
+
+fn removal_dir(removed: u128) -> PathBuf {
+    trash_dir().join(removed.to_string())
+}
+
+fn walk_removal(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read trash: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read trash: {}", e))?;
+        let name = entry.file_name();
+        // the revisions travel along, see history::move_revisions_between
+        if relative.as_os_str().is_empty() && name == history::HISTORY_DIR_NAME {
+            continue;
+        }
+        let full_path = entry.path();
+        if full_path.is_dir() {
+            walk_removal(&full_path, &relative.join(&name), files)?;
+        } else if full_path.is_file() {
+            files.push(relative.join(&name));
+        }
+    }
+    Ok(())
+}
+
+// every removed vault, oldest first. A store that hides its names only knows the paths of
+// its trash through the index.
+fn list_trashed(st: &mut state::State) -> Result<Vec<Trashed>, String> {
+    let dir = trash_dir();
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let hidden_names: Option<HashMap<String, String>> = st.get_index()?.map(|index| {
+        index
+            .trashed_paths()
+            .iter()
+            .map(|path| (index.file_name(path), path.clone()))
+            .collect()
+    });
+    let entries = dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read trash: {}", e))?;
+    let mut trashed = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read trash: {}", e))?;
+        let removed = match entry.file_name().to_string_lossy().parse::<u128>() {
+            Ok(removed) if entry.path().is_dir() => removed,
+            _ => continue,
+        };
+        let mut files = Vec::new();
+        walk_removal(&entry.path(), Path::new(""), &mut files)?;
+        for relative in files {
+            let name = relative.to_string_lossy().to_string();
+            let path = match hidden_names.as_ref() {
+                Some(names) => names.get(&name).cloned().unwrap_or(name),
+                None => name,
+            };
+            trashed.push(Trashed {
+                entry: TrashEntry { path, removed },
+                relative,
+            });
+        }
+    }
+    trashed.sort_by(|a, b| (a.entry.removed, &a.entry.path).cmp(&(b.entry.removed, &b.entry.path)));
+    Ok(trashed)
+}
+
+pub fn list(st: &mut state::State) -> Result<Vec<TrashEntry>, String> {
+    Ok(list_trashed(st)?.into_iter().map(|t| t.entry).collect())
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to.parent().unwrap())
+        .map_err(|e| format!("Unable to create directory: {}", e))?;
+    fs::rename(from, to).map_err(|e| format!("Unable to move vault: {}", e))?;
+    durable::sync_parent(to)?;
+    durable::sync_parent(from)
+}
+
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = dir.read_dir() {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                remove_empty_dirs(&path);
+                let _ = fs::remove_dir(&path);
+            }
+        }
+    }
+}
+
+fn record(
+    event: audit::AuditEvent,
+    path: Option<&str>,
+    detail: Option<&str>,
+    res: &Result<(), String>,
+) {
+    let detail = res.as_ref().err().map(|e| e.as_str()).or(detail);
+    audit::record_or_log(event, path, &[], detail, res.is_ok());
+}
+
+pub fn remove_secret(st: &mut state::State, path: &str) -> Result<(), String> {
+    let res = move_to_trash(st, path);
+    record(audit::AuditEvent::Remove, Some(path), None, &res);
+    res
+}
+
+fn move_to_trash(st: &mut state::State, path: &str) -> Result<(), String> {
+    let store_file = paths::store_file(st, path)?;
+    if !store_file.file.is_file() {
+        return Err(format!("Path '{}' doesn't exist in the store", path));
+    }
+    let root = removal_dir(timestamp::now_millis());
+    rename(&store_file.file, &root.join(store_file.relative_file()?))?;
+    history::move_revisions_between(&store_file, &config::get_store_directory(), &root)?;
+    st.record_change("remove", Some(path), None);
+    if let Some(index) = st.get_index()? {
+        index.remove(path);
+        index.set_trashed(path, true);
+        paths::write_index(st)?;
+    }
+    Ok(())
+}
+
+// brings back the most recently removed vault of path
+pub fn restore_secret(st: &mut state::State, path: &str) -> Result<(), String> {
+    let res = restore_from_trash(st, path);
+    record(audit::AuditEvent::Restore, Some(path), None, &res);
+    res
+}
+
+fn restore_from_trash(st: &mut state::State, path: &str) -> Result<(), String> {
+    let path = vault::store_path(path);
+    let mut trashed: Vec<Trashed> = list_trashed(st)?
+        .into_iter()
+        .filter(|t| t.entry.path == path)
+        .collect();
+    let newest = trashed
+        .pop()
+        .ok_or(format!("Path '{}' is not in the trash", path))?;
+    let store_file = paths::store_file(st, &path)?;
+    if store_file.file.exists() {
+        return Err(format!(
+            "Path '{}' exists in the store, remove it before restoring it",
+            path
+        ));
+    }
+    if store_file.relative_file()? != newest.relative {
+        return Err(format!(
+            "Path '{}' was removed before the store changed how it names vaults",
+            path
+        ));
+    }
+    let root = removal_dir(newest.entry.removed);
+    rename(&root.join(&newest.relative), &store_file.file)?;
+    history::move_revisions_between(&store_file, &root, &config::get_store_directory())?;
+    remove_empty_dirs(&root);
+    let _ = fs::remove_dir(&root);
+    st.record_change("restore", Some(&path), None);
+    if let Some(index) = st.get_index()? {
+        index.insert(&path);
+        index.set_trashed(&path, !trashed.is_empty());
+        paths::write_index(st)?;
+    }
+    Ok(())
+}
+
+// zeroes a file on disk before unlinking it, unless another link still needs its contents
+fn overwrite_and_remove(file: &Path) -> Result<(), String> {
+    let metadata = fs::metadata(file).map_err(|e| format!("filesystem error: {}", e))?;
+    if metadata.nlink() == 1 {
+        let mut handle = OpenOptions::new()
+            .write(true)
+            .open(file)
+            .map_err(|e| format!("filesystem error: {}", e))?;
+        let zeros = vec![0u8; OVERWRITE_CHUNK_SIZE];
+        let mut left = metadata.len();
+        while left > 0 {
+            let chunk = std::cmp::min(left, OVERWRITE_CHUNK_SIZE as u64) as usize;
+            handle
+                .write_all(&zeros[..chunk])
+                .map_err(|e| format!("Unable to overwrite {}: {}", file.display(), e))?;
+            left -= chunk as u64;
+        }
+        handle
+            .sync_all()
+            .map_err(|e| format!("Unable to overwrite {}: {}", file.display(), e))?;
+    }
+    fs::remove_file(file).map_err(|e| format!("Unable to remove {}: {}", file.display(), e))
+}
+
+fn purge_dir(dir: &Path) -> Result<(), String> {
+    let entries = dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read trash: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read trash: {}", e))?;
+        let path = entry.path();
+        if path.is_dir() {
+            purge_dir(&path)?;
+        } else {
+            overwrite_and_remove(&path)?;
+        }
+    }
+    fs::remove_dir(dir).map_err(|e| format!("Unable to remove {}: {}", dir.display(), e))
+}
+
+// removes for good what was removed at least older_than milliseconds ago, or everything
+pub fn purge(st: &mut state::State, older_than: Option<u128>) -> Result<Vec<TrashEntry>, String> {
+    let res = purge_trash(st, older_than);
+    let detail = res
+        .as_ref()
+        .ok()
+        .map(|purged| format!("{} vaults purged", purged.len()));
+    let status = res.as_ref().map(|_| ()).map_err(|e| e.clone());
+    record(audit::AuditEvent::Purge, None, detail.as_deref(), &status);
+    res
+}
+
+fn purge_trash(st: &mut state::State, older_than: Option<u128>) -> Result<Vec<TrashEntry>, String> {
+    let cutoff = older_than.map(|older_than| timestamp::now_millis().saturating_sub(older_than));
+    let (purged, kept): (Vec<Trashed>, Vec<Trashed>) = list_trashed(st)?
+        .into_iter()
+        .partition(|t| cutoff.is_none_or(|cutoff| t.entry.removed <= cutoff));
+    let mut removals: Vec<u128> = purged.iter().map(|t| t.entry.removed).collect();
+    removals.dedup();
+    for removed in removals {
+        purge_dir(&removal_dir(removed))?;
+    }
+    st.record_change("purge trash", None, None);
+    if let Some(index) = st.get_index()? {
+        for trashed in purged.iter() {
+            if !kept.iter().any(|t| t.entry.path == trashed.entry.path) {
+                index.set_trashed(&trashed.entry.path, false);
+            }
+        }
+        paths::write_index(st)?;
+    }
+    Ok(purged.into_iter().map(|t| t.entry).collect())
+}
@@ -1,16 +1,181 @@
+use super::durable;
+use super::logger;
 use super::private;
 use super::public;
 use super::public::PublicKey;
+use super::secure::SecretBytes;
+use super::shamir;
 use super::state;
+use super::timestamp;
 use crate::config;
+use crate::constants;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json;
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::generichash;
 use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::secretstream;
+use sodiumoxide::crypto::sign;
 use std::cmp::Ordering;
-use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
 
-#[derive(Serialize, Deserialize)]
+// Layout: magic "BSLT" | format (u16 LE) | cipher | key wrap | padding (format 4 on), then
+// from format 3 the body length (u32 LE), a JSON body and the payload as secretstream chunks.
+// Padding is 0x80 and zeros. From format 5 an author's signature over the BLAKE2b hash of
+// the rest follows the final chunk. Bare JSON vaults from before the header are format 0/1.
+const VAULT_MAGIC: &[u8] = b"BSLT";
+const HEADER_LEN: usize = 8;
+const PADDED_HEADER_LEN: usize = 9;
+pub const FORMAT_VERSION: u16 = 5;
+const VAULT_AD_CONTEXT: &[u8] = b"basalt-vault";
+const VAULT_SIGNATURE_CONTEXT: &[u8] = b"basalt-vault-author";
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_BODY_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cipher {
+    SecretBox = 1,
+    XChaCha20Poly1305 = 2,
+    SecretStream = 3,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyWrap {
+    SealedBox = 1,
+    Threshold = 2,
+}
+
+// PADMÉ leaks at most O(log log n) bits of the length for under 12% overhead, bucket pads
+// to a multiple of the bucket size
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Padding {
+    None = 0,
+    Padme = 1,
+    Bucket = 2,
+}
+
+// how the vaults of a store are padded, kept in the keychain so everyone writing to the
+// store pads alike
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PaddingRule {
+    pub mode: Padding,
+    // bytes the padded length is a multiple of with bucket padding
+    pub bucket_size: u64,
+}
+
+impl Default for PaddingRule {
+    fn default() -> Self {
+        PaddingRule {
+            mode: Padding::None,
+            bucket_size: constants::DEFAULT_PADDING_BUCKET_SIZE,
+        }
+    }
+}
+
+impl PaddingRule {
+    pub fn is_default(&self) -> bool {
+        *self == PaddingRule::default()
+    }
+
+    pub fn describe(&self) -> String {
+        match self.mode {
+            Padding::None => "none".to_string(),
+            Padding::Padme => "padme".to_string(),
+            Padding::Bucket => format!("bucket of {} bytes", self.bucket_size),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    format_version: u16,
+    cipher: Cipher,
+    key_wrap: KeyWrap,
+    padding: Padding,
+}
+
+impl Header {
+    fn current(padding: Padding) -> Header {
+        Header {
+            format_version: FORMAT_VERSION,
+            cipher: Cipher::SecretStream,
+            key_wrap: KeyWrap::SealedBox,
+            padding,
+        }
+    }
+
+    fn len(format_version: u16) -> usize {
+        if format_version >= 4 {
+            PADDED_HEADER_LEN
+        } else {
+            HEADER_LEN
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Header::len(self.format_version));
+        bytes.extend_from_slice(VAULT_MAGIC);
+        bytes.extend_from_slice(&self.format_version.to_le_bytes());
+        bytes.push(self.cipher as u8);
+        bytes.push(self.key_wrap as u8);
+        if self.format_version >= 4 {
+            bytes.push(self.padding as u8);
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Header, String> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != VAULT_MAGIC {
+            return Err("Missing vault header".to_string());
+        }
+        let format_version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if format_version > FORMAT_VERSION {
+            return Err(format!(
+                "Vault format version {} is newer than this version of {} supports",
+                format_version,
+                constants::APP_NAME
+            ));
+        }
+        let cipher = match bytes[6] {
+            1 => Cipher::SecretBox,
+            2 => Cipher::XChaCha20Poly1305,
+            3 => Cipher::SecretStream,
+            id => return Err(format!("Unknown vault cipher id: {}", id)),
+        };
+        let key_wrap = match bytes[7] {
+            1 => KeyWrap::SealedBox,
+            2 => KeyWrap::Threshold,
+            id => return Err(format!("Unknown vault key wrap id: {}", id)),
+        };
+        if bytes.len() < Header::len(format_version) {
+            return Err("Vault header is truncated".to_string());
+        }
+        let padding = match bytes.get(8) {
+            _ if format_version < 4 => Padding::None,
+            Some(0) => Padding::None,
+            Some(1) => Padding::Padme,
+            Some(2) => Padding::Bucket,
+            id => return Err(format!("Unknown vault padding id: {}", id.unwrap())),
+        };
+        Ok(Header {
+            format_version,
+            cipher,
+            key_wrap,
+            padding,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Recipient {
     pub_key: public::PublicKeyWrapper,
     payload: Vec<u8>,
@@ -32,129 +197,1117 @@ fn sort_recipient(rec1: &Recipient, rec2: &Recipient) -> Ordering {
 }
 
 #[derive(Serialize, Deserialize)]
+struct VaultBody {
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    payload: Vec<u8>,
+    nonce: Vec<u8>,
+    recipients: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    // when it was written, in milliseconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u128>,
+    // the number of shares that open a vault with the threshold key wrap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    threshold: Option<u8>,
+}
+
+// the key a vault is written by, see write_vault_stream
+pub struct Author<'a> {
+    pub name: &'a str,
+    pub key: &'a dyn private::PrivateKey,
+}
+
+// the author a vault names and its signature, which is only known to be valid once it is
+// checked against the author's key in the keychain
+pub struct Signature {
+    pub author: String,
+    digest: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    pub fn verify(&self, author_key: &public::PublicKeyWrapper) -> bool {
+        author_key.get_key_name() == self.author
+            && author_key.verify(&signed_message(&self.digest), &self.signature)
+    }
+}
+
+// what opened a vault and who signed it
+pub struct Unlocked {
+    pub key_name: String,
+    pub signature: Option<Signature>,
+}
+
+// bare JSON vaults written before the header was introduced
+#[derive(Deserialize)]
+struct LegacyVault {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    path: Option<String>,
+    payload: Vec<u8>,
+    nonce: Vec<u8>,
+    recipients: Vec<Recipient>,
+}
+
 pub struct Vault {
+    header: Header,
+    path: Option<String>,
+    // formats before 3 only, later formats read the payload from `stream`
     payload: Vec<u8>,
-    nonce: secretbox::Nonce,
+    // the secretstream header for format 3
+    nonce: Vec<u8>,
     recipients: Vec<Recipient>,
+    author: Option<String>,
+    timestamp: Option<u128>,
+    threshold: Option<u8>,
+    stream: Option<BufReader<File>>,
+    stream_len: u64,
+    // hash of the header and body, the chunks are added as they are read
+    hasher: Option<generichash::State>,
+}
+
+// the path of a vault relative to the store with redundant separators and dots removed,
+// so the same vault always authenticates the same path however it was addressed
+pub fn store_path(path: &str) -> String {
+    let store_dir = config::get_store_directory();
+    let path = Path::new(path);
+    let relative = path.strip_prefix(&store_dir).unwrap_or(path);
+    let parts: Vec<String> = relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+// where a vault lives: the logical store path it is bound to and the file holding it, which
+// differ when the store hides its path names (see agent::index)
+pub struct StoreFile {
+    pub path: String,
+    pub file: PathBuf,
+    hide_path: bool,
+}
+
+impl StoreFile {
+    pub fn plain(path: &str) -> StoreFile {
+        StoreFile {
+            path: store_path(path),
+            file: config::get_store_directory().join(path),
+            hide_path: false,
+        }
+    }
+
+    pub fn copy(path: &str, file: PathBuf) -> StoreFile {
+        StoreFile {
+            path: store_path(path),
+            file,
+            hide_path: false,
+        }
+    }
+
+    pub fn hidden(path: &str, file_name: &str) -> StoreFile {
+        StoreFile {
+            path: store_path(path),
+            file: config::get_store_directory().join(file_name),
+            hide_path: true,
+        }
+    }
+
+    // the same vault kept in another file, as git hands it to a merge driver
+    pub fn at(&self, file: PathBuf) -> StoreFile {
+        StoreFile {
+            path: self.path.clone(),
+            file,
+            hide_path: self.hide_path,
+        }
+    }
+
+    // where the file is kept relative to the store directory
+    pub fn relative_file(&self) -> Result<PathBuf, String> {
+        self.file
+            .strip_prefix(config::get_store_directory())
+            .map(|relative| relative.to_path_buf())
+            .map_err(|_| format!("{} is not in the store", self.file.display()))
+    }
+}
+
+fn associated_data(header: &Header, path: &str) -> Vec<u8> {
+    let mut ad = Vec::new();
+    ad.extend_from_slice(VAULT_AD_CONTEXT);
+    ad.push(0);
+    if header.format_version == 1 {
+        ad.extend_from_slice(&1u32.to_le_bytes());
+    } else {
+        ad.extend_from_slice(&header.to_bytes());
+    }
+    ad.extend_from_slice(path.as_bytes());
+    ad
 }
 
 impl Vault {
-    pub fn read_vault(path: &str) -> Result<Vault, String> {
-        let path = config::get_store_directory().join(path);
-        let bytes = fs::read(&path).map_err(|e| format!("filesystem error: {}", e))?;
-        serde_json::from_slice(&bytes).map_err(|e| format!("json error: {}", e))
+    pub fn read_vault(store_file: &StoreFile) -> Result<Vault, String> {
+        let file = File::open(&store_file.file).map_err(|e| format!("filesystem error: {}", e))?;
+        let file_len = file
+            .metadata()
+            .map_err(|e| format!("filesystem error: {}", e))?
+            .len();
+        let mut reader = BufReader::new(file);
+        let mut prefix = vec![0; HEADER_LEN];
+        let prefix_len = read_full(&mut reader, &mut prefix)?;
+        prefix.truncate(prefix_len);
+        if !prefix.starts_with(VAULT_MAGIC) {
+            reader
+                .read_to_end(&mut prefix)
+                .map_err(|e| format!("filesystem error: {}", e))?;
+            return Vault::from_legacy_json(&prefix);
+        }
+
+        if prefix.len() == HEADER_LEN {
+            // newer headers carry more fields after the first HEADER_LEN bytes
+            let format_version = u16::from_le_bytes([prefix[4], prefix[5]]);
+            let mut extra = vec![0; Header::len(format_version) - HEADER_LEN];
+            let extra_len = read_full(&mut reader, &mut extra)?;
+            prefix.extend_from_slice(&extra[..extra_len]);
+        }
+        let header = Header::from_bytes(&prefix)?;
+        if header.format_version < 3 {
+            let mut body = Vec::new();
+            reader
+                .read_to_end(&mut body)
+                .map_err(|e| format!("filesystem error: {}", e))?;
+            let body: VaultBody =
+                serde_json::from_slice(&body).map_err(|e| format!("json error: {}", e))?;
+            return Ok(Vault::from_body(header, body, None, 0, None));
+        }
+
+        let mut body_len = [0; 4];
+        if read_full(&mut reader, &mut body_len)? != body_len.len() {
+            return Err("Vault is truncated".to_string());
+        }
+        let body_len = u32::from_le_bytes(body_len) as usize;
+        if body_len > MAX_BODY_LENGTH {
+            return Err("Vault body is too large".to_string());
+        }
+        let mut body = vec![0; body_len];
+        if read_full(&mut reader, &mut body)? != body_len {
+            return Err("Vault is truncated".to_string());
+        }
+        let mut hasher = new_hasher()?;
+        hash_update(&mut hasher, &prefix)?;
+        hash_update(&mut hasher, &(body_len as u32).to_le_bytes())?;
+        hash_update(&mut hasher, &body)?;
+        let mut body: VaultBody =
+            serde_json::from_slice(&body).map_err(|e| format!("json error: {}", e))?;
+        if body.path.is_none() && header.format_version >= 4 {
+            // the path was left out to hide it, the AEAD still fails if it is the wrong one
+            body.path = Some(store_file.path.clone());
+        }
+        if header.format_version < 5 {
+            body.author = None;
+            body.timestamp = None;
+        }
+        let stream_len = file_len.saturating_sub((prefix.len() + 4 + body_len) as u64);
+        Ok(Vault::from_body(
+            header,
+            body,
+            Some(reader),
+            stream_len,
+            Some(hasher),
+        ))
+    }
+
+    fn from_body(
+        header: Header,
+        body: VaultBody,
+        stream: Option<BufReader<File>>,
+        stream_len: u64,
+        hasher: Option<generichash::State>,
+    ) -> Vault {
+        Vault {
+            header,
+            path: body.path,
+            payload: body.payload,
+            nonce: body.nonce,
+            recipients: body.recipients,
+            author: body.author,
+            timestamp: body.timestamp,
+            threshold: body.threshold,
+            stream,
+            stream_len,
+            hasher,
+        }
+    }
+
+    fn from_legacy_json(bytes: &[u8]) -> Result<Vault, String> {
+        let legacy: LegacyVault =
+            serde_json::from_slice(bytes).map_err(|e| format!("json error: {}", e))?;
+        let cipher = match legacy.version {
+            0 => Cipher::SecretBox,
+            1 => Cipher::XChaCha20Poly1305,
+            v => return Err(format!("Unsupported legacy vault version: {}", v)),
+        };
+        Ok(Vault {
+            header: Header {
+                format_version: legacy.version as u16,
+                cipher,
+                key_wrap: KeyWrap::SealedBox,
+                padding: Padding::None,
+            },
+            path: legacy.path,
+            payload: legacy.payload,
+            nonce: legacy.nonce,
+            recipients: legacy.recipients,
+            author: None,
+            timestamp: None,
+            threshold: None,
+            stream: None,
+            stream_len: 0,
+            hasher: None,
+        })
+    }
+
+    pub fn format_version(&self) -> u16 {
+        self.header.format_version
+    }
+
+    pub fn needs_migration(&self) -> bool {
+        self.header.format_version < FORMAT_VERSION
+    }
+
+    // as the vault claims it, the author is verified once the vault is decrypted
+    pub fn get_author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
+    // the path the vault is bound to, None where it is left out to hide it
+    pub fn get_path(&self) -> Option<&str> {
+        self.path.as_deref().filter(|path| !path.is_empty())
+    }
+
+    pub fn get_timestamp(&self) -> Option<u128> {
+        self.timestamp
+    }
+
+    pub fn get_recipient_keys(&self) -> Vec<public::PublicKeyWrapper> {
+        self.recipients.iter().map(|r| r.pub_key.clone()).collect()
+    }
+
+    pub fn get_threshold(&self) -> Option<u8> {
+        match self.header.key_wrap {
+            KeyWrap::Threshold => self.threshold,
+            KeyWrap::SealedBox => None,
+        }
     }
 
     pub fn write_vault(
-        path: &str,
+        store_file: &StoreFile,
         message: &[u8],
         recipients: Vec<public::PublicKeyWrapper>,
+        author: Option<&Author>,
     ) -> Result<(), String> {
-        let sym_key = secretbox::gen_key();
-        let nonce = secretbox::gen_nonce();
-        let payload = secretbox::seal(message, &nonce, &sym_key);
-
-        let recipients = recipients
-            .into_iter()
-            .map(|r| {
-                let payload = r.encrypt(&sym_key.0);
-                Recipient {
-                    pub_key: r,
-                    payload,
+        let mut source = message;
+        Vault::write_vault_stream(store_file, &mut source, recipients, author).map(|_| ())
+    }
+
+    // returns the plaintext size, the previous vault is only replaced once this one is on disk
+    pub fn write_vault_stream(
+        store_file: &StoreFile,
+        source: &mut dyn Read,
+        recipients: Vec<public::PublicKeyWrapper>,
+        author: Option<&Author>,
+    ) -> Result<u64, String> {
+        Vault::write_threshold_vault_stream(
+            store_file,
+            source,
+            recipients,
+            None,
+            PaddingRule::default(),
+            author,
+        )
+    }
+
+    // each recipient gets a share of the key when threshold names how many it takes
+    pub fn write_threshold_vault_stream(
+        store_file: &StoreFile,
+        source: &mut dyn Read,
+        recipients: Vec<public::PublicKeyWrapper>,
+        threshold: Option<u8>,
+        padding: PaddingRule,
+        author: Option<&Author>,
+    ) -> Result<u64, String> {
+        let bound_path = store_file.path.clone();
+        let mut header = Header::current(padding.mode);
+        let sym_key = secretstream::gen_key();
+        let (mut stream, stream_header) = secretstream::Stream::init_push(&sym_key)
+            .map_err(|_| "Unable to start vault encryption stream".to_string())?;
+
+        let recipients = match threshold {
+            Some(threshold) => {
+                header.key_wrap = KeyWrap::Threshold;
+                if recipients.len() > u8::MAX as usize {
+                    return Err("A threshold vault has at most 255 recipients".to_string());
                 }
-            })
-            .collect();
-        let vault = Vault {
-            payload,
-            nonce,
+                let shares = shamir::split(&sym_key.0, threshold, recipients.len() as u8)?;
+                recipients
+                    .into_iter()
+                    .zip(shares.iter())
+                    .map(|(r, share)| {
+                        let payload = r.encrypt(share);
+                        Recipient {
+                            pub_key: r,
+                            payload,
+                        }
+                    })
+                    .collect()
+            }
+            None => recipients
+                .into_iter()
+                .map(|r| {
+                    let payload = r.encrypt(&sym_key.0);
+                    Recipient {
+                        pub_key: r,
+                        payload,
+                    }
+                })
+                .collect(),
+        };
+        let ad = associated_data(&header, &bound_path);
+        let body = VaultBody {
+            path: if store_file.hide_path {
+                None
+            } else {
+                Some(bound_path)
+            },
+            payload: Vec::new(),
+            nonce: stream_header.0.to_vec(),
             recipients,
+            author: author.map(|a| a.name.to_string()),
+            timestamp: Some(timestamp::now_millis()),
+            threshold,
         };
+        let body = serde_json::to_vec(&body).map_err(|e| format!("json error: {}", e))?;
 
-        let vault_json = serde_json::to_string(&vault).map_err(|e| format!("json error: {}", e))?;
-        let path = config::get_store_directory().join(path);
-        fs::write(path, vault_json.as_bytes()).map_err(|e| format!("filesystem error: {}", e))
+        let bucket_size = padding.bucket_size;
+        let bucket = move |len| bucket_length(len, bucket_size);
+        let padded_len: Option<&dyn Fn(u64) -> u64> = match header.padding {
+            Padding::None => None,
+            Padding::Padme => Some(&padme_length),
+            Padding::Bucket => Some(&bucket),
+        };
+        durable::write_file_with(&store_file.file, &mut |file| {
+            let mut writer = HashingWriter {
+                inner: BufWriter::new(file),
+                hasher: new_hasher()?,
+            };
+            writer
+                .write_all(&header.to_bytes())
+                .and_then(|_| writer.write_all(&(body.len() as u32).to_le_bytes()))
+                .and_then(|_| writer.write_all(&body))
+                .map_err(|e| format!("filesystem error: {}", e))?;
+            let size = encrypt_stream(source, &mut stream, &ad, padded_len, &mut writer)?;
+            let mut writer = match author {
+                Some(author) => {
+                    let digest = hash_finalize(writer.hasher)?;
+                    let signature = author.key.sign(&signed_message(&digest))?;
+                    let mut inner = writer.inner;
+                    inner
+                        .write_all(&signature)
+                        .map_err(|e| format!("filesystem error: {}", e))?;
+                    inner
+                }
+                None => writer.inner,
+            };
+            writer
+                .flush()
+                .map_err(|e| format!("filesystem error: {}", e))?;
+            Ok(size)
+        })
+    }
+
+    // the path is authenticated by the AEAD, this only turns a swapped vault into a clear error
+    fn check_path(&self, st: &mut state::State, path: &str) -> Result<(), String> {
+        // the keychain is read before the format it records is known
+        let migrated_format = if path == constants::KEYCHAIN_FILE_NAME {
+            0
+        } else {
+            st.get_chain()?.migrated_format
+        };
+        // an older format would drop what the store relies on, such as the bound path or
+        // the author
+        if self.header.format_version < migrated_format {
+            return Err(format!(
+                "Vault at {} is in format {} but the store was migrated to format {}, refusing to decrypt",
+                path, self.header.format_version, migrated_format
+            ));
+        }
+        if self.header.format_version == 0 {
+            logger::warn(&format!(
+                "Vault at {} is not bound to its path, run migrate to upgrade it",
+                path
+            ));
+            return Ok(());
+        }
+        let requested = path;
+        match self.path.as_ref() {
+            Some(bound) if *bound == requested => Ok(()),
+            Some(bound) => Err(format!(
+                "Vault at {} belongs to {}, refusing to decrypt",
+                requested, bound
+            )),
+            None => Err(format!("Vault at {} is missing its bound path", requested)),
+        }
     }
 
     fn try_decode_vault(
         &self,
         recipient: &Recipient,
         priv_key: &dyn private::PrivateKey,
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<SecretBytes, String> {
         let sym_key = priv_key.decrypt(&recipient.payload);
         if sym_key.is_err() {
             return Err("WARNING: Unable to decrypt vault symmetric key".to_string());
         }
         let sym_key = sym_key.unwrap();
-        let sym_key = secretbox::Key::from_slice(&sym_key);
-        if sym_key.is_none() {
-            return Err("Invalid symmetric key decrypted".to_string());
-        }
-        let sym_key = sym_key.unwrap();
-
-        let decrypted_contents = secretbox::open(&self.payload, &self.nonce, &sym_key);
+        let decrypted_contents = match self.header.cipher {
+            Cipher::SecretBox => {
+                let sym_key = secretbox::Key::from_slice(&sym_key)
+                    .ok_or("Invalid symmetric key decrypted".to_string())?;
+                let nonce = secretbox::Nonce::from_slice(&self.nonce)
+                    .ok_or("Invalid vault nonce".to_string())?;
+                secretbox::open(&self.payload, &nonce, &sym_key)
+            }
+            Cipher::XChaCha20Poly1305 => {
+                let sym_key = aead::Key::from_slice(&sym_key)
+                    .ok_or("Invalid symmetric key decrypted".to_string())?;
+                let nonce = aead::Nonce::from_slice(&self.nonce)
+                    .ok_or("Invalid vault nonce".to_string())?;
+                let ad = associated_data(&self.header, self.path.as_deref().unwrap_or(""));
+                aead::open(&self.payload, Some(&ad), &nonce, &sym_key)
+            }
+            Cipher::SecretStream => {
+                return Err("Streamed vaults cannot be decoded in one piece".to_string())
+            }
+        };
         if decrypted_contents.is_err() {
             return Err("Unable to decrypt vault contents with symmetric key".to_string());
         }
-        return Ok(decrypted_contents.unwrap());
+        Ok(SecretBytes::new(decrypted_contents.unwrap()))
+    }
+
+    // returns the plaintext size and the signature of a vault that names its author
+    fn decrypt_stream(
+        &mut self,
+        sym_key: &[u8],
+        out: &mut dyn Write,
+    ) -> Result<(u64, Option<Signature>), String> {
+        let sym_key = secretstream::Key::from_slice(sym_key)
+            .ok_or("Invalid symmetric key decrypted".to_string())?;
+        let stream_header = secretstream::Header::from_slice(&self.nonce)
+            .ok_or("Invalid vault stream header".to_string())?;
+        let mut stream = secretstream::Stream::init_pull(&stream_header, &sym_key)
+            .map_err(|_| "Invalid vault stream header".to_string())?;
+        let ad = associated_data(&self.header, self.path.as_deref().unwrap_or(""));
+        let reader = self
+            .stream
+            .as_mut()
+            .ok_or("Vault stream has already been read".to_string())?;
+        let mut hasher = self
+            .hasher
+            .take()
+            .ok_or("Vault stream has already been read".to_string())?;
+        let trailer_len = if self.author.is_some() {
+            sign::SIGNATUREBYTES as u64
+        } else {
+            0
+        };
+        if self.stream_len < trailer_len {
+            return Err("Vault is truncated".to_string());
+        }
+        let mut chunks = (&mut *reader).take(self.stream_len - trailer_len);
+
+        let mut chunk = vec![0; CHUNK_SIZE + secretstream::ABYTES];
+        let mut size = 0;
+        let mut in_padding = false;
+        loop {
+            let chunk_len = read_full(&mut chunks, &mut chunk)?;
+            if chunk_len == 0 {
+                return Err("Vault is truncated".to_string());
+            }
+            hash_update(&mut hasher, &chunk[..chunk_len])?;
+            let (plaintext, tag) = stream
+                .pull(&chunk[..chunk_len], Some(&ad))
+                .map_err(|_| "Unable to decrypt vault contents with symmetric key".to_string())?;
+            let plaintext = SecretBytes::new(plaintext);
+            let data = if in_padding {
+                if plaintext.iter().any(|b| *b != 0) {
+                    return Err("Invalid vault padding".to_string());
+                }
+                &plaintext[..0]
+            } else if self.header.padding != Padding::None && tag != secretstream::Tag::Message {
+                in_padding = true;
+                unpad(&plaintext)?
+            } else {
+                &plaintext[..]
+            };
+            out.write_all(data)
+                .map_err(|e| format!("Unable to write decrypted contents: {}", e))?;
+            size += data.len() as u64;
+            if tag == secretstream::Tag::Final {
+                break;
+            }
+        }
+        if read_full(&mut chunks, &mut chunk[..1])? != 0 {
+            return Err("Vault has data after its final chunk".to_string());
+        }
+
+        let signature = match self.author.as_ref() {
+            Some(author) => {
+                let reader = chunks.into_inner();
+                let mut signature = vec![0; sign::SIGNATUREBYTES];
+                if read_full(reader, &mut signature)? != signature.len() {
+                    return Err("Vault is truncated".to_string());
+                }
+                if read_full(reader, &mut chunk[..1])? != 0 {
+                    return Err("Vault has data after its signature".to_string());
+                }
+                Some(Signature {
+                    author: author.clone(),
+                    digest: hash_finalize(hasher)?,
+                    signature,
+                })
+            }
+            None => None,
+        };
+        Ok((size, signature))
     }
 
-    pub fn unlock_vault(st: &mut state::State, path: &str) -> Result<Vec<u8>, String> {
-        let mut vault = Vault::read_vault(path)?;
-        for recipient in vault.recipients.iter() {
-            let mut priv_key = st.keys.unlocked.get(recipient.pub_key.get_key_name());
+    // the result of the first recipient key `open` succeeds with, and the name of the key
+    fn open_with_recipient_key<T>(
+        &self,
+        st: &mut state::State,
+        path: &str,
+        open: &mut dyn FnMut(&Recipient, &dyn private::PrivateKey) -> Result<T, String>,
+    ) -> Result<(T, String), String> {
+        for recipient in self.recipients.iter() {
+            let key_name = recipient.pub_key.get_key_name();
+            let mut priv_key = st.keys.unlocked.get(key_name);
             if priv_key.is_none() {
-                priv_key = st
-                    .keys
-                    .session_unlocked
-                    .get(recipient.pub_key.get_key_name());
+                priv_key = st.keys.session_unlocked.get(key_name);
             }
             if priv_key.is_none() {
                 continue;
             }
-            let priv_key = st
-                .keys
-                .unlocked
-                .get(recipient.pub_key.get_key_name())
-                .unwrap();
-            let decrypted_contents = vault.try_decode_vault(&recipient, priv_key.as_ref());
-            if decrypted_contents.is_err() {
-                super::log_message(&format!("WARNING: {}", decrypted_contents.err().unwrap()));
-                continue;
+            match open(recipient, priv_key.unwrap().as_ref()) {
+                Ok(res) => return Ok((res, key_name.to_string())),
+                Err(err) => logger::warn(&err),
             }
-            return decrypted_contents;
         }
 
-        for recipient in vault.recipients.iter() {
-            let priv_key = st.keys.try_unlock(recipient.pub_key.get_key_name());
+        for recipient in self.recipients.iter() {
+            let priv_key = st.keys.try_unlock(recipient.pub_key.get_key_name())?;
             if priv_key.is_none() {
                 continue;
             }
-
-            let priv_key = priv_key.unwrap();
-            let decrypted_contents = vault.try_decode_vault(&recipient, priv_key);
-            if decrypted_contents.is_err() {
-                super::log_message(&format!("WARNING: {}", decrypted_contents.err().unwrap()));
-                continue;
+            match open(recipient, priv_key.unwrap()) {
+                Ok(res) => return Ok((res, recipient.pub_key.get_key_name().to_string())),
+                Err(err) => logger::warn(&err),
             }
-            return decrypted_contents;
         }
 
-        vault.recipients.sort_by(sort_recipient);
-        for recipient in vault.recipients.iter() {
-            let priv_key = st.keys.try_load_key(&recipient.pub_key);
+        let mut recipients: Vec<&Recipient> = self.recipients.iter().collect();
+        recipients.sort_by(|rec1, rec2| sort_recipient(rec1, rec2));
+        for recipient in recipients {
+            let priv_key = st.keys.try_load_key(&recipient.pub_key)?;
             if priv_key.is_none() {
                 continue;
             }
+            match open(recipient, priv_key.unwrap()) {
+                Ok(res) => return Ok((res, recipient.pub_key.get_key_name().to_string())),
+                Err(err) => logger::warn(&err),
+            }
+        }
+
+        return Err(format!("No keys able to unlock file: {}", path));
+    }
 
-            let priv_key = priv_key.unwrap();
-            let decrypted_contents = vault.try_decode_vault(&recipient, priv_key);
-            if decrypted_contents.is_err() {
-                super::log_message(&format!("WARNING: {}", decrypted_contents.err().unwrap()));
+    // the stream key from the shares supplied for the command and those of this machine's
+    // keys, with the holders that gave them joined by +
+    fn combine_shares(
+        &self,
+        st: &mut state::State,
+        path: &str,
+    ) -> Result<(SecretBytes, String), String> {
+        let required =
+            self.threshold
+                .ok_or(format!("Vault at {} is missing its threshold", path))? as usize;
+        let mut shares: Vec<SecretBytes> = Vec::new();
+        let mut holders: Vec<String> = Vec::new();
+        for supplied in std::mem::take(&mut st.shares) {
+            if supplied.path != path || supplied.nonce != self.nonce {
+                return Err(format!(
+                    "The share of {} is not for the current revision of {}",
+                    supplied.holder, path
+                ));
+            }
+            if !self
+                .recipients
+                .iter()
+                .any(|r| r.pub_key.get_key_name() == supplied.holder)
+            {
+                return Err(format!("{} holds no share of {}", supplied.holder, path));
+            }
+            if holders.contains(&supplied.holder) {
                 continue;
             }
-            return decrypted_contents;
+            let sealed_for = st
+                .get_chain()?
+                .key_names_to_keys(std::slice::from_ref(&supplied.recipient))
+                .pop()
+                .ok_or(format!("Key {} is not in the keychain", supplied.recipient))?;
+            let priv_key = st.keys.get_private_key(&sealed_for)?.ok_or(format!(
+                "Key {} is needed to open the share of {}",
+                supplied.recipient, supplied.holder
+            ))?;
+            let share = priv_key
+                .decrypt(&supplied.sealed)
+                .map_err(|_| format!("Unable to open the share of {}", supplied.holder))?;
+            shares.push(share);
+            holders.push(supplied.holder);
         }
 
-        return Err(format!("No keys able to unlock file: {}", path));
+        // keys that are already unlocked, then locked keys, then keys loaded from disk
+        for pass in 0..3 {
+            for recipient in self.recipients.iter() {
+                let name = recipient.pub_key.get_key_name();
+                if shares.len() >= required || holders.iter().any(|h| h == name) {
+                    continue;
+                }
+                let priv_key = match pass {
+                    0 => st
+                        .keys
+                        .unlocked
+                        .get(name)
+                        .or(st.keys.session_unlocked.get(name))
+                        .map(|k| k.as_ref()),
+                    1 => st.keys.try_unlock(name)?,
+                    _ => match recipient.pub_key {
+                        public::PublicKeyWrapper::Sodium(_) => {
+                            st.keys.try_load_key(&recipient.pub_key)?
+                        }
+                        _ => None,
+                    },
+                };
+                match priv_key.map(|k| k.decrypt(&recipient.payload)) {
+                    Some(Ok(share)) => {
+                        shares.push(share);
+                        holders.push(name.to_string());
+                    }
+                    Some(Err(_)) => {
+                        logger::warn(&format!("Unable to decrypt the share of {}", name))
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        if shares.len() < required {
+            return Err(format!(
+                "{} takes the shares of {} of its {} holders and {} were found{}, ask the others for theirs with threshold share",
+                path,
+                required,
+                self.recipients.len(),
+                shares.len(),
+                if holders.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", holders.join(", "))
+                }
+            ));
+        }
+        let sym_key = shamir::combine(&shares)?;
+        Ok((sym_key, holders.join("+")))
+    }
+
+    // the share a key of this machine holds, with the key's name and the vault's revision
+    pub fn open_own_share(
+        st: &mut state::State,
+        store_file: &StoreFile,
+    ) -> Result<(SecretBytes, String, Vec<u8>), String> {
+        let path = &store_file.path;
+        let vault = Vault::read_vault(store_file)?;
+        if vault.get_threshold().is_none() {
+            return Err(format!("{} is not a threshold vault", path));
+        }
+        vault.check_path(st, path)?;
+        let (share, key_name) =
+            vault.open_with_recipient_key(st, path, &mut |recipient, priv_key| {
+                priv_key
+                    .decrypt(&recipient.payload)
+                    .map_err(|_| "WARNING: Unable to decrypt vault key share".to_string())
+            })?;
+        Ok((share, key_name, vault.nonce))
+    }
+
+    pub fn unlock_vault(
+        st: &mut state::State,
+        store_file: &StoreFile,
+    ) -> Result<SecretBytes, String> {
+        Vault::unlock_vault_with_key(st, store_file).map(|(contents, _)| contents)
+    }
+
+    // also returns the key that opened the vault and its author's signature, which is up
+    // to the caller to check
+    pub fn unlock_vault_with_key(
+        st: &mut state::State,
+        store_file: &StoreFile,
+    ) -> Result<(SecretBytes, Unlocked), String> {
+        let path = &store_file.path;
+        let vault = Vault::read_vault(store_file)?;
+        if vault.header.cipher == Cipher::SecretStream {
+            let mut contents = SecretBytes::with_capacity(vault.stream_len as usize);
+            let unlocked = Vault::unlock_vault_into(st, path, vault, &mut contents)?;
+            return Ok((contents, unlocked));
+        }
+        vault.check_path(st, path)?;
+        let (contents, key_name) =
+            vault.open_with_recipient_key(st, path, &mut |recipient, priv_key| {
+                vault.try_decode_vault(recipient, priv_key)
+            })?;
+        let unlocked = Unlocked {
+            key_name,
+            signature: None,
+        };
+        Ok((contents, unlocked))
+    }
+
+    // writes the decrypted contents to out as they are decrypted instead of collecting them
+    pub fn unlock_vault_to(
+        st: &mut state::State,
+        store_file: &StoreFile,
+        out: &mut dyn Write,
+    ) -> Result<Unlocked, String> {
+        let vault = Vault::read_vault(store_file)?;
+        if vault.header.cipher == Cipher::SecretStream {
+            return Vault::unlock_vault_into(st, &store_file.path, vault, out);
+        }
+        let (contents, unlocked) = Vault::unlock_vault_with_key(st, store_file)?;
+        out.write_all(&contents)
+            .map_err(|e| format!("Unable to write decrypted contents: {}", e))?;
+        Ok(unlocked)
+    }
+
+    fn unlock_vault_into(
+        st: &mut state::State,
+        path: &str,
+        mut vault: Vault,
+        out: &mut dyn Write,
+    ) -> Result<Unlocked, String> {
+        vault.check_path(st, path)?;
+        // every key opens the stream header, so only the key wrap is tried per recipient
+        let (sym_key, key_name) = match vault.header.key_wrap {
+            KeyWrap::Threshold => vault.combine_shares(st, path)?,
+            KeyWrap::SealedBox => {
+                vault.open_with_recipient_key(st, path, &mut |recipient, priv_key| {
+                    priv_key
+                        .decrypt(&recipient.payload)
+                        .map_err(|_| "WARNING: Unable to decrypt vault symmetric key".to_string())
+                })?
+            }
+        };
+        let (_, signature) = vault.decrypt_stream(&sym_key, out)?;
+        Ok(Unlocked {
+            key_name,
+            signature,
+        })
+    }
+}
+
+// hashes everything written, so the author can sign the vault once it is complete
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: generichash::State,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher
+            .update(&buf[..n])
+            .map_err(|_| std::io::Error::other("Unable to hash vault"))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn new_hasher() -> Result<generichash::State, String> {
+    generichash::State::new(generichash::DIGEST_MAX, None)
+        .map_err(|_| "Unable to hash vault".to_string())
+}
+
+fn hash_update(hasher: &mut generichash::State, data: &[u8]) -> Result<(), String> {
+    hasher
+        .update(data)
+        .map_err(|_| "Unable to hash vault".to_string())
+}
+
+fn hash_finalize(hasher: generichash::State) -> Result<Vec<u8>, String> {
+    hasher
+        .finalize()
+        .map(|digest| digest.as_ref().to_vec())
+        .map_err(|_| "Unable to hash vault".to_string())
+}
+
+fn signed_message(digest: &[u8]) -> Vec<u8> {
+    let mut message = VAULT_SIGNATURE_CONTEXT.to_vec();
+    message.push(0);
+    message.extend_from_slice(digest);
+    message
+}
+
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader
+            .read(&mut buf[filled..])
+            .map_err(|e| format!("Unable to read vault contents: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+// the smallest length of at least len that only has its top log2(log2(len)) + 1 bits set
+fn padme_length(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let exponent = 63 - len.leading_zeros() as u64;
+    let mantissa_bits = 64 - exponent.leading_zeros() as u64;
+    let mask = (1u64 << (exponent - mantissa_bits)) - 1;
+    (len + mask) & !mask
+}
+
+fn bucket_length(len: u64, bucket_size: u64) -> u64 {
+    let bucket_size = std::cmp::max(bucket_size, 1);
+    len.div_ceil(bucket_size) * bucket_size
+}
+
+fn unpad(chunk: &[u8]) -> Result<&[u8], String> {
+    match chunk.iter().rposition(|b| *b != 0) {
+        Some(idx) if chunk[idx] == 0x80 => Ok(&chunk[..idx]),
+        _ => Err("Invalid vault padding".to_string()),
+    }
+}
+
+fn encrypt_stream(
+    source: &mut dyn Read,
+    stream: &mut secretstream::Stream<secretstream::Push>,
+    ad: &[u8],
+    padded_len: Option<&dyn Fn(u64) -> u64>,
+    out: &mut dyn Write,
+) -> Result<u64, String> {
+    let mut push = |chunk: &[u8], tag| {
+        stream
+            .push(chunk, Some(ad), tag)
+            .map_err(|_| "Unable to encrypt vault contents".to_string())
+            .and_then(|c| {
+                out.write_all(&c)
+                    .map_err(|e| format!("filesystem error: {}", e))
+            })
+    };
+
+    // one chunk is read ahead so the last chunk of data is known when it is pushed
+    let mut current = SecretBytes::new(vec![0; CHUNK_SIZE]);
+    let mut next = SecretBytes::new(vec![0; CHUNK_SIZE]);
+    let mut current_len = read_full(source, &mut current)?;
+    let mut size = 0;
+    loop {
+        let mut next_len = 0;
+        if current_len == CHUNK_SIZE {
+            next_len = read_full(source, &mut next)?;
+        }
+        if next_len > 0 {
+            push(&current[..current_len], secretstream::Tag::Message)?;
+            size += current_len as u64;
+            std::mem::swap(&mut current, &mut next);
+            current_len = next_len;
+            continue;
+        }
+
+        let pushed = size;
+        size += current_len as u64;
+        let padded_len = match padded_len {
+            Some(padded_len) => padded_len(size + 1),
+            None => {
+                push(&current[..current_len], secretstream::Tag::Final)?;
+                return Ok(size);
+            }
+        };
+
+        // the rest of the data, then 0x80, then zeros up to the padded length
+        let mut data = &current[..current_len];
+        let mut remaining = padded_len - pushed;
+        let mut marker_pending = true;
+        let mut chunk = SecretBytes::new(vec![0; CHUNK_SIZE]);
+        while remaining > 0 {
+            let chunk_len = std::cmp::min(CHUNK_SIZE as u64, remaining) as usize;
+            let data_len = std::cmp::min(chunk_len, data.len());
+            chunk[..data_len].copy_from_slice(&data[..data_len]);
+            data = &data[data_len..];
+            for byte in chunk[data_len..chunk_len].iter_mut() {
+                *byte = 0;
+            }
+            let mut tag = secretstream::Tag::Message;
+            if marker_pending && data_len < chunk_len {
+                chunk[data_len] = 0x80;
+                marker_pending = false;
+                tag = secretstream::Tag::Push;
+            }
+            remaining -= chunk_len as u64;
+            if remaining == 0 {
+                tag = secretstream::Tag::Final;
+            }
+            push(&chunk[..chunk_len], tag)?;
+        }
+        return Ok(size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::private::PrivateKey;
+
+    #[test]
+    fn padme_rounds_up_by_little() {
+        assert_eq!(padme_length(0), 0);
+        assert_eq!(padme_length(1), 1);
+        assert_eq!(padme_length(9), 10);
+        assert_eq!(padme_length(100), 104);
+        assert_eq!(padme_length(1000), 1024);
+        for len in (2..5000).step_by(7) {
+            let padded = padme_length(len);
+            assert!(padded >= len);
+            assert!((padded - len) as f64 <= len as f64 * 0.12);
+            assert_eq!(padme_length(padded), padded);
+        }
+    }
+
+    #[test]
+    fn bucket_rounds_up_to_a_multiple() {
+        assert_eq!(bucket_length(0, 512), 0);
+        assert_eq!(bucket_length(1, 512), 512);
+        assert_eq!(bucket_length(512, 512), 512);
+        assert_eq!(bucket_length(513, 512), 1024);
+        assert_eq!(bucket_length(5, 0), 5);
+    }
+
+    #[test]
+    fn unpad_finds_the_marker() {
+        assert_eq!(unpad(b"abc\x80\0\0").unwrap(), b"abc");
+        assert_eq!(unpad(b"a\x80\0\x80").unwrap(), b"a\x80\0");
+        assert_eq!(unpad(b"\x80").unwrap(), b"");
+        assert!(unpad(b"abc\0").is_err());
+        assert!(unpad(b"\0\0").is_err());
+        assert!(unpad(b"").is_err());
+    }
+
+    #[test]
+    fn header_round_trips() {
+        for padding in [Padding::None, Padding::Padme, Padding::Bucket].iter() {
+            let mut header = Header::current(*padding);
+            header.key_wrap = KeyWrap::Threshold;
+            let decoded = Header::from_bytes(&header.to_bytes()).unwrap();
+            assert_eq!(decoded.format_version, FORMAT_VERSION);
+            assert!(decoded.cipher == Cipher::SecretStream);
+            assert!(decoded.key_wrap == KeyWrap::Threshold);
+            assert!(decoded.padding == *padding);
+        }
+        // formats before 4 have no padding byte
+        let mut header = Header::current(Padding::Padme);
+        header.format_version = 3;
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN);
+        assert!(Header::from_bytes(&bytes).unwrap().padding == Padding::None);
+    }
+
+    #[test]
+    fn header_rejects_unknown_vaults() {
+        let mut bytes = Header::current(Padding::None).to_bytes();
+        assert!(Header::from_bytes(&bytes[..HEADER_LEN]).is_err());
+        bytes[0] ^= 1;
+        assert!(Header::from_bytes(&bytes).is_err());
+        let mut header = Header::current(Padding::None);
+        header.format_version = FORMAT_VERSION + 1;
+        assert!(Header::from_bytes(&header.to_bytes()).is_err());
+    }
+
+    fn write_and_open(contents: &[u8], padding: PaddingRule) -> (Vault, SecretBytes) {
+        let key = private::SodiumPrivateKey::gen_key();
+        let store_file = StoreFile::plain("dir/secret");
+        std::fs::create_dir_all(store_file.file.parent().unwrap()).unwrap();
+        let mut source = contents;
+        Vault::write_threshold_vault_stream(
+            &store_file,
+            &mut source,
+            vec![key.get_public_key("test")],
+            None,
+            padding,
+            None,
+        )
+        .unwrap();
+        let mut vault = Vault::read_vault(&store_file).unwrap();
+        let sym_key = key.decrypt(&vault.recipients[0].payload).unwrap();
+        let mut out = Vec::new();
+        vault.decrypt_stream(&sym_key, &mut out).unwrap();
+        (vault, SecretBytes::new(out))
+    }
+
+    #[test]
+    fn padded_vaults_round_trip() {
+        sodiumoxide::init().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        config::with_home(home.path(), || {
+            for mode in [Padding::None, Padding::Padme, Padding::Bucket].iter() {
+                let padding = PaddingRule {
+                    mode: *mode,
+                    bucket_size: 512,
+                };
+                for len in [0, 1, 100, 70000].iter() {
+                    let contents = vec![0x80; *len];
+                    let (vault, opened) = write_and_open(&contents, padding);
+                    assert!(vault.header.padding == *mode);
+                    assert_eq!(&opened[..], &contents[..]);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn vault_is_bound_to_its_path() {
+        sodiumoxide::init().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        config::with_home(home.path(), || {
+            let (vault, _) = write_and_open(b"secret", PaddingRule::default());
+            let mut st = state::State::new();
+            assert!(vault.check_path(&mut st, "dir/secret").is_ok());
+            assert!(vault.check_path(&mut st, "dir/other").is_err());
+
+            // a vault copied over another one is refused under the other path
+            let file = StoreFile::plain("dir/secret").file;
+            let moved = StoreFile::copy("dir/other", file);
+            let vault = Vault::read_vault(&moved).unwrap();
+            assert!(vault.check_path(&mut st, &moved.path).is_err());
+        });
     }
 }
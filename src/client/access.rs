@@ -0,0 +1,68 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::rules;
+
+pub fn set_access(path: &str, keys: Vec<String>, priority: i32, add: bool) -> Result<(), String> {
+    let cleared = keys.is_empty();
+    let rule = rules::PathRule {
+        keys,
+        priority,
+        mode: if add {
+            rules::RuleMode::Add
+        } else {
+            rules::RuleMode::Replace
+        },
+    };
+    let cmd = command::Command::SetAccess(command::SetAccessRequest::new(path.to_string(), rule));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let (reencrypted, failed) = match resp {
+        command::Response::SetAccess(reencrypted, failed) => (reencrypted, failed),
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if cleared {
+        println!("Removed the rule of {}", path);
+    } else {
+        println!("Updated the keys of {}", path);
+    }
+    println!("{} vaults encrypted again", reencrypted);
+    super::report_failures(&failed)
+}
+
+pub fn explain_access(path: &str) -> Result<(), String> {
+    let cmd = command::Command::ExplainAccess(command::ExplainAccessRequest::new(path.to_string()));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let explanation = match resp {
+        command::Response::ExplainAccess(explanation) => explanation,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    println!(
+        "{} is encrypted for {}",
+        explanation.path,
+        explanation.keys.join(", ")
+    );
+    if let Some((rule_path, threshold)) = explanation.threshold.as_ref() {
+        println!(
+            "  {:<10} {}: {} together, the rules below do not count",
+            "threshold",
+            rule_path,
+            threshold.describe()
+        );
+    }
+    for ranked in explanation.rules.iter() {
+        let effect = match ranked.effect {
+            rules::Effect::Chose => "chose",
+            rules::Effect::Added => "added",
+            rules::Effect::Overridden => "overridden",
+        };
+        let pattern = if ranked.pattern.is_empty() {
+            "(whole store)"
+        } else {
+            &ranked.pattern
+        };
+        println!("  {:<10} {}: {}", effect, pattern, ranked.rule.describe());
+    }
+    if explanation.all_keys && explanation.threshold.is_none() {
+        println!("  no rule replaces the keys, so every key of the keychain reads it");
+    }
+    Ok(())
+}
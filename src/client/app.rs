@@ -1,7 +1,20 @@
+use super::access;
 use super::agent_cmd;
+use super::attach;
+use super::audit;
 use super::decrypt;
+use super::device;
 use super::encrypt;
+use super::git;
+use super::group;
+use super::history;
 use super::keys;
+use super::list;
+use super::migrate;
+use super::policy;
+use super::threshold;
+use super::trash;
+use super::writers;
 use crate::constants;
 use clap;
 use std::io::Write;
@@ -22,7 +35,107 @@ fn get_app<'a, 'b>() -> clap::App<'a, 'b> {
             clap::SubCommand::with_name("key")
                 .about("Manage keychain")
                 .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-                .subcommand(clap::SubCommand::with_name("add").about("add key")),
+                .subcommand(clap::SubCommand::with_name("add").about("add key"))
+                .subcommand(
+                    clap::SubCommand::with_name("upgrade-kdf")
+                        .about("rewrap a device key with stronger PIN hashing")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .long("name")
+                                .takes_value(true)
+                                .help("name of the device key to upgrade")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("level")
+                                .long("level")
+                                .takes_value(true)
+                                .possible_values(&["interactive", "moderate", "sensitive"])
+                                .default_value("sensitive")
+                                .help("new hashing level"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("export")
+                        .about("write a public key to hand to someone else")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .help("name of the key in the keychain")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("file")
+                                .long("file")
+                                .takes_value(true)
+                                .help("file to write the key to, stdout when missing"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("import")
+                        .about("add the public key of someone else to the keychain")
+                        .arg(
+                            clap::Arg::with_name("file")
+                                .help("the exported key")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("add-signing")
+                        .about("give a device key made before keychains were signed a signing key")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .long("name")
+                                .takes_value(true)
+                                .help("name of the device key")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("device")
+                .about("Enroll a new machine")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("request")
+                        .about("make the key of this machine and a request to enroll it")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .long("name")
+                                .takes_value(true)
+                                .help("name of the new device key")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("file")
+                                .long("file")
+                                .takes_value(true)
+                                .help("file to write the request to, - for stdout")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("level")
+                                .long("level")
+                                .takes_value(true)
+                                .possible_values(&["interactive", "moderate", "sensitive"])
+                                .default_value("interactive")
+                                .help("PIN hashing level"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("approve")
+                        .about("add the key of a new machine and encrypt its vaults for it")
+                        .arg(
+                            clap::Arg::with_name("file")
+                                .help("the enrollment request")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("like")
+                                .long("like")
+                                .takes_value(true)
+                                .help("give the new key the paths of this key"),
+                        ),
+                ),
         )
         .subcommand(
             clap::SubCommand::with_name("encrypt")
@@ -37,6 +150,7 @@ fn get_app<'a, 'b>() -> clap::App<'a, 'b> {
         )
         .subcommand(
             clap::SubCommand::with_name("decrypt")
+                .visible_alias("show")
                 .about("Decrypt secrets in the store")
                 .arg(
                     clap::Arg::with_name("path")
@@ -44,6 +158,549 @@ fn get_app<'a, 'b>() -> clap::App<'a, 'b> {
                         .takes_value(true)
                         .help("path to decrypt secret in the store")
                         .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("verify")
+                        .long("verify")
+                        .help("also report which key wrote the secret"),
+                )
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .long("rev")
+                        .takes_value(true)
+                        .help("decrypt an earlier revision, as listed by history"),
+                )
+                .arg(
+                    clap::Arg::with_name("share")
+                        .long("share")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("share file of a threshold secret from another holder, removed once read"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("history")
+                .about("List the earlier revisions of a secret")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("path of the secret in the store")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("restore")
+                .about("Bring back an earlier revision of a secret")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("path of the secret in the store")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .long("rev")
+                        .takes_value(true)
+                        .help("revision to restore, as listed by history")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("ls")
+                .about("List the secrets in the store")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("only list secrets below this directory"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("rm")
+                .about("Move a secret to the trash")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("path of the secret in the store")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("trash")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .about("Recover or purge removed secrets")
+                .subcommand(clap::SubCommand::with_name("list").about("list removed secrets"))
+                .subcommand(
+                    clap::SubCommand::with_name("restore")
+                        .about("bring back the last removed secret at a path")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path of the secret in the store")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("purge")
+                        .about("overwrite and delete removed secrets for good")
+                        .arg(
+                            clap::Arg::with_name("older-than")
+                                .long("older-than")
+                                .takes_value(true)
+                                .help("only purge secrets removed at least this long ago (90s, 45m, 12h, 30d, 2w)"),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("attach")
+                .about("Encrypt a file of any size into the store")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("path to save the file in the store")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .help("file to encrypt, - for stdin")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("extract")
+                .about("Decrypt a file from the store")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("path of the file in the store")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("file to write the decrypted contents to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("audit")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .about("Inspect the audit log of secret access")
+                .subcommand(
+                    clap::SubCommand::with_name("show")
+                        .about("show audit entries")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("only show entries for this store path"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .help("only show entries involving this key"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("since")
+                                .long("since")
+                                .takes_value(true)
+                                .help("only show entries at or after this time (YYYY-MM-DD[THH:MM:SS] UTC or unix seconds)"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("until")
+                                .long("until")
+                                .takes_value(true)
+                                .help("only show entries at or before this time"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("verify").about("check the audit log hash chain"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("repin")
+                        .about("pin the head of an intact audit log whose pin fell behind or was lost"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("migrate")
+                .about("Upgrade vaults in the store to the current format")
+                .arg(
+                    clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("only list the vaults that would be migrated"),
+                )
+                .arg(
+                    clap::Arg::with_name("hide-paths")
+                        .long("hide-paths")
+                        .help("store vaults under keyed hashes of their paths from now on"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("access")
+                .about("Choose which keys can read secrets below a path")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("encrypt the secrets below the path for the given keys and groups")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path or glob pattern such as */prod/** in the store, empty for the whole store")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key, or @group, able to read")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("priority")
+                                .long("priority")
+                                .takes_value(true)
+                                .allow_hyphen_values(true)
+                                .default_value("0")
+                                .help("rules of higher priority take precedence over more specific ones"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("add")
+                                .long("add")
+                                .help("add the keys to those the rules below this one choose"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("clear")
+                        .about("remove the rule of a path or pattern")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path or pattern of the rule")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("explain")
+                        .about("show which rules choose the keys a secret is encrypted for")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .help("path in the store")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("group")
+                .about("Manage groups of keys that path rules can name")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("create")
+                        .about("create a group")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .help("name of the group, as in @backend")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key in the group"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("add")
+                        .about("add keys to a group")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .help("name of the group, as in @backend")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key to add")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("remove")
+                        .about("remove keys from a group, or the group without keys")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .help("name of the group, as in @backend")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key to remove"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("show")
+                        .about("show the keys of groups and the rules naming them")
+                        .arg(
+                            clap::Arg::with_name("name")
+                                .help("name of the group, every group when missing"),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("policy")
+                .about("Require what the keys reading secrets below a path have to include")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("refuse to encrypt the secrets below the path for keys that break the policy")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path or glob pattern in the store, empty for the whole store")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("require")
+                                .long("require")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key, or @group of which any key, that must be able to read"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("min-recipients")
+                                .long("min-recipients")
+                                .takes_value(true)
+                                .help("number of keys that must be able to read"),
+                        )
+                        .arg(
+                            clap::Arg::with_name("hardware")
+                                .long("hardware")
+                                .help("a hardware key, which can only be imported, must be able to read"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("clear")
+                        .about("remove the policy of a path or pattern")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path or pattern of the policy")
+                                .required(true),
+                        ),
+                )
+                .subcommand(clap::SubCommand::with_name("show").about("list the policies")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("padding")
+                .about("Hide the exact length of secrets by padding the vaults of the store")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("pad the vaults written from now on")
+                        .arg(
+                            clap::Arg::with_name("mode")
+                                .long("mode")
+                                .takes_value(true)
+                                .possible_values(&["none", "padme", "bucket"])
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("bucket-size")
+                                .long("bucket-size")
+                                .takes_value(true)
+                                .help("bytes the padded length is a multiple of with bucket padding"),
+                        ),
+                )
+                .subcommand(clap::SubCommand::with_name("show").about("show the padding")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("threshold")
+                .about("Require several keyholders together to decrypt secrets below a path")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("split the key of the secrets below the path between the keys")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .help("path in the store")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("required")
+                                .help("number of keys it takes to decrypt")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .help("name of a key, or @group, holding a share")
+                                .multiple(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("clear")
+                        .about("let any key of the path rules decrypt the secrets below the path again")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .help("path in the store")
+                                .required(true),
+                        ),
+                )
+                .subcommand(clap::SubCommand::with_name("show").about("list the thresholds"))
+                .subcommand(
+                    clap::SubCommand::with_name("share")
+                        .about("write the share of a secret this machine holds for someone else to decrypt it")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .help("path of the secret in the store")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("for")
+                                .long("for")
+                                .takes_value(true)
+                                .help("name of the key of whoever decrypts the secret, the share is sealed for it")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("file")
+                                .long("file")
+                                .takes_value(true)
+                                .help("file to write the sealed share to")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("writers")
+                .about("Limit which keys may write secrets below a path")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("only let the given keys write below the path")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path in the store, empty for the whole store")
+                                .required(true),
+                        )
+                        .arg(
+                            clap::Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("name of a key allowed to write")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("clear")
+                        .about("let any key write below the path again")
+                        .arg(
+                            clap::Arg::with_name("path")
+                                .long("path")
+                                .takes_value(true)
+                                .help("path in the store")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("git")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .about("Share the store through a git repository")
+                .subcommand(
+                    clap::SubCommand::with_name("init")
+                        .about("make the store a git repository")
+                        .arg(
+                            clap::Arg::with_name("remote")
+                                .long("remote")
+                                .takes_value(true)
+                                .help("repository to push to and pull from"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("clone")
+                        .about("fill an empty store from a git repository")
+                        .arg(
+                            clap::Arg::with_name("url")
+                                .long("url")
+                                .takes_value(true)
+                                .help("repository to clone")
+                                .required(true),
+                        ),
+                )
+                .subcommand(clap::SubCommand::with_name("push").about("push store commits"))
+                .subcommand(clap::SubCommand::with_name("pull").about("pull and merge store commits"))
+                .subcommand(clap::SubCommand::with_name("log").about("show the store commits"))
+        )
+        .subcommand(
+            clap::SubCommand::with_name("git-merge-driver")
+                .about("merge two revisions of a store file, used by git as a merge driver")
+                .arg(
+                    clap::Arg::with_name("base")
+                        .long("base")
+                        .takes_value(true)
+                        .help("revision both sides started from")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("ours")
+                        .long("ours")
+                        .takes_value(true)
+                        .help("our revision, replaced by the merge")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("theirs")
+                        .long("theirs")
+                        .takes_value(true)
+                        .help("their revision")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .help("the file in the store")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("git-textconv")
+                .about("show a store file as text, used by git diff")
+                .arg(
+                    clap::Arg::with_name("file")
+                        .help("the file to show")
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -60,6 +717,32 @@ fn handle_app<'a, 'b>(app: clap::App<'a, 'b>) -> i32 {
     let res = match matches.subcommand() {
         ("key", Some(key_matches)) => match key_matches.subcommand() {
             ("add", _) => keys::add_key(),
+            ("upgrade-kdf", Some(upgrade_matches)) => keys::upgrade_kdf(
+                upgrade_matches.value_of("name").unwrap(),
+                upgrade_matches.value_of("level").unwrap(),
+            ),
+            ("add-signing", Some(signing_matches)) => {
+                keys::add_signing_key(signing_matches.value_of("name").unwrap())
+            }
+            ("export", Some(export_matches)) => keys::export_key(
+                export_matches.value_of("name").unwrap(),
+                export_matches.value_of("file"),
+            ),
+            ("import", Some(import_matches)) => {
+                keys::import_key(import_matches.value_of("file").unwrap())
+            }
+            _ => panic!("subcommand required"),
+        },
+        ("device", Some(device_matches)) => match device_matches.subcommand() {
+            ("request", Some(request_matches)) => device::request(
+                request_matches.value_of("name").unwrap(),
+                request_matches.value_of("file").unwrap(),
+                request_matches.value_of("level").unwrap(),
+            ),
+            ("approve", Some(approve_matches)) => device::approve(
+                approve_matches.value_of("file").unwrap(),
+                approve_matches.value_of("like"),
+            ),
             _ => panic!("subcommand required"),
         },
         ("encrypt", Some(enc_matches)) => {
@@ -68,15 +751,239 @@ fn handle_app<'a, 'b>(app: clap::App<'a, 'b>) -> i32 {
         }
         ("decrypt", Some(dec_matches)) => {
             let path = dec_matches.value_of("path").unwrap();
-            let contents = decrypt::decrypt_path(path);
+            let share_files: Vec<&str> = dec_matches
+                .values_of("share")
+                .map(|files| files.collect())
+                .unwrap_or_default();
+            let contents = dec_matches
+                .value_of("rev")
+                .map(history::parse_rev)
+                .transpose()
+                .and_then(|rev| {
+                    threshold::read_share_files(&share_files).map(|shares| (rev, shares))
+                })
+                .and_then(|(rev, shares)| decrypt::decrypt_path(path, rev, shares))
+                .inspect(|_| threshold::scrub_share_files(&share_files));
             if contents.is_err() {
                 Err(contents.err().unwrap())
             } else {
-                std::io::stdout().write_all(&contents.unwrap()).unwrap();
+                let (contents, author) = contents.unwrap();
+                if dec_matches.is_present("verify") {
+                    match author {
+                        Some(author) => eprintln!("Written by: {} (signature verified)", author),
+                        None => eprintln!("Not signed by its author"),
+                    }
+                }
+                std::io::stdout().write_all(&contents).unwrap();
                 println!("");
                 Ok(())
             }
         }
+        ("history", Some(history_matches)) => {
+            history::show_history(history_matches.value_of("path").unwrap())
+        }
+        ("restore", Some(restore_matches)) => history::restore_revision(
+            restore_matches.value_of("path").unwrap(),
+            restore_matches.value_of("rev").unwrap(),
+        ),
+        ("ls", Some(ls_matches)) => list::list_paths(ls_matches.value_of("path")),
+        ("rm", Some(rm_matches)) => trash::remove_path(rm_matches.value_of("path").unwrap()),
+        ("trash", Some(trash_matches)) => match trash_matches.subcommand() {
+            ("list", _) => trash::list_trash(),
+            ("restore", Some(restore_matches)) => {
+                trash::restore_path(restore_matches.value_of("path").unwrap())
+            }
+            ("purge", Some(purge_matches)) => {
+                trash::purge_trash(purge_matches.value_of("older-than"))
+            }
+            _ => panic!("subcommand required"),
+        },
+        ("attach", Some(attach_matches)) => attach::attach_file(
+            attach_matches.value_of("path").unwrap(),
+            attach_matches.value_of("file").unwrap(),
+        ),
+        ("extract", Some(extract_matches)) => attach::extract_file(
+            extract_matches.value_of("path").unwrap(),
+            extract_matches.value_of("output").unwrap(),
+        ),
+        ("audit", Some(audit_matches)) => match audit_matches.subcommand() {
+            ("show", Some(show_matches)) => audit::AuditFilter::new(
+                show_matches.value_of("path"),
+                show_matches.value_of("key"),
+                show_matches.value_of("since"),
+                show_matches.value_of("until"),
+            )
+            .and_then(audit::show),
+            ("verify", _) => audit::verify(),
+            ("repin", _) => audit::repin(),
+            _ => panic!("subcommand required"),
+        },
+        ("migrate", Some(migrate_matches)) => migrate::migrate_store(
+            migrate_matches.is_present("dry-run"),
+            migrate_matches.is_present("hide-paths"),
+        ),
+        ("access", Some(access_matches)) => match access_matches.subcommand() {
+            ("set", Some(set_matches)) => set_matches
+                .value_of("priority")
+                .unwrap()
+                .parse::<i32>()
+                .map_err(|e| format!("Invalid priority: {}", e))
+                .and_then(|priority| {
+                    access::set_access(
+                        set_matches.value_of("path").unwrap(),
+                        set_matches
+                            .values_of("key")
+                            .unwrap()
+                            .map(|k| k.to_string())
+                            .collect(),
+                        priority,
+                        set_matches.is_present("add"),
+                    )
+                }),
+            ("clear", Some(clear_matches)) => access::set_access(
+                clear_matches.value_of("path").unwrap(),
+                Vec::new(),
+                0,
+                false,
+            ),
+            ("explain", Some(explain_matches)) => {
+                access::explain_access(explain_matches.value_of("path").unwrap())
+            }
+            _ => panic!("subcommand required"),
+        },
+        ("group", Some(group_matches)) => match group_matches.subcommand() {
+            ("create", Some(create_matches)) => group::create_group(
+                create_matches.value_of("name").unwrap(),
+                create_matches
+                    .values_of("key")
+                    .map(|keys| keys.map(|k| k.to_string()).collect())
+                    .unwrap_or_default(),
+            ),
+            ("add", Some(add_matches)) => group::add_to_group(
+                add_matches.value_of("name").unwrap(),
+                add_matches
+                    .values_of("key")
+                    .map(|keys| keys.map(|k| k.to_string()).collect())
+                    .unwrap_or_default(),
+            ),
+            ("remove", Some(remove_matches)) => group::remove_from_group(
+                remove_matches.value_of("name").unwrap(),
+                remove_matches
+                    .values_of("key")
+                    .map(|keys| keys.map(|k| k.to_string()).collect())
+                    .unwrap_or_default(),
+            ),
+            ("show", Some(show_matches)) => group::show_groups(show_matches.value_of("name")),
+            _ => panic!("subcommand required"),
+        },
+        ("policy", Some(policy_matches)) => match policy_matches.subcommand() {
+            ("set", Some(set_matches)) => set_matches
+                .value_of("min-recipients")
+                .unwrap_or("0")
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid number of recipients: {}", e))
+                .and_then(|min_recipients| {
+                    let policy = crate::agent::policy::Policy {
+                        required: set_matches
+                            .values_of("require")
+                            .map(|keys| keys.map(|k| k.to_string()).collect())
+                            .unwrap_or_default(),
+                        min_recipients,
+                        hardware: set_matches.is_present("hardware"),
+                    };
+                    if policy.is_empty() {
+                        return Err("Give keys to require, a number of recipients or --hardware"
+                            .to_string());
+                    }
+                    policy::set_policy(set_matches.value_of("path").unwrap(), policy)
+                }),
+            ("clear", Some(clear_matches)) => policy::set_policy(
+                clear_matches.value_of("path").unwrap(),
+                crate::agent::policy::Policy::default(),
+            ),
+            ("show", _) => policy::show_policies(),
+            _ => panic!("subcommand required"),
+        },
+        ("padding", Some(padding_matches)) => match padding_matches.subcommand() {
+            ("set", Some(set_matches)) => {
+                let mode = match set_matches.value_of("mode").unwrap() {
+                    "padme" => crate::agent::vault::Padding::Padme,
+                    "bucket" => crate::agent::vault::Padding::Bucket,
+                    _ => crate::agent::vault::Padding::None,
+                };
+                set_matches
+                    .value_of("bucket-size")
+                    .map(|size| match size.parse::<u64>() {
+                        Ok(size) if size > 0 => Ok(size),
+                        _ => Err(format!("Invalid bucket size: {}", size)),
+                    })
+                    .unwrap_or(Ok(crate::constants::DEFAULT_PADDING_BUCKET_SIZE))
+                    .and_then(|bucket_size| {
+                        policy::set_padding(crate::agent::vault::PaddingRule { mode, bucket_size })
+                    })
+            }
+            ("show", _) => policy::show_padding(),
+            _ => panic!("subcommand required"),
+        },
+        ("threshold", Some(threshold_matches)) => match threshold_matches.subcommand() {
+            ("set", Some(set_matches)) => set_matches
+                .value_of("required")
+                .unwrap()
+                .parse::<u8>()
+                .map_err(|e| format!("Invalid number of keys: {}", e))
+                .and_then(|required| {
+                    threshold::set_threshold(
+                        set_matches.value_of("path").unwrap(),
+                        required,
+                        set_matches
+                            .values_of("key")
+                            .unwrap()
+                            .map(|k| k.to_string())
+                            .collect(),
+                    )
+                }),
+            ("clear", Some(clear_matches)) => {
+                threshold::set_threshold(clear_matches.value_of("path").unwrap(), 0, Vec::new())
+            }
+            ("show", _) => threshold::show_thresholds(),
+            ("share", Some(share_matches)) => threshold::share(
+                share_matches.value_of("path").unwrap(),
+                share_matches.value_of("for").unwrap(),
+                share_matches.value_of("file").unwrap(),
+            ),
+            _ => panic!("subcommand required"),
+        },
+        ("writers", Some(writers_matches)) => match writers_matches.subcommand() {
+            ("set", Some(set_matches)) => writers::set_writers(
+                set_matches.value_of("path").unwrap(),
+                set_matches
+                    .values_of("key")
+                    .unwrap()
+                    .map(|k| k.to_string())
+                    .collect(),
+            ),
+            ("clear", Some(clear_matches)) => {
+                writers::set_writers(clear_matches.value_of("path").unwrap(), Vec::new())
+            }
+            _ => panic!("subcommand required"),
+        },
+        ("git", Some(git_matches)) => match git_matches.subcommand() {
+            ("init", Some(init_matches)) => git::init(init_matches.value_of("remote")),
+            ("clone", Some(clone_matches)) => git::clone(clone_matches.value_of("url").unwrap()),
+            ("push", _) => git::push(),
+            ("pull", _) => git::pull(),
+            ("log", _) => git::log(),
+            _ => panic!("subcommand required"),
+        },
+        ("git-merge-driver", Some(merge_matches)) => git::merge_driver(
+            merge_matches.value_of("base").unwrap(),
+            merge_matches.value_of("ours").unwrap(),
+            merge_matches.value_of("theirs").unwrap(),
+            merge_matches.value_of("path").unwrap(),
+        ),
+        ("git-textconv", Some(textconv_matches)) => {
+            git::textconv(textconv_matches.value_of("file").unwrap())
+        }
         ("agent", Some(agent_matches)) => match agent_matches.subcommand() {
             ("reload", _) => agent_cmd::reload_agent(),
             ("quit", _) => agent_cmd::kill_agent(),
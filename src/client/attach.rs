@@ -0,0 +1,86 @@
+use crate::agent;
+use crate::agent::command;
+use crate::agent::secure::SecretBytes;
+use crate::agent::vault;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+fn send_file(
+    source: &mut dyn Read,
+    socket: &mut std::os::unix::net::UnixStream,
+) -> Result<(), String> {
+    let mut writer = agent::FrameWriter::new(socket);
+    let mut buf = SecretBytes::new(vec![0; vault::CHUNK_SIZE]);
+    loop {
+        let n = source
+            .read(&mut buf)
+            .map_err(|e| format!("Unable to read file: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        writer
+            .write_all(&buf[..n])
+            .map_err(|e| format!("Unable to send file to agent: {}", e))?;
+    }
+    writer.finish()
+}
+
+// "-" reads the file from stdin
+pub fn attach_file(path: &str, file: &str) -> Result<(), String> {
+    let cmd = command::Command::Attach(command::AttachRequest::new(path.to_string()));
+    let resp = if file == "-" {
+        super::send_stream_request(cmd, |socket| send_file(&mut io::stdin(), socket))
+    } else {
+        let mut source = File::open(file).map_err(|e| format!("Unable to open {}: {}", file, e))?;
+        super::send_stream_request(cmd, |socket| send_file(&mut source, socket))
+    }?;
+    match resp {
+        command::Response::Attach(size) => {
+            println!("Attached {} bytes at {}", size, path);
+            Ok(())
+        }
+        _ => Err("Agent response is malformed".to_string()),
+    }
+}
+
+fn receive_file(
+    socket: &mut std::os::unix::net::UnixStream,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let mut reader = agent::FrameReader::new(socket);
+    io::copy(&mut reader, out)
+        .map(|_| ())
+        .map_err(|e| format!("Unable to write extracted file: {}", e))
+}
+
+// The contents only go to output once the whole transfer succeeded, a transfer cut short
+// is scrubbed.
+pub fn extract_file(path: &str, output: &str) -> Result<(), String> {
+    let cmd = command::Command::Extract(command::ExtractRequest::new(path.to_string()));
+    let output = Path::new(output);
+    let output_dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp_file = NamedTempFile::new_in(output_dir)
+        .map_err(|e| format!("Unable to create temporary file: {}", e))?;
+    let res =
+        super::send_stream_request(cmd, |socket| receive_file(socket, tmp_file.as_file_mut()));
+    if let Err(err) = res {
+        if let Err(remove_err) = super::scrub_file(tmp_file.path()) {
+            eprintln!("{}", remove_err);
+        }
+        return Err(err);
+    }
+    tmp_file
+        .persist(output)
+        .map_err(|e| format!("Unable to write {}: {}", output.display(), e))?;
+    let size = fs::metadata(output).map(|m| m.len()).unwrap_or(0);
+    println!("Extracted {} bytes to {}", size, output.display());
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::agent::audit;
+use crate::agent::timestamp;
+
+pub struct AuditFilter {
+    pub path: Option<String>,
+    pub key: Option<String>,
+    pub since: Option<u128>,
+    pub until: Option<u128>,
+}
+
+impl AuditFilter {
+    pub fn new(
+        path: Option<&str>,
+        key: Option<&str>,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(AuditFilter {
+            path: path.map(|p| p.to_string()),
+            key: key.map(|k| k.to_string()),
+            since: since.map(timestamp::parse).transpose()?,
+            until: until.map(timestamp::parse).transpose()?,
+        })
+    }
+
+    fn matches(&self, entry: &audit::AuditEntry) -> bool {
+        if self.path.is_some() && entry.path != self.path {
+            return false;
+        }
+        if let Some(key) = self.key.as_ref() {
+            if !entry.keys.contains(key) {
+                return false;
+            }
+        }
+        if self.since.is_some_and(|since| entry.timestamp < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| entry.timestamp > until) {
+            return false;
+        }
+        true
+    }
+}
+
+fn format_entry(entry: &audit::AuditEntry) -> String {
+    let event = match entry.event {
+        audit::AuditEvent::Encrypt => "encrypt",
+        audit::AuditEvent::Decrypt => "decrypt",
+        audit::AuditEvent::KeyUnlock => "key-unlock",
+        audit::AuditEvent::KeyAdded => "key-added",
+        audit::AuditEvent::PolicyChange => "policy-change",
+        audit::AuditEvent::Remove => "remove",
+        audit::AuditEvent::Restore => "restore",
+        audit::AuditEvent::Purge => "purge",
+    };
+    let mut line = format!(
+        "{} #{} {} {}",
+        timestamp::format(entry.timestamp),
+        entry.seq,
+        event,
+        if entry.success { "ok" } else { "FAILED" }
+    );
+    if let Some(path) = entry.path.as_ref() {
+        line += &format!(" path={}", path);
+    }
+    if !entry.keys.is_empty() {
+        line += &format!(" keys={}", entry.keys.join(","));
+    }
+    if let Some(peer) = entry.peer.as_ref() {
+        line += &format!(" pid={} uid={}", peer.pid, peer.uid);
+        if let Some(process) = peer.process.as_ref() {
+            line += &format!(" process={}", process);
+        }
+    }
+    if let Some(detail) = entry.detail.as_ref() {
+        line += &format!(" ({})", detail);
+    }
+    line
+}
+
+pub fn show(filter: AuditFilter) -> Result<(), String> {
+    let entries = audit::read_entries()?;
+    for entry in entries.iter().filter(|e| filter.matches(e)) {
+        println!("{}", format_entry(entry));
+    }
+    Ok(())
+}
+
+pub fn verify() -> Result<(), String> {
+    let entries = audit::read_entries()?;
+    let count = audit::verify_chain(&entries)?;
+    println!("Audit log intact: {} entries verified", count);
+    Ok(())
+}
+
+pub fn repin() -> Result<(), String> {
+    let entries = audit::read_entries()?;
+    let (previous, pinned) = audit::repin(&entries)?;
+    match previous {
+        Some(previous) if previous == pinned => {
+            println!("Audit log head already pinned at entry {}", pinned)
+        }
+        Some(previous) => println!(
+            "Audit log head pinned at entry {} instead of entry {}",
+            pinned, previous
+        ),
+        None => println!("Audit log head pinned at entry {}", pinned),
+    }
+    Ok(())
+}
@@ -1,13 +1,21 @@
 use crate::agent::command;
+use crate::agent::secure::SecretBytes;
+use crate::agent::threshold;
 
-pub fn decrypt_path(path: &str) -> Result<Vec<u8>, String> {
-    let cmd = command::Command::Decrypt(command::DecryptRequest::new(path.to_string()));
+// also returns the author the agent verified the secret was written by
+pub fn decrypt_path(
+    path: &str,
+    rev: Option<u64>,
+    shares: Vec<threshold::ShareFile>,
+) -> Result<(SecretBytes, Option<String>), String> {
+    let cmd =
+        command::Command::Decrypt(command::DecryptRequest::new(path.to_string(), rev, shares));
     let cmds = vec![cmd];
 
     let resp = super::send_requests(&cmds);
     let resp = super::process_unary_response(resp)?;
     match resp {
-        command::Response::Decrypt(contents) => Ok(contents),
+        command::Response::Decrypt(contents, author) => Ok((contents, author)),
         _ => Err("Agent response is malformed".to_string()),
     }
 }
@@ -0,0 +1,60 @@
+use super::confirm;
+use super::keys::parse_kdf_strength;
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::device;
+use crate::agent::timestamp;
+use std::fs;
+use std::io::Write;
+
+pub fn request(name: &str, file: &str, level: &str) -> Result<(), String> {
+    let kdf = parse_kdf_strength(level)?;
+    let cmd = command::Command::Enroll(command::EnrollRequest::new(name.to_string(), kdf));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let request = match resp {
+        command::Response::Enroll(request) => request,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    let json = serde_json::to_vec_pretty(&request)
+        .map_err(|e| format!("Unable to serialize enrollment request: {}", e))?;
+    if file == "-" {
+        std::io::stdout()
+            .write_all(&json)
+            .map_err(|e| format!("Unable to write enrollment request: {}", e))?;
+    } else {
+        fs::write(file, &json).map_err(|e| format!("Unable to write {}: {}", file, e))?;
+        eprintln!("Wrote the enrollment request of {} to {}", name, file);
+    }
+    eprintln!("Verification code: {}", request.verification_code());
+    eprintln!("Approve it on an enrolled machine with basalt device approve and compare the codes");
+    Ok(())
+}
+
+pub fn approve(file: &str, like: Option<&str>) -> Result<(), String> {
+    let json = fs::read(file).map_err(|e| format!("Unable to read {}: {}", file, e))?;
+    let request: device::EnrollmentRequest = serde_json::from_slice(&json)
+        .map_err(|e| format!("Unable to parse enrollment request: {}", e))?;
+    let enrollment = request.open()?;
+    println!("Key:         {}", enrollment.name);
+    println!("Fingerprint: {}", enrollment.key.fingerprint());
+    println!("Requested:   {}", timestamp::format(enrollment.created));
+    println!("Verification code: {}", request.verification_code());
+    if !confirm("Does the new machine show the same code?") {
+        return Err("Enrollment not approved".to_string());
+    }
+
+    let cmd = command::Command::ApproveDevice(command::ApproveDeviceRequest::new(
+        request,
+        like.map(|l| l.to_string()),
+    ));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let (reencrypted, failed) = match resp {
+        command::Response::ApproveDevice(reencrypted, failed) => (reencrypted, failed),
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    println!(
+        "Added key {}, {} vaults encrypted for it",
+        enrollment.name, reencrypted
+    );
+    super::report_failures(&failed)
+}
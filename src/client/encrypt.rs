@@ -1,4 +1,5 @@
 use crate::agent::command;
+use crate::agent::secure::SecretBytes;
 use crate::constants;
 use std::env;
 use std::fs;
@@ -21,7 +22,7 @@ pub fn get_tmp_file() -> Result<NamedTempFile, String> {
     NamedTempFile::new().map_err(|e| format!("Unable to allocated temporary file: {}", e))
 }
 
-pub fn edit_tmp_file(file: NamedTempFile) -> Result<Vec<u8>, String> {
+pub fn edit_tmp_file(file: NamedTempFile) -> Result<SecretBytes, String> {
     let edit_cmd = Command::new(get_editor())
         .arg(file.path().as_os_str())
         .status()
@@ -33,10 +34,12 @@ pub fn edit_tmp_file(file: NamedTempFile) -> Result<Vec<u8>, String> {
         ));
     }
 
-    fs::read(file.path()).map_err(|e| format!("Unable to read from temp file: {}", e))
+    fs::read(file.path())
+        .map(SecretBytes::new)
+        .map_err(|e| format!("Unable to read from temp file: {}", e))
 }
 
-pub fn encrypt_contents(path: &str, contents: Vec<u8>) -> Result<(), String> {
+pub fn encrypt_contents(path: &str, contents: SecretBytes) -> Result<(), String> {
     let cmd = command::Command::Encrypt(command::EncryptRequest::new(path.to_string(), contents));
     let cmds = vec![cmd];
 
@@ -0,0 +1,179 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::durable;
+use crate::agent::git;
+use crate::config;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+const CLONE_DIR_NAME: &str = ".store.clone";
+
+// runs git in the store directory with its output going to the terminal
+fn run_git(args: &[&str]) -> Result<(), String> {
+    let status = git::command()
+        .args(args)
+        .status()
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+    if !status.success() {
+        return Err(format!("git {} failed", args.first().unwrap_or(&"")));
+    }
+    Ok(())
+}
+
+// points the merge driver and diff converter named in .gitattributes at this binary, git keeps
+// them per clone
+fn configure_merge_driver() -> Result<(), String> {
+    let exe = env::current_exe().map_err(|e| format!("Unable to find basalt: {}", e))?;
+    let name_key = format!("merge.{}.name", git::MERGE_DRIVER);
+    let driver_key = format!("merge.{}.driver", git::MERGE_DRIVER);
+    let textconv_key = format!("diff.{}.textconv", git::MERGE_DRIVER);
+    let driver = format!(
+        "'{}' git-merge-driver --base %O --ours %A --theirs %B --path %P",
+        exe.display()
+    );
+    let textconv = format!("'{}' git-textconv", exe.display());
+    git::run(&["config", &name_key, "basalt vault merge"])?;
+    git::run(&["config", &driver_key, &driver])?;
+    git::run(&["config", &textconv_key, &textconv])?;
+    Ok(())
+}
+
+fn require_repository() -> Result<(), String> {
+    if !git::is_repository() {
+        return Err("The store is not a git repository, see basalt git init".to_string());
+    }
+    Ok(())
+}
+
+// turns the existing store into a repository with everything in it committed
+pub fn init(remote: Option<&str>) -> Result<(), String> {
+    if git::is_repository() {
+        return Err("The store is already a git repository".to_string());
+    }
+    let _lock = durable::StoreLock::acquire()?;
+    git::run(&["init", "--quiet"])?;
+    configure_merge_driver()?;
+    git::write_gitfiles()?;
+    git::commit("Share the store through git")?;
+    if let Some(remote) = remote {
+        git::run(&["remote", "add", "origin", remote])?;
+    }
+    println!(
+        "Initialized a git repository in {}",
+        config::get_store_directory().display()
+    );
+    Ok(())
+}
+
+// the store must be empty, the clone is made next to it and then moved in
+pub fn clone(url: &str) -> Result<(), String> {
+    let _lock = durable::StoreLock::acquire()?;
+    let store_dir = config::get_store_directory();
+    let entries: Vec<fs::DirEntry> = store_dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read store: {}", e))?
+        .filter_map(|e| e.ok())
+        .collect();
+    // the store lock is the only file an empty store has
+    if entries.iter().any(|e| e.path() != durable::lock_path()) {
+        return Err(format!("The store at {} is not empty", store_dir.display()));
+    }
+    let clone_dir = config::get_app_dir().join(CLONE_DIR_NAME);
+    if clone_dir.exists() {
+        fs::remove_dir_all(&clone_dir)
+            .map_err(|e| format!("Unable to remove {}: {}", clone_dir.display(), e))?;
+    }
+    let status = process::Command::new("git")
+        .arg("clone")
+        .arg(url)
+        .arg(&clone_dir)
+        .status()
+        .map_err(|e| format!("Unable to run git: {}", e))?;
+    if !status.success() {
+        let _ = fs::remove_dir_all(&clone_dir);
+        return Err("git clone failed".to_string());
+    }
+    let cloned = clone_dir
+        .read_dir()
+        .map_err(|e| format!("Unable to read clone: {}", e))?;
+    for entry in cloned {
+        let entry = entry.map_err(|e| format!("Unable to read clone: {}", e))?;
+        fs::rename(entry.path(), store_dir.join(entry.file_name()))
+            .map_err(|e| format!("Unable to move clone into the store: {}", e))?;
+    }
+    fs::remove_dir(&clone_dir)
+        .map_err(|e| format!("Unable to remove {}: {}", clone_dir.display(), e))?;
+    configure_merge_driver()?;
+    git::write_gitfiles()?;
+    println!("Cloned {} into {}", url, store_dir.display());
+    Ok(())
+}
+
+pub fn push() -> Result<(), String> {
+    require_repository()?;
+    run_git(&["push", "--set-upstream", "origin", "HEAD"])
+}
+
+// the agent notices a keychain or index that changed on disk and reloads it
+pub fn pull() -> Result<(), String> {
+    require_repository()?;
+    let _lock = durable::StoreLock::acquire()?;
+    configure_merge_driver()?;
+    // stores shared before vaults were merged only had the keychain driver
+    let _ = git::run(&["config", "--remove-section", "merge.basalt-keychain"]);
+    git::write_gitfiles()?;
+    git::commit("Merge and diff store files with basalt")?;
+    run_git(&["pull", "--no-rebase", "--no-edit"])
+}
+
+pub fn log() -> Result<(), String> {
+    require_repository()?;
+    run_git(&["log", "--date=iso", "--format=%h %ad %an%n    %s"])
+}
+
+fn absolute(file: &str) -> Result<PathBuf, String> {
+    fs::canonicalize(Path::new(file)).map_err(|e| format!("Unable to find {}: {}", file, e))
+}
+
+// run by git as the merge driver of the store, conflicts make git leave the file unmerged
+pub fn merge_driver(base: &str, ours: &str, theirs: &str, path: &str) -> Result<(), String> {
+    let cmd = command::Command::GitMerge(command::GitMergeRequest::new(
+        absolute(base)?,
+        absolute(ours)?,
+        absolute(theirs)?,
+        path.to_string(),
+    ));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let conflicts = match resp {
+        command::Response::GitMerge(conflicts) => conflicts,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    for conflict in conflicts.iter() {
+        eprintln!("{}", conflict);
+    }
+    Err(format!(
+        "{} conflicts left in {}, resolve them with basalt show and attach, then git add it",
+        conflicts.len(),
+        path
+    ))
+}
+
+// run by git diff to show a vault as its contents
+pub fn textconv(file: &str) -> Result<(), String> {
+    let cmd = command::Command::Textconv(command::TextconvRequest::new(absolute(file)?));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let text = match resp {
+        command::Response::Textconv(text) => text,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    std::io::stdout()
+        .write_all(&text)
+        .map_err(|e| format!("Unable to write diff: {}", e))
+}
@@ -0,0 +1,56 @@
+use super::send_requests;
+use crate::agent::command;
+
+fn change_group(cmd: command::Command, done: &str) -> Result<(), String> {
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let (reencrypted, failed) = match resp {
+        command::Response::Group(reencrypted, failed) => (reencrypted, failed),
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    println!("{}, {} vaults encrypted again", done, reencrypted);
+    super::report_failures(&failed)
+}
+
+pub fn create_group(name: &str, keys: Vec<String>) -> Result<(), String> {
+    let cmd = command::Command::GroupCreate(command::GroupRequest::new(name.to_string(), keys));
+    change_group(cmd, &format!("Created group {}", name))
+}
+
+pub fn add_to_group(name: &str, keys: Vec<String>) -> Result<(), String> {
+    let cmd = command::Command::GroupAdd(command::GroupRequest::new(name.to_string(), keys));
+    change_group(cmd, &format!("Added keys to group {}", name))
+}
+
+// without keys the group itself is removed
+pub fn remove_from_group(name: &str, keys: Vec<String>) -> Result<(), String> {
+    let done = if keys.is_empty() {
+        format!("Removed group {}", name)
+    } else {
+        format!("Removed keys from group {}", name)
+    };
+    let cmd = command::Command::GroupRemove(command::GroupRequest::new(name.to_string(), keys));
+    change_group(cmd, &done)
+}
+
+pub fn show_groups(name: Option<&str>) -> Result<(), String> {
+    let cmd =
+        command::Command::GroupShow(command::GroupShowRequest::new(name.map(|n| n.to_string())));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let groups = match resp {
+        command::Response::GroupShow(groups) => groups,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if groups.is_empty() {
+        println!("The keychain has no groups");
+    }
+    for group in groups.iter() {
+        println!("{}: {}", group.name, group.keys.join(", "));
+        if !group.paths.is_empty() {
+            println!("    reads {}", group.paths.join(", "));
+        }
+        if !group.writers.is_empty() {
+            println!("    writes {}", group.writers.join(", "));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::timestamp;
+
+pub fn parse_rev(rev: &str) -> Result<u64, String> {
+    rev.parse::<u64>()
+        .map_err(|_| format!("Invalid revision: {}", rev))
+}
+
+pub fn show_history(path: &str) -> Result<(), String> {
+    let cmd = command::Command::History(command::HistoryRequest::new(path.to_string()));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let revisions = match resp {
+        command::Response::History(revisions) => revisions,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if revisions.is_empty() {
+        println!("No earlier revisions of {}", path);
+        return Ok(());
+    }
+    for revision in revisions.iter().rev() {
+        println!(
+            "{:>4}  {}  {}",
+            revision.rev,
+            revision
+                .timestamp
+                .map_or("unknown time".to_string(), timestamp::format),
+            revision.author.as_deref().unwrap_or("unsigned"),
+        );
+    }
+    Ok(())
+}
+
+pub fn restore_revision(path: &str, rev: &str) -> Result<(), String> {
+    let rev = parse_rev(rev)?;
+    let cmd = command::Command::Restore(command::RestoreRequest::new(path.to_string(), rev));
+    super::process_unary_response_ignore(send_requests(&[cmd]))?;
+    println!("Restored revision {} of {}", rev, path);
+    Ok(())
+}
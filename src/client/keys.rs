@@ -1,7 +1,13 @@
+use super::confirm;
 use super::prompt_user;
 use super::send_requests;
 use super::user_menu;
 use crate::agent::command;
+use crate::agent::private::KdfStrength;
+use crate::agent::public;
+use std::fs;
+use std::io;
+use std::io::Write;
 
 pub fn add_key() -> Result<(), String> {
     let key_name = prompt_user("Please enter a name for your new key");
@@ -16,13 +22,26 @@ pub fn add_key() -> Result<(), String> {
         _ => return Err("Unknown key type".to_string()),
     };
 
+    let kdf = if key_type == command::KeyType::Sodium {
+        let kdf_choices = vec!["Interactive", "Moderate", "Sensitive"];
+        let kdf_input = user_menu(
+            "Please choose how hard the PIN is to brute force (slower to unlock when harder)",
+            &kdf_choices,
+            Some(0),
+        );
+        parse_kdf_strength(&kdf_choices[kdf_input].to_lowercase())?
+    } else {
+        KdfStrength::Interactive
+    };
+
     println!("key type is: {}", key_type_input);
     let cmd = command::Command::AddKey(command::AddKeyRequest::new(
         key_name.clone(),
         key_type.clone(),
+        kdf,
     ));
 
-    let resp = send_requests(&vec![cmd]);
+    let resp = send_requests(&[cmd]);
     let ret = super::process_unary_response(resp);
     if ret.is_ok() {
         println!("Successfully added key: {}", key_name);
@@ -30,7 +49,7 @@ pub fn add_key() -> Result<(), String> {
     if key_type == command::KeyType::PaperKey {
         match ret.unwrap() {
             command::Response::AddKey(Some(paperkey)) => {
-                println!("Your paper key: {}", paperkey);
+                println!("Your paper key: {}", &*paperkey);
                 println!("Please save that in a safe place!");
                 Ok(())
             }
@@ -40,3 +59,105 @@ pub fn add_key() -> Result<(), String> {
         ret.map(|_| ())
     }
 }
+
+pub fn parse_kdf_strength(level: &str) -> Result<KdfStrength, String> {
+    match level {
+        "interactive" => Ok(KdfStrength::Interactive),
+        "moderate" => Ok(KdfStrength::Moderate),
+        "sensitive" => Ok(KdfStrength::Sensitive),
+        _ => Err(format!("Unknown KDF level: {}", level)),
+    }
+}
+
+pub fn upgrade_kdf(key_name: &str, level: &str) -> Result<(), String> {
+    let kdf = parse_kdf_strength(level)?;
+    let cmd =
+        command::Command::UpgradeKdf(command::UpgradeKdfRequest::new(key_name.to_string(), kdf));
+
+    let resp = send_requests(&[cmd]);
+    super::process_unary_response_ignore(resp)?;
+    println!("Successfully upgraded key: {}", key_name);
+    Ok(())
+}
+
+pub fn add_signing_key(key_name: &str) -> Result<(), String> {
+    let cmd =
+        command::Command::AddSigningKey(command::AddSigningKeyRequest::new(key_name.to_string()));
+
+    let resp = send_requests(&[cmd]);
+    super::process_unary_response_ignore(resp)?;
+    println!("Successfully added a signing key to key: {}", key_name);
+    Ok(())
+}
+
+// writes the public key to file, or stdout when there is none
+pub fn export_key(key_name: &str, file: Option<&str>) -> Result<(), String> {
+    let cmd = command::Command::ExportKey(command::ExportKeyRequest::new(key_name.to_string()));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let exported = match resp {
+        command::Response::ExportKey(exported) => exported,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    let mut json = serde_json::to_vec_pretty(&exported)
+        .map_err(|e| format!("Unable to serialize key: {}", e))?;
+    json.push(b'\n');
+    match file {
+        Some(file) => {
+            fs::write(file, &json).map_err(|e| format!("Unable to write {}: {}", file, e))?;
+            println!("Exported key {} to {}", key_name, file);
+            println!("Fingerprint: {}", exported.fingerprint);
+            Ok(())
+        }
+        None => io::stdout()
+            .write_all(&json)
+            .map_err(|e| format!("Unable to write key: {}", e)),
+    }
+}
+
+pub fn import_key(file: &str) -> Result<(), String> {
+    let json = fs::read(file).map_err(|e| format!("Unable to read {}: {}", file, e))?;
+    let exported: public::ExportedKey =
+        serde_json::from_slice(&json).map_err(|e| format!("Unable to parse key: {}", e))?;
+    exported.check()?;
+    println!("Key:         {}", exported.name);
+    println!("Type:        {}", exported.key_type);
+    println!("Fingerprint: {}", exported.fingerprint);
+
+    // a key of the same name would be replaced, and whatever it could read with it
+    let cmd = command::Command::ExportKey(command::ExportKeyRequest::new(exported.name.clone()));
+    let existing = match super::process_unary_response(send_requests(&[cmd])) {
+        Ok(command::Response::ExportKey(existing)) => Some(existing),
+        _ => None,
+    };
+    let replace = match existing {
+        Some(existing) if existing.fingerprint == exported.fingerprint => {
+            println!("Key {} is already in the keychain", exported.name);
+            return Ok(());
+        }
+        Some(existing) => {
+            eprintln!(
+                "WARNING: the keychain has another key named {}, fingerprint {}",
+                existing.name, existing.fingerprint
+            );
+            eprintln!("Importing replaces it, and vaults are encrypted for the new key instead");
+            true
+        }
+        None => false,
+    };
+    if !confirm("Is this the fingerprint the owner of the key gave you?") {
+        return Err("Key not imported".to_string());
+    }
+
+    let name = exported.name.clone();
+    let cmd = command::Command::ImportKey(command::ImportKeyRequest::new(exported, replace));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let (reencrypted, failed) = match resp {
+        command::Response::ImportKey(reencrypted, failed) => (reencrypted, failed),
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    println!(
+        "Imported key {}, {} vaults encrypted for it",
+        name, reencrypted
+    );
+    super::report_failures(&failed)
+}
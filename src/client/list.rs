@@ -0,0 +1,16 @@
+use super::send_requests;
+use crate::agent::command;
+
+pub fn list_paths(path: Option<&str>) -> Result<(), String> {
+    let cmd = command::Command::List(command::ListRequest::new(path.unwrap_or("").to_string()));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    match resp {
+        command::Response::List(paths) => {
+            for path in paths {
+                println!("{}", path);
+            }
+            Ok(())
+        }
+        _ => Err("Agent response is malformed".to_string()),
+    }
+}
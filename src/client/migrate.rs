@@ -0,0 +1,24 @@
+use super::send_requests;
+use crate::agent::command;
+
+pub fn migrate_store(dry_run: bool, hide_paths: bool) -> Result<(), String> {
+    let cmd = command::Command::Migrate(command::MigrateRequest::new(dry_run, hide_paths));
+    let resp = send_requests(&[cmd]);
+    let migrated = match super::process_unary_response(resp)? {
+        command::Response::Migrate(migrated) => migrated,
+        _ => return Err("Unexpected response from agent (expected migrate response)".to_string()),
+    };
+
+    if migrated.is_empty() {
+        println!("All vaults are already in the current format");
+        return Ok(());
+    }
+    for path in migrated.iter() {
+        if dry_run {
+            println!("would migrate: {}", path);
+        } else {
+            println!("migrated: {}", path);
+        }
+    }
+    Ok(())
+}
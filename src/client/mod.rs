@@ -1,15 +1,32 @@
+pub mod access;
 pub mod agent_cmd;
 pub mod app;
+pub mod attach;
+pub mod audit;
 pub mod decrypt;
+pub mod device;
 pub mod encrypt;
+pub mod git;
+pub mod group;
+pub mod history;
 pub mod keys;
+pub mod list;
+pub mod migrate;
+pub mod policy;
+pub mod threshold;
+pub mod trash;
+pub mod writers;
 
 use crate::agent;
 use crate::config;
 use agent::command;
+use std::fs;
+use std::fs::OpenOptions;
 use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 
 fn get_agent_stream() -> Result<UnixStream, String> {
     let socket_path = config::get_agent_socket_file();
@@ -36,6 +53,19 @@ fn send_requests(reqs: &[command::Command]) -> Vec<Result<command::Response, Str
     resp.unwrap()
 }
 
+// sends a single command whose data is transferred right after it, see agent::FrameReader
+fn send_stream_request<F>(cmd: command::Command, transfer: F) -> Result<command::Response, String>
+where
+    F: FnOnce(&mut UnixStream) -> Result<(), String>,
+{
+    let mut socket = get_agent_stream()?;
+    agent::write_message(&mut socket, &[cmd])?;
+    // on failure the socket is dropped mid-transfer, which makes the agent abort as well
+    transfer(&mut socket)?;
+    let resp = agent::parse_message(&mut socket)?;
+    process_unary_response(resp)
+}
+
 fn process_unary_response(
     mut resp: Vec<Result<command::Response, String>>,
 ) -> Result<command::Response, String> {
@@ -73,6 +103,38 @@ fn prompt_user(prompt: &str) -> String {
     read_line()
 }
 
+// the vaults the agent could not encrypt again after the keychain changed
+fn report_failures(failed: &[String]) -> Result<(), String> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    for failure in failed.iter() {
+        eprintln!("{}", failure);
+    }
+    Err(format!(
+        "{} vaults could not be encrypted again, re-encrypt them from a machine that can read them",
+        failed.len()
+    ))
+}
+
+// zeroes a file holding secret contents before removing it
+fn scrub_file(path: &Path) -> Result<(), String> {
+    let scrub = || -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let len = file.metadata()?.len();
+        io::copy(&mut io::repeat(0).take(len), &mut file)?;
+        file.sync_all()?;
+        fs::remove_file(path)
+    };
+    scrub().map_err(|e| format!("Unable to scrub {}: {}", path.display(), e))
+}
+
+// a yes or no question, anything but yes is no
+fn confirm(prompt: &str) -> bool {
+    let answer = prompt_user(&format!("{} [y/N]", prompt));
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
+
 fn user_menu(prompt: &str, choices: &[&str], default: Option<usize>) -> usize {
     println!("{}", prompt);
     for (idx, choice) in choices.iter().enumerate() {
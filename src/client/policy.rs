@@ -0,0 +1,56 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::policy;
+use crate::agent::vault;
+
+pub fn set_policy(path: &str, policy: policy::Policy) -> Result<(), String> {
+    let cleared = policy.is_empty();
+    let cmd = command::Command::SetPolicy(command::SetPolicyRequest::new(path.to_string(), policy));
+    let resp = send_requests(&[cmd]);
+    super::process_unary_response_ignore(resp)?;
+    if cleared {
+        println!("Removed the policy of {}", path);
+    } else {
+        println!("Updated the policy of {}", path);
+    }
+    Ok(())
+}
+
+pub fn show_policies() -> Result<(), String> {
+    let resp = super::process_unary_response(send_requests(&[command::Command::PolicyShow]))?;
+    let policies = match resp {
+        command::Response::PolicyShow(policies) => policies,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if policies.is_empty() {
+        println!("The keychain has no policies");
+    }
+    for (pattern, policy) in policies.iter() {
+        let pattern = if pattern.is_empty() {
+            "(whole store)"
+        } else {
+            pattern
+        };
+        println!("{}: {}", pattern, policy.describe());
+    }
+    Ok(())
+}
+
+pub fn set_padding(padding: vault::PaddingRule) -> Result<(), String> {
+    let cmd = command::Command::SetPadding(command::SetPaddingRequest::new(padding));
+    super::process_unary_response_ignore(send_requests(&[cmd]))?;
+    println!(
+        "Padding of the vaults written from now on: {}",
+        padding.describe()
+    );
+    Ok(())
+}
+
+pub fn show_padding() -> Result<(), String> {
+    let resp = super::process_unary_response(send_requests(&[command::Command::PaddingShow]))?;
+    match resp {
+        command::Response::PaddingShow(padding) => println!("Padding: {}", padding.describe()),
+        _ => return Err("Agent response is malformed".to_string()),
+    }
+    Ok(())
+}
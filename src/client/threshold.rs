@@ -0,0 +1,83 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::threshold;
+use std::fs;
+use std::path::Path;
+
+pub fn set_threshold(path: &str, required: u8, keys: Vec<String>) -> Result<(), String> {
+    let cleared = keys.is_empty();
+    let cmd = command::Command::SetThreshold(command::SetThresholdRequest::new(
+        path.to_string(),
+        required,
+        keys,
+    ));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let (reencrypted, failed) = match resp {
+        command::Response::SetThreshold(reencrypted, failed) => (reencrypted, failed),
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if cleared {
+        println!("Removed the threshold of {}", path);
+    } else {
+        println!("{} now takes {} keys to open", path, required);
+    }
+    println!("{} vaults encrypted again", reencrypted);
+    super::report_failures(&failed)
+}
+
+pub fn show_thresholds() -> Result<(), String> {
+    let cmd = command::Command::ThresholdShow;
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let thresholds = match resp {
+        command::Response::ThresholdShow(thresholds) => thresholds,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    if thresholds.is_empty() {
+        println!("The keychain has no thresholds");
+    }
+    for (path, rule) in thresholds.iter() {
+        println!("{}: {}", path, rule.describe());
+    }
+    Ok(())
+}
+
+pub fn share(path: &str, recipient: &str, file: &str) -> Result<(), String> {
+    let cmd = command::Command::ThresholdShare(command::ThresholdShareRequest::new(
+        path.to_string(),
+        recipient.to_string(),
+    ));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    let share = match resp {
+        command::Response::ThresholdShare(share) => share,
+        _ => return Err("Agent response is malformed".to_string()),
+    };
+    let json = serde_json::to_vec_pretty(&share)
+        .map_err(|e| format!("Unable to serialize share: {}", e))?;
+    fs::write(file, &json).map_err(|e| format!("Unable to write {}: {}", file, e))?;
+    println!(
+        "Wrote the share of {} held by {} to {}, sealed for {}",
+        path, share.holder, file, recipient
+    );
+    Ok(())
+}
+
+pub fn read_share_files(files: &[&str]) -> Result<Vec<threshold::ShareFile>, String> {
+    let mut shares = Vec::new();
+    for file in files.iter() {
+        let json = fs::read(file).map_err(|e| format!("Unable to read {}: {}", file, e))?;
+        let share = serde_json::from_slice(&json)
+            .map_err(|e| format!("Unable to parse share {}: {}", file, e))?;
+        shares.push(share);
+    }
+    Ok(shares)
+}
+
+// shares are spent once they opened the vault, and kept for another try until then
+pub fn scrub_share_files(files: &[&str]) {
+    for file in files.iter() {
+        match super::scrub_file(Path::new(file)) {
+            Ok(_) => eprintln!("Removed share file {}", file),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use super::send_requests;
+use crate::agent::command;
+use crate::agent::timestamp;
+
+pub fn remove_path(path: &str) -> Result<(), String> {
+    let cmd = command::Command::Remove(command::RemoveRequest::new(path.to_string()));
+    super::process_unary_response_ignore(send_requests(&[cmd]))?;
+    println!("Moved {} to the trash", path);
+    Ok(())
+}
+
+fn print_entries(entries: &[crate::agent::trash::TrashEntry]) {
+    for entry in entries {
+        println!("{}  {}", timestamp::format(entry.removed), entry.path);
+    }
+}
+
+pub fn list_trash() -> Result<(), String> {
+    let resp = super::process_unary_response(send_requests(&[command::Command::TrashList]))?;
+    match resp {
+        command::Response::TrashList(entries) => {
+            if entries.is_empty() {
+                println!("The trash is empty");
+            }
+            print_entries(&entries);
+            Ok(())
+        }
+        _ => Err("Agent response is malformed".to_string()),
+    }
+}
+
+pub fn restore_path(path: &str) -> Result<(), String> {
+    let cmd = command::Command::TrashRestore(command::TrashRestoreRequest::new(path.to_string()));
+    super::process_unary_response_ignore(send_requests(&[cmd]))?;
+    println!("Restored {} from the trash", path);
+    Ok(())
+}
+
+pub fn purge_trash(older_than: Option<&str>) -> Result<(), String> {
+    let older_than = older_than.map(timestamp::parse_duration).transpose()?;
+    let cmd = command::Command::TrashPurge(command::TrashPurgeRequest::new(older_than));
+    let resp = super::process_unary_response(send_requests(&[cmd]))?;
+    match resp {
+        command::Response::TrashPurge(entries) => {
+            print_entries(&entries);
+            println!("Purged {} vaults from the trash", entries.len());
+            Ok(())
+        }
+        _ => Err("Agent response is malformed".to_string()),
+    }
+}
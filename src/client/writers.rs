@@ -0,0 +1,16 @@
+use super::send_requests;
+use crate::agent::command;
+
+pub fn set_writers(path: &str, writers: Vec<String>) -> Result<(), String> {
+    let cleared = writers.is_empty();
+    let cmd =
+        command::Command::SetWriters(command::SetWritersRequest::new(path.to_string(), writers));
+    let resp = send_requests(&[cmd]);
+    super::process_unary_response_ignore(resp)?;
+    if cleared {
+        println!("Any key may now write {}", path);
+    } else {
+        println!("Updated the writers of {}", path);
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
+use crate::agent::logger;
 use crate::constants;
-use dirs;
+use serde::Deserialize;
+use serde::Serialize;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -51,3 +53,162 @@ pub fn get_agent_log_file() -> PathBuf {
     let app_dir = get_app_dir();
     app_dir.join(constants::AGENT_LOG_FILE_NAME)
 }
+
+pub fn get_audit_log_file() -> PathBuf {
+    let app_dir = get_app_dir();
+    app_dir.join(constants::AUDIT_LOG_FILE_NAME)
+}
+
+// the last entry of the audit log, kept outside the log
+pub fn get_audit_head_file() -> PathBuf {
+    let app_dir = get_app_dir();
+    app_dir.join(constants::AUDIT_HEAD_FILE_NAME)
+}
+
+// the last keychain this device accepted, kept outside the store
+pub fn get_keychain_pin_file() -> PathBuf {
+    let app_dir = get_app_dir();
+    app_dir.join(constants::KEYCHAIN_PIN_FILE_NAME)
+}
+
+pub fn get_config_file() -> PathBuf {
+    let app_dir = get_app_dir();
+    app_dir.join(constants::CONFIG_FILE_NAME)
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub pinentry: PinentryConfig,
+    pub pin_policy: PinPolicyConfig,
+    pub log: LogConfig,
+    pub vault: VaultConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinentryConfig {
+    pub program: String,
+    // seconds before pinentry gives up, 0 waits forever
+    pub timeout: u32,
+    pub max_tries: u32,
+}
+
+impl Default for PinentryConfig {
+    fn default() -> Self {
+        PinentryConfig {
+            program: constants::DEFAULT_PINENTRY_PROGRAM.to_string(),
+            timeout: 0,
+            max_tries: constants::DEFAULT_PIN_TRIES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PinPolicyConfig {
+    pub min_length: usize,
+    // estimated strength in bits, see agent::pinpolicy::estimate_entropy
+    pub min_entropy: f64,
+    // extra PINs to refuse on top of the built-in list
+    pub blocklist: Vec<String>,
+    // an empty PIN stores the device key unencrypted when allowed
+    pub allow_unencrypted: bool,
+}
+
+impl Default for PinPolicyConfig {
+    fn default() -> Self {
+        PinPolicyConfig {
+            min_length: constants::DEFAULT_PIN_MIN_LENGTH,
+            min_entropy: constants::DEFAULT_PIN_MIN_ENTROPY,
+            blocklist: Vec::new(),
+            allow_unencrypted: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: logger::Level,
+    // write to agent.log in the app directory
+    pub file: bool,
+    // bytes before agent.log is rotated, 0 never rotates
+    pub max_size: u64,
+    // rotated files to keep
+    pub max_files: u32,
+    // also send entries to syslog (and so journald) through /dev/log
+    pub syslog: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: logger::Level::Info,
+            file: true,
+            max_size: constants::DEFAULT_LOG_MAX_SIZE,
+            max_files: constants::DEFAULT_LOG_MAX_FILES,
+            syslog: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct VaultConfig {
+    // earlier revisions kept of each secret, see agent::history
+    pub history: u32,
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        VaultConfig {
+            history: constants::DEFAULT_HISTORY_REVISIONS,
+        }
+    }
+}
+
+pub fn get_config() -> Result<Config, String> {
+    let config_file = get_config_file();
+    if !config_file.exists() {
+        return Ok(Config::default());
+    }
+
+    let config_bytes = fs::read(&config_file).map_err(|e| {
+        format!(
+            "Unable to read config file {} with err: {}",
+            config_file.display(),
+            e
+        )
+    })?;
+    let config: Config = serde_json::from_slice(&config_bytes).map_err(|e| {
+        format!(
+            "Unable to parse config file {} with err: {}",
+            config_file.display(),
+            e
+        )
+    })?;
+    // no tries at all would look like every PIN prompt was cancelled
+    if config.pinentry.max_tries < 1 {
+        return Err(format!(
+            "Invalid config file {}: pinentry max_tries must be at least 1",
+            config_file.display()
+        ));
+    }
+    Ok(config)
+}
+
+// runs f with the home directory, and so the store, at home. Tests changing it take turns.
+#[cfg(test)]
+pub fn with_home<T>(home: &Path, f: impl FnOnce() -> T) -> T {
+    static HOME_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = HOME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let previous = std::env::var_os("HOME");
+    std::env::set_var("HOME", home);
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    match previous {
+        Some(previous) => std::env::set_var("HOME", previous),
+        None => std::env::remove_var("HOME"),
+    }
+    res.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
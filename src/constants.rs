@@ -1,11 +1,26 @@
 // list of program constants
-pub const APP_DIR_NAME: &'static str = ".basalt";
-pub const APP_NAME: &'static str = "basalt";
-pub const KEY_DIR_NAME: &'static str = "keys";
-pub const STORE_DIR_NAME: &'static str = "store";
-pub const KEYCHAIN_FILE_NAME: &'static str = "keychain.json";
-pub const SOCKET_NAME: &'static str = "agent.socket";
-pub const AGENT_LOG_FILE_NAME: &'static str = "agent.log";
+pub const APP_DIR_NAME: &str = ".basalt";
+pub const APP_NAME: &str = "basalt";
+pub const KEY_DIR_NAME: &str = "keys";
+pub const STORE_DIR_NAME: &str = "store";
+pub const KEYCHAIN_FILE_NAME: &str = "keychain.json";
+// a dotfile, so listings of the store skip it
+pub const KEYCHAIN_PREVIOUS_FILE_NAME: &str = ".keychain.json.prev";
+pub const KEYCHAIN_PIN_FILE_NAME: &str = "keychain.pin";
+pub const INDEX_FILE_NAME: &str = "index.json";
+pub const SOCKET_NAME: &str = "agent.socket";
+pub const AGENT_LOG_FILE_NAME: &str = "agent.log";
+pub const AUDIT_LOG_FILE_NAME: &str = "audit.log";
+pub const AUDIT_HEAD_FILE_NAME: &str = "audit.head";
+pub const CONFIG_FILE_NAME: &str = "config.json";
 
-pub const APP_DESC: &'static str = env!("CARGO_PKG_DESCRIPTION");
-pub const DEFAULT_EDITOR: &'static str = "vim";
+pub const APP_DESC: &str = env!("CARGO_PKG_DESCRIPTION");
+pub const DEFAULT_EDITOR: &str = "vim";
+pub const DEFAULT_PINENTRY_PROGRAM: &str = "pinentry";
+pub const DEFAULT_PIN_TRIES: u32 = 3;
+pub const DEFAULT_PIN_MIN_LENGTH: usize = 6;
+pub const DEFAULT_PIN_MIN_ENTROPY: f64 = 16.0;
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_LOG_MAX_FILES: u32 = 5;
+pub const DEFAULT_PADDING_BUCKET_SIZE: u64 = 1024;
+pub const DEFAULT_HISTORY_REVISIONS: u32 = 10;